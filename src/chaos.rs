@@ -0,0 +1,338 @@
+//! Управляемые сбои для репетиций на стенде (feature `chaos`).
+//!
+//! Каждый сбой включается через админ API на ограниченное время и
+//! выключается сам, поэтому забытый флаг не переживет `ttl`. Пока ни один
+//! сбой не включен, проверки сводятся к чтению одного атомарного флага.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+/// Максимальная задержка обработчиков `ChatServer`
+pub const MAX_LATENCY: Duration = Duration::from_secs(2);
+/// Максимальная задержка медленного потребителя на одно сообщение
+pub const MAX_SLOW_DELAY: Duration = Duration::from_secs(5);
+/// Максимальное число принудительных отключений в минуту
+pub const MAX_DISCONNECTS_PER_MIN: u32 = 60;
+/// Максимальное время жизни сбоя
+pub const MAX_TTL: Duration = Duration::from_secs(600);
+
+/// Сбой, который можно включить
+#[derive(Debug)]
+pub enum Fault {
+    /// Задержка каждого обработчика `ChatServer`
+    Latency(Duration),
+    /// Отбросить `percent` процентов исходящих сообщений выбранных сессий
+    Drop {
+        percent: u8,
+        sessions: HashSet<usize>,
+    },
+    /// Отключать случайные сессии с заданной частотой
+    Disconnect { per_min: u32 },
+    /// Выбранные сессии обрабатывают каждое сообщение с задержкой
+    Slow {
+        delay: Duration,
+        sessions: HashSet<usize>,
+    },
+}
+
+/// Параметры сбоя вне допустимых границ
+#[derive(Debug)]
+pub struct OutOfBounds(&'static str);
+
+impl fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is out of bounds", self.0)
+    }
+}
+
+/// Включенный сбой и момент, когда он выключится
+struct Armed<T> {
+    value: T,
+    expires: Instant,
+}
+
+impl<T> Armed<T> {
+    fn active(slot: &mut Option<Armed<T>>, now: Instant) -> Option<&T> {
+        if slot.as_ref().is_some_and(|armed| armed.expires <= now) {
+            *slot = None;
+        }
+        slot.as_ref().map(|armed| &armed.value)
+    }
+}
+
+#[derive(Default)]
+struct Faults {
+    latency: Option<Armed<Duration>>,
+    drop: Option<Armed<(u8, HashSet<usize>)>>,
+    disconnect: Option<Armed<u32>>,
+    slow: Option<Armed<(Duration, HashSet<usize>)>>,
+}
+
+impl Faults {
+    fn is_empty(&self) -> bool {
+        self.latency.is_none()
+            && self.drop.is_none()
+            && self.disconnect.is_none()
+            && self.slow.is_none()
+    }
+}
+
+/// Набор включенных сбоев, общий для сервера и сессий.
+/// Пустой набор сессий у `Drop` и `Slow` означает все сессии
+#[derive(Default)]
+pub struct Chaos {
+    /// Включен ли хотя бы один сбой
+    armed: AtomicBool,
+    faults: Mutex<Faults>,
+}
+
+impl Chaos {
+    /// Включить сбой на `ttl`, повторное включение заменяет прежние параметры
+    pub fn arm(&self, fault: Fault, ttl: Duration) -> Result<(), OutOfBounds> {
+        if ttl > MAX_TTL {
+            return Err(OutOfBounds("ttl"));
+        }
+        let expires = Instant::now() + ttl;
+
+        let mut faults = self.faults.lock().unwrap();
+        match fault {
+            Fault::Latency(delay) if delay > MAX_LATENCY => return Err(OutOfBounds("latency")),
+            Fault::Latency(value) => faults.latency = Some(Armed { value, expires }),
+            Fault::Drop { percent, .. } if percent > 100 => return Err(OutOfBounds("percent")),
+            Fault::Drop { percent, sessions } => {
+                faults.drop = Some(Armed {
+                    value: (percent, sessions),
+                    expires,
+                })
+            }
+            Fault::Disconnect { per_min } if per_min > MAX_DISCONNECTS_PER_MIN => {
+                return Err(OutOfBounds("per_min"))
+            }
+            Fault::Disconnect { per_min } => {
+                faults.disconnect = Some(Armed {
+                    value: per_min,
+                    expires,
+                })
+            }
+            Fault::Slow { delay, .. } if delay > MAX_SLOW_DELAY => {
+                return Err(OutOfBounds("delay"))
+            }
+            Fault::Slow { delay, sessions } => {
+                faults.slow = Some(Armed {
+                    value: (delay, sessions),
+                    expires,
+                })
+            }
+        }
+        self.armed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Выключить все сбои
+    pub fn clear(&self) {
+        *self.faults.lock().unwrap() = Faults::default();
+        self.armed.store(false, Ordering::Relaxed);
+    }
+
+    /// Выполнить `f` над сбоями, если хоть один включен
+    fn with_faults<T>(&self, f: impl FnOnce(&mut Faults, Instant) -> Option<T>) -> Option<T> {
+        if !self.armed.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut faults = self.faults.lock().unwrap();
+        let result = f(&mut faults, Instant::now());
+        if faults.is_empty() {
+            self.armed.store(false, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Задержка для обработчика `ChatServer`
+    pub fn latency(&self) -> Option<Duration> {
+        self.with_faults(|faults, now| Armed::active(&mut faults.latency, now).copied())
+    }
+
+    /// Отбросить ли очередное исходящее сообщение сессии `id`
+    pub fn should_drop(&self, id: usize) -> bool {
+        self.with_faults(|faults, now| {
+            let (percent, sessions) = Armed::active(&mut faults.drop, now)?;
+            let selected = sessions.is_empty() || sessions.contains(&id);
+            Some(selected && rand::thread_rng().gen_range(0..100) < *percent)
+        })
+        .unwrap_or(false)
+    }
+
+    /// Задержка медленного потребителя для сессии `id`
+    pub fn slow_delay(&self, id: usize) -> Option<Duration> {
+        self.with_faults(|faults, now| {
+            let (delay, sessions) = Armed::active(&mut faults.slow, now)?;
+            Some(*delay).filter(|_| sessions.is_empty() || sessions.contains(&id))
+        })
+    }
+
+    /// Отключить ли случайную сессию на очередной секундной проверке
+    pub fn should_disconnect(&self) -> bool {
+        self.with_faults(|faults, now| {
+            let per_min = *Armed::active(&mut faults.disconnect, now)?;
+            Some(rand::thread_rng().gen_range(0..60) < per_min)
+        })
+        .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread::sleep;
+
+    const SHORT_TTL: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn disarmed_chaos_injects_nothing() {
+        let chaos = Chaos::default();
+        assert_eq!(chaos.latency(), None);
+        assert!(!chaos.should_drop(1));
+        assert_eq!(chaos.slow_delay(1), None);
+        assert!(!chaos.should_disconnect());
+    }
+
+    #[test]
+    fn armed_faults_fire() {
+        let chaos = Chaos::default();
+        let ttl = Duration::from_secs(60);
+        chaos
+            .arm(Fault::Latency(Duration::from_millis(10)), ttl)
+            .unwrap();
+        chaos
+            .arm(
+                Fault::Drop {
+                    percent: 100,
+                    sessions: HashSet::new(),
+                },
+                ttl,
+            )
+            .unwrap();
+        chaos.arm(Fault::Disconnect { per_min: 60 }, ttl).unwrap();
+
+        assert_eq!(chaos.latency(), Some(Duration::from_millis(10)));
+        assert!(chaos.should_drop(1));
+        assert!(chaos.should_drop(2));
+        assert!(chaos.should_disconnect());
+    }
+
+    #[test]
+    fn session_faults_hit_only_selected_sessions() {
+        let chaos = Chaos::default();
+        let sessions: HashSet<usize> = [7].iter().copied().collect();
+        let ttl = Duration::from_secs(60);
+        chaos
+            .arm(
+                Fault::Slow {
+                    delay: Duration::from_millis(100),
+                    sessions: sessions.clone(),
+                },
+                ttl,
+            )
+            .unwrap();
+        chaos
+            .arm(
+                Fault::Drop {
+                    percent: 100,
+                    sessions,
+                },
+                ttl,
+            )
+            .unwrap();
+
+        assert_eq!(chaos.slow_delay(7), Some(Duration::from_millis(100)));
+        assert_eq!(chaos.slow_delay(8), None);
+        assert!(chaos.should_drop(7));
+        assert!(!chaos.should_drop(8));
+    }
+
+    #[test]
+    fn faults_out_of_bounds_are_rejected() {
+        let chaos = Chaos::default();
+        let ttl = Duration::from_secs(1);
+        assert!(chaos
+            .arm(Fault::Latency(MAX_LATENCY + Duration::from_millis(1)), ttl)
+            .is_err());
+        assert!(chaos
+            .arm(
+                Fault::Drop {
+                    percent: 101,
+                    sessions: HashSet::new(),
+                },
+                ttl,
+            )
+            .is_err());
+        assert!(chaos
+            .arm(
+                Fault::Disconnect {
+                    per_min: MAX_DISCONNECTS_PER_MIN + 1,
+                },
+                ttl,
+            )
+            .is_err());
+        assert!(chaos
+            .arm(
+                Fault::Slow {
+                    delay: MAX_SLOW_DELAY + Duration::from_millis(1),
+                    sessions: HashSet::new(),
+                },
+                ttl,
+            )
+            .is_err());
+        assert!(chaos
+            .arm(
+                Fault::Latency(Duration::from_millis(1)),
+                MAX_TTL + Duration::from_secs(1)
+            )
+            .is_err());
+        // отклоненный сбой ничего не включает
+        assert_eq!(chaos.latency(), None);
+        assert!(!chaos.armed.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn faults_expire_after_their_ttl() {
+        let chaos = Chaos::default();
+        chaos
+            .arm(Fault::Latency(Duration::from_millis(10)), SHORT_TTL)
+            .unwrap();
+        chaos
+            .arm(Fault::Disconnect { per_min: 60 }, SHORT_TTL * 4)
+            .unwrap();
+        assert!(chaos.latency().is_some());
+
+        sleep(SHORT_TTL * 2);
+        assert_eq!(chaos.latency(), None);
+        assert!(chaos.should_disconnect());
+        assert!(chaos.armed.load(Ordering::Relaxed));
+
+        sleep(SHORT_TTL * 3);
+        assert!(!chaos.should_disconnect());
+        // после истечения последнего сбоя проверки снова читают только флаг
+        assert!(!chaos.armed.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn clear_disarms_everything() {
+        let chaos = Chaos::default();
+        chaos
+            .arm(
+                Fault::Latency(Duration::from_millis(10)),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        chaos.clear();
+        assert_eq!(chaos.latency(), None);
+        assert!(!chaos.armed.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,103 @@
+//! Фильтры текста сообщений перед рассылкой в комнату.
+//!
+//! `ChatServer` применяет фильтры по порядку к тексту каждого сообщения
+//! пользователя и внешней интеграции: фильтр может пропустить текст,
+//! заменить его для следующих фильтров или отклонить сообщение.
+
+use std::collections::HashSet;
+
+/// Решение фильтра о сообщении
+#[derive(Debug, PartialEq)]
+pub enum FilterDecision {
+    /// Текст не меняется
+    Pass,
+    /// Разослать этот текст вместо исходного
+    Rewrite(String),
+    /// Не рассылать сообщение, причина уходит отправителю
+    Reject(String),
+}
+
+/// Фильтр сообщений комнаты
+pub trait MessageFilter: Send {
+    /// Проверить `text` сессии `sender` в комнате `room`. У сообщений
+    /// внешних интеграций `sender` равен 0
+    fn apply(&self, room: &str, sender: usize, text: &str) -> FilterDecision;
+}
+
+/// Находит слова из списка без учета регистра и заменяет их звездочками
+/// или отклоняет сообщение целиком
+pub struct WordFilter {
+    /// Слова в нижнем регистре
+    words: HashSet<String>,
+    /// Отклонять сообщение вместо замены
+    reject: bool,
+}
+
+impl WordFilter {
+    /// Заменять слова звездочками
+    pub fn masking<I, S>(words: I) -> WordFilter
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        WordFilter::new(words, false)
+    }
+
+    /// Отклонять сообщения с этими словами
+    pub fn rejecting<I, S>(words: I) -> WordFilter
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        WordFilter::new(words, true)
+    }
+
+    fn new<I, S>(words: I, reject: bool) -> WordFilter
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        WordFilter {
+            words: words
+                .into_iter()
+                .map(|word| word.as_ref().trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+            reject,
+        }
+    }
+}
+
+impl MessageFilter for WordFilter {
+    fn apply(&self, _: &str, _: usize, text: &str) -> FilterDecision {
+        let mut masked = String::with_capacity(text.len());
+        let mut changed = false;
+        // слово - непрерывная последовательность букв и цифр
+        let mut rest = text;
+        while let Some(start) = rest.find(char::is_alphanumeric) {
+            masked.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|c: char| !c.is_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            if self.words.contains(&word.to_lowercase()) {
+                if self.reject {
+                    return FilterDecision::Reject("contains a blocked word".to_owned());
+                }
+                masked.extend(word.chars().map(|_| '*'));
+                changed = true;
+            } else {
+                masked.push_str(word);
+            }
+            rest = &rest[end..];
+        }
+        masked.push_str(rest);
+
+        if changed {
+            FilterDecision::Rewrite(masked)
+        } else {
+            FilterDecision::Pass
+        }
+    }
+}
@@ -0,0 +1,466 @@
+//! Общие ограничители частоты: token bucket и скользящее окно.
+//! Время берется из `Clock`, поэтому ограничители не зависят от источника часов.
+//! `KeyedLimiter` хранит отдельный ограничитель на каждый ключ (сессию, IP и т.п.)
+//! и вытесняет давно не использованные ключи, чтобы не расти бесконечно.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Источник текущего времени
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// Монотонные системные часы
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Ограничитель частоты событий
+pub trait Limiter {
+    /// Разрешить событие в момент `now`. Возвращает `false`, если лимит исчерпан
+    fn check(&mut self, now: Instant) -> bool;
+
+    /// Разрешил бы `check` событие в момент `now`, не расходуя лимит
+    fn allows(&mut self, now: Instant) -> bool;
+}
+
+/// Token bucket: до `capacity` событий подряд, затем одно событие на каждый `refill`
+#[derive(Clone)]
+pub struct TokenBucket {
+    capacity: u32,
+    tokens: u32,
+    refill: Duration,
+    /// Момент, с которого отсчитывается следующее пополнение
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill: Duration, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            refill,
+            last_refill: now,
+        }
+    }
+
+    /// Начислить токены за целые интервалы `refill`, прошедшие с последнего пополнения
+    fn refill(&mut self, now: Instant) {
+        if self.refill.as_nanos() == 0 {
+            self.tokens = self.capacity;
+            return;
+        }
+        // полное ведро не копит время к следующему токену, иначе после
+        // паузы пачка получит лишний токен раньше срока
+        if self.tokens >= self.capacity {
+            self.last_refill = now;
+            return;
+        }
+
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let intervals = elapsed.as_nanos() / self.refill.as_nanos();
+        if intervals == 0 {
+            return;
+        }
+
+        if self.tokens as u128 + intervals >= self.capacity as u128 {
+            self.tokens = self.capacity;
+            self.last_refill = now;
+        } else {
+            self.tokens += intervals as u32;
+            // остаток неполного интервала не теряется
+            self.last_refill += self.refill * intervals as u32;
+        }
+    }
+}
+
+impl TokenBucket {
+    /// Через сколько появится следующий токен, ноль если токены есть
+    pub fn retry_after(&self, now: Instant) -> Duration {
+        if self.tokens > 0 {
+            return Duration::ZERO;
+        }
+        self.refill
+            .saturating_sub(now.saturating_duration_since(self.last_refill))
+    }
+}
+
+impl Limiter for TokenBucket {
+    fn check(&mut self, now: Instant) -> bool {
+        if !self.allows(now) {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    fn allows(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens > 0
+    }
+}
+
+/// Скользящее окно: не больше `limit` событий за любой промежуток длиной `window`
+pub struct SlidingWindow {
+    limit: usize,
+    window: Duration,
+    /// Моменты разрешенных событий внутри окна, самый старый первым
+    hits: VecDeque<Instant>,
+}
+
+impl SlidingWindow {
+    pub fn new(limit: usize, window: Duration) -> SlidingWindow {
+        SlidingWindow {
+            limit,
+            window,
+            hits: VecDeque::with_capacity(limit),
+        }
+    }
+}
+
+impl Limiter for SlidingWindow {
+    fn check(&mut self, now: Instant) -> bool {
+        if !self.allows(now) {
+            return false;
+        }
+        self.hits.push_back(now);
+        true
+    }
+
+    fn allows(&mut self, now: Instant) -> bool {
+        while let Some(&oldest) = self.hits.front() {
+            if now.saturating_duration_since(oldest) >= self.window {
+                self.hits.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.hits.len() < self.limit
+    }
+}
+
+/// Ограничители по ключам с вытеснением давно не использованных ключей
+pub struct KeyedLimiter<K, L, C = SystemClock> {
+    clock: C,
+    max_keys: usize,
+    /// Создает ограничитель для нового ключа
+    make: Box<dyn Fn(Instant) -> L + Send>,
+    /// Ограничитель и момент последнего обращения по ключу
+    entries: HashMap<K, (L, Instant)>,
+}
+
+impl<K: Hash + Eq + Clone, L: Limiter> KeyedLimiter<K, L> {
+    pub fn new(max_keys: usize, make: impl Fn(Instant) -> L + Send + 'static) -> Self {
+        KeyedLimiter::with_clock(SystemClock, max_keys, make)
+    }
+}
+
+impl<K: Hash + Eq + Clone, L: Limiter, C: Clock> KeyedLimiter<K, L, C> {
+    pub fn with_clock(
+        clock: C,
+        max_keys: usize,
+        make: impl Fn(Instant) -> L + Send + 'static,
+    ) -> Self {
+        KeyedLimiter {
+            clock,
+            max_keys,
+            make: Box::new(make),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Разрешить событие для ключа `key`
+    pub fn check(&mut self, key: &K) -> bool {
+        let now = self.clock.now();
+
+        if !self.entries.contains_key(key) {
+            if self.entries.len() >= self.max_keys {
+                self.evict_idle();
+            }
+            self.entries.insert(key.clone(), ((self.make)(now), now));
+        }
+
+        let (limiter, last_used) = self.entries.get_mut(key).expect("entry inserted above");
+        *last_used = now;
+        limiter.check(now)
+    }
+
+    /// Разрешил бы `check` событие для ключа `key`, не расходуя лимит
+    /// и не запоминая ключ
+    pub fn allows(&mut self, key: &K) -> bool {
+        let now = self.clock.now();
+        match self.entries.get_mut(key) {
+            Some((limiter, _)) => limiter.allows(now),
+            None => (self.make)(now).allows(now),
+        }
+    }
+
+    /// Забыть состояние ключа, например когда сессия отключилась
+    pub fn remove(&mut self, key: &K) {
+        self.entries.remove(key);
+    }
+
+    /// Вытеснить ключ, к которому дольше всех не обращались
+    fn evict_idle(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, (_, last_used))| *last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// Часы, которые двигает тест
+    #[derive(Clone)]
+    struct FakeClock(Rc<Cell<Instant>>);
+
+    impl FakeClock {
+        fn new() -> FakeClock {
+            FakeClock(Rc::new(Cell::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            self.0.set(self.0.get() + by);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    /// Случайные моменты событий: пачки в один момент вперемешку с паузами
+    fn random_times(seed: u64, start: Instant, count: usize) -> Vec<Instant> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut now = start;
+        (0..count)
+            .map(|_| {
+                if rng.gen_bool(0.3) {
+                    now += Duration::from_millis(rng.gen_range(0..1500));
+                }
+                now
+            })
+            .collect()
+    }
+
+    #[test]
+    fn token_bucket_allows_exactly_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(3, Duration::from_secs(1), now);
+        assert!(bucket.check(now));
+        assert!(bucket.check(now));
+        assert!(bucket.check(now));
+        assert!(!bucket.check(now));
+        assert_eq!(bucket.retry_after(now), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn token_bucket_refills_one_token_per_interval() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, Duration::from_secs(1), start);
+        assert!(bucket.check(start));
+        assert!(bucket.check(start));
+
+        let almost = start + Duration::from_millis(999);
+        assert!(!bucket.check(almost));
+        assert_eq!(bucket.retry_after(almost), Duration::from_millis(1));
+
+        let later = start + Duration::from_millis(1500);
+        assert!(bucket.check(later));
+        assert!(!bucket.check(later));
+        // остаток неполного интервала не теряется: следующий токен через 2 с от начала
+        assert!(bucket.check(start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn token_bucket_never_exceeds_capacity_after_a_long_pause() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, Duration::from_secs(1), start);
+        let later = start + Duration::from_secs(3600);
+        assert!(bucket.check(later));
+        assert!(bucket.check(later));
+        assert!(!bucket.check(later));
+    }
+
+    #[test]
+    fn token_bucket_never_exceeds_its_rate_over_any_interval() {
+        let (capacity, refill) = (4, Duration::from_millis(500));
+        for seed in 0..50 {
+            let start = Instant::now();
+            let mut bucket = TokenBucket::new(capacity, refill, start);
+            let allowed: Vec<Instant> = random_times(seed, start, 300)
+                .into_iter()
+                .filter(|&now| bucket.check(now))
+                .collect();
+
+            // за любой промежуток [a, b] не больше capacity + целых интервалов в нем
+            for (i, &a) in allowed.iter().enumerate() {
+                for (j, &b) in allowed.iter().enumerate().skip(i) {
+                    let intervals = (b - a).as_nanos() / refill.as_nanos();
+                    let limit = capacity as u128 + intervals;
+                    assert!(
+                        (j - i + 1) as u128 <= limit,
+                        "seed {}: {} events in {:?}",
+                        seed,
+                        j - i + 1,
+                        b - a
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sliding_window_expires_old_events() {
+        let start = Instant::now();
+        let mut window = SlidingWindow::new(2, Duration::from_secs(10));
+        assert!(window.check(start));
+        assert!(window.check(start + Duration::from_secs(1)));
+        assert!(!window.check(start + Duration::from_secs(9)));
+        // первое событие вышло из окна, второе еще в нем
+        assert!(window.check(start + Duration::from_secs(10)));
+        assert!(!window.check(start + Duration::from_secs(10)));
+        assert!(window.check(start + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn sliding_window_never_exceeds_its_limit_in_any_window() {
+        let (limit, length) = (5, Duration::from_secs(2));
+        for seed in 0..50 {
+            let start = Instant::now();
+            let mut window = SlidingWindow::new(limit, length);
+            let allowed: Vec<Instant> = random_times(seed, start, 300)
+                .into_iter()
+                .filter(|&now| window.check(now))
+                .collect();
+
+            for (i, &a) in allowed.iter().enumerate() {
+                let inside = allowed[i..].iter().take_while(|&&b| b - a < length).count();
+                assert!(
+                    inside <= limit,
+                    "seed {}: {} events in one window",
+                    seed,
+                    inside
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn keyed_limiter_keeps_keys_isolated() {
+        let clock = FakeClock::new();
+        let mut limiter = KeyedLimiter::with_clock(clock.clone(), 10, |now| {
+            TokenBucket::new(1, Duration::from_secs(1), now)
+        });
+        assert!(limiter.check(&1));
+        assert!(!limiter.check(&1));
+        // другой ключ не тратит токены первого
+        assert!(limiter.check(&2));
+        assert!(!limiter.check(&2));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.check(&1));
+        assert!(limiter.check(&2));
+    }
+
+    #[test]
+    fn keyed_limiter_evicts_the_least_recently_used_key() {
+        let clock = FakeClock::new();
+        let mut limiter = KeyedLimiter::with_clock(clock.clone(), 2, |now| {
+            TokenBucket::new(1, Duration::from_secs(60), now)
+        });
+        assert!(limiter.check(&1));
+        clock.advance(Duration::from_millis(1));
+        assert!(limiter.check(&2));
+        clock.advance(Duration::from_millis(1));
+        assert!(!limiter.check(&1));
+        clock.advance(Duration::from_millis(1));
+
+        // ключ 2 использовался давнее всех и вытесняется ради ключа 3
+        assert!(limiter.check(&3));
+        assert_eq!(limiter.entries.len(), 2);
+        assert!(!limiter.check(&1));
+        assert!(limiter.check(&2));
+    }
+
+    #[test]
+    fn keyed_limiter_forgets_removed_keys() {
+        let clock = FakeClock::new();
+        let mut limiter = KeyedLimiter::with_clock(clock, 10, |_| {
+            SlidingWindow::new(1, Duration::from_secs(60))
+        });
+        assert!(limiter.check(&"a"));
+        assert!(!limiter.check(&"a"));
+        limiter.remove(&"a");
+        assert!(limiter.check(&"a"));
+    }
+
+    #[test]
+    fn allows_does_not_spend_the_limit() {
+        let clock = FakeClock::new();
+        let mut limiter = KeyedLimiter::with_clock(clock, 10, |_| {
+            SlidingWindow::new(1, Duration::from_secs(60))
+        });
+        assert!(limiter.allows(&"a"));
+        assert!(limiter.entries.is_empty());
+        assert!(limiter.check(&"a"));
+        assert!(!limiter.allows(&"a"));
+        assert!(!limiter.check(&"a"));
+    }
+
+    /// Стоимость одной проверки на горячем пути сообщений:
+    /// `cargo test --release -- --ignored --nocapture bench_`
+    #[test]
+    #[ignore]
+    fn bench_check_cost() {
+        const CHECKS: u32 = 5_000_000;
+        let report = |name: &str, started: Instant| {
+            let per_check = started.elapsed() / CHECKS;
+            println!("{}: {:?} per check", name, per_check);
+        };
+
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, Duration::from_millis(1), now);
+        let started = Instant::now();
+        for i in 0..CHECKS {
+            std::hint::black_box(bucket.check(now + Duration::from_nanos(i as u64 * 100)));
+        }
+        report("TokenBucket", started);
+
+        let mut window = SlidingWindow::new(10, Duration::from_millis(1));
+        let started = Instant::now();
+        for i in 0..CHECKS {
+            std::hint::black_box(window.check(now + Duration::from_nanos(i as u64 * 100)));
+        }
+        report("SlidingWindow", started);
+
+        let mut keyed = KeyedLimiter::new(1_000, |now| {
+            TokenBucket::new(10, Duration::from_millis(1), now)
+        });
+        let started = Instant::now();
+        for i in 0..CHECKS {
+            std::hint::black_box(keyed.check(&(i as usize % 1_000)));
+        }
+        report("KeyedLimiter<TokenBucket>", started);
+    }
+}
@@ -1,57 +1,770 @@
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
-};
-use std::time::{Duration, Instant};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{atomic::Ordering, Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use actix::*;
-use actix_files as fs;
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_http::ws::Item;
+use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
+use log::{debug, error, info, trace, warn};
+use unicode_segmentation::UnicodeSegmentation;
 
+#[cfg(feature = "chaos")]
+use chaos::{Chaos, Fault};
+use limiter::{Limiter, TokenBucket};
+use protocol::{ClientEvent, ErrorCode, Format, ServerEvent};
+
+#[cfg(feature = "chaos")]
+mod chaos;
+mod filter;
+mod limiter;
+mod protocol;
 mod server;
+mod store;
+mod tls;
+mod uploads;
 
-/// Как часто отправляются пинги сердцебиения
+/// Как часто отправляются пинги сердцебиения (по умолчанию, `CHAT_HEARTBEAT_SECS`)
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-/// Через какое время отсутствие ответа клиента приводит к тайм-ауту
+/// Через какое время отсутствие ответа клиента приводит к тайм-ауту (по умолчанию, `CHAT_TIMEOUT_SECS`)
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Код закрытия, когда владелец комнаты выгнал пользователя, коды 4000-4999
+/// отведены приложениям
+const CLOSE_KICKED: u16 = 4001;
+/// Код закрытия, когда сервер удалил давно молчащую сессию
+const CLOSE_IDLE: u16 = 4002;
+
+/// Сколько команд подряд может отправить сессия
+const COMMAND_BURST: u32 = 10;
+/// Как часто восстанавливается возможность отправить еще одну команду
+const COMMAND_REFILL: Duration = Duration::from_millis(500);
+
+/// Самая длинная тема комнаты в графемах
+const MAX_TOPIC_LEN: usize = 200;
+
+/// Самый длинный интервал медленного режима
+const MAX_SLOW_MODE: Duration = Duration::from_secs(3600);
+/// Сколько сообщений `/history` показывает по умолчанию
+const HISTORY_DEFAULT: usize = 10;
+/// Сессия отправляет уведомление о наборе не чаще этого интервала
+const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Поддерживаемые команды и их описание для `/help`
+const COMMANDS: &[(&str, &str)] = &[
+    ("/help", "show this list"),
+    (
+        "/list [filter]",
+        "list the busiest rooms with member counts, optionally only names containing filter",
+    ),
+    (
+        "/join <room> [max=N] [password]",
+        "join a room, creating it if needed (with an optional size limit and password)",
+    ),
+    ("/name <name>", "set your name"),
+    ("/nick <name>", "same as /name"),
+    ("/who", "list members of the current room"),
+    ("/whoami", "show your session id, name and room"),
+    ("/me <action>", "describe what you are doing"),
+    ("/typing", "tell the room you are typing"),
+    (
+        "/file <url>",
+        "share a link to a file uploaded with POST /upload",
+    ),
+    (
+        "/stats [room]",
+        "show server counters and the current room, or the given room",
+    ),
+    ("/status <text|clear>", "set or clear your status"),
+    ("/history [n]", "show the last n messages of the room"),
+    ("/clear", "clear the history of the room (owner only)"),
+    ("/edit <id> <text>", "replace the text of your message #id"),
+    ("/delete <id>", "delete your message #id"),
+    (
+        "/resync <id>",
+        "replay messages of the room after message #id",
+    ),
+    (
+        "/ignore [name]",
+        "hide messages from a user, or list ignored users",
+    ),
+    ("/unignore <name>", "show messages from a user again"),
+    (
+        "/kick <name>",
+        "disconnect a member of the room (moderators)",
+    ),
+    ("/ban <name>", "ban a name from the room (moderators)"),
+    ("/unban <name>", "lift a ban (moderators)"),
+    ("/mute <name>", "stop a member from posting (moderators)"),
+    (
+        "/unmute <name>",
+        "let a muted member post again (moderators)",
+    ),
+    (
+        "/topic [text]",
+        "show the room topic, or set it (moderators)",
+    ),
+    (
+        "/slowmode <seconds|off>",
+        "limit how often members may post, 0 or off disables it (moderators)",
+    ),
+    ("/promote <name>", "make a member a moderator (owner only)"),
+    ("/demote <name>", "take moderator rights away (owner only)"),
+    (
+        "/away [reason]",
+        "mark yourself away, or come back when already away",
+    ),
+    ("/back", "come back after /away"),
+    (
+        "/invite <name>",
+        "invite someone to the current room, no password needed",
+    ),
+    ("/admin <token>", "enable admin commands"),
+    (
+        "/fsck [repair]",
+        "check server state consistency (admin only)",
+    ),
+    (
+        "/roomopt announcements <full|counts_only|silent>",
+        "set join/leave notices (owner only)",
+    ),
+    (
+        "/roomopt statuses <on|off>",
+        "show member statuses (owner only)",
+    ),
+    ("/quit", "leave the chat"),
+];
+
+/// Адрес, на котором слушает сервер
+const BIND_ADDR: &str = "127.0.0.1:8081";
+
+/// Сколько комнат показывает `/list`
+const LIST_LIMIT: usize = 50;
+
+/// Максимальный размер текстового сообщения в байтах (по умолчанию, `CHAT_MAX_MESSAGE_LEN`)
+const MAX_MESSAGE_LEN: usize = 2048;
+
+/// Настройки, общие для всех сессий
+#[derive(Clone)]
+struct SessionConfig {
+    hb_interval: Duration,
+    client_timeout: Duration,
+    max_message_len: usize,
+    /// Писать в комнату можно только после `/name` (`CHAT_REQUIRE_NAME`)
+    require_name: bool,
+    /// Комната новых сессий (`CHAT_DEFAULT_ROOM`)
+    default_room: String,
+    /// Токен администратора (`CHAT_ADMIN_TOKEN`), без него админ-команды недоступны
+    admin_token: Option<Arc<str>>,
+    /// Токен интеграций (`CHAT_INTEGRATION_TOKEN`), дает только отправку сообщений по REST
+    integration_token: Option<Arc<str>>,
+    /// Сервер стоит за прокси, адрес клиента берется из `X-Forwarded-For` (`CHAT_TRUST_PROXY`)
+    trust_proxy: bool,
+    /// Управляемые сбои, общие с сервером чата
+    #[cfg(feature = "chaos")]
+    chaos: Arc<Chaos>,
+}
+
+impl SessionConfig {
+    /// Совпадает ли `token` с токеном администратора
+    fn is_admin_token(&self, token: &str) -> bool {
+        self.admin_token
+            .as_deref()
+            .is_some_and(|admin| server::secrets_match(admin, token))
+    }
+
+    /// Адрес клиента. За доверенным прокси это последний адрес `X-Forwarded-For`:
+    /// его дописал сам прокси, а предыдущие клиент может подставить любые.
+    /// Порт клиента в нем неизвестен и равен 0
+    fn peer_addr(&self, req: &HttpRequest) -> Option<SocketAddr> {
+        let forwarded = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+        match forwarded {
+            Some(ip) if self.trust_proxy => Some(SocketAddr::new(ip, 0)),
+            _ => req.peer_addr(),
+        }
+    }
+
+    /// Есть ли в запросе заголовок `Authorization: Bearer <CHAT_ADMIN_TOKEN>`
+    fn is_authorized(&self, req: &HttpRequest) -> bool {
+        bearer_token(req).is_some_and(|token| self.is_admin_token(token))
+    }
+
+    /// Может ли запрос писать в комнаты от имени интеграции: подходит токен
+    /// администратора или `CHAT_INTEGRATION_TOKEN`
+    fn is_integration(&self, req: &HttpRequest) -> bool {
+        bearer_token(req).is_some_and(|token| {
+            self.is_admin_token(token)
+                || self
+                    .integration_token
+                    .as_deref()
+                    .is_some_and(|integration| server::secrets_match(integration, token))
+        })
+    }
+}
+
+/// Токен из заголовка `Authorization: Bearer <token>`
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Длиннее `User-Agent` обрезается
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Сколько последних сообщений хранится в каждой комнате (по умолчанию, `CHAT_HISTORY_LEN`)
+const HISTORY_LEN: usize = 50;
+
+/// Сколько из них показывается при входе в комнату (по умолчанию, `CHAT_REPLAY_LEN`)
+const REPLAY_LEN: usize = 20;
+
+/// Сколько сессий может быть подключено одновременно (по умолчанию, `CHAT_MAX_CONNECTIONS`)
+const MAX_CONNECTIONS: usize = 1000;
+
+/// Вместимость новых комнат без `max=N`, 0 без ограничения (по умолчанию, `CHAT_ROOM_CAPACITY`)
+const ROOM_CAPACITY: usize = 0;
+
+/// Сколько сообщений подряд сервер принимает от сессии (по умолчанию, `CHAT_FLOOD_BURST`)
+const FLOOD_BURST: u32 = 10;
+
+/// За сколько пополняется `FLOOD_BURST` (по умолчанию, `CHAT_FLOOD_WINDOW_SECS`)
+const FLOOD_WINDOW: Duration = Duration::from_secs(10);
+
+/// После скольких предупреждений о флуде сессия отключается, 0 без отключения
+/// (по умолчанию, `CHAT_FLOOD_MAX_WARNINGS`)
+const FLOOD_MAX_WARNINGS: u32 = 3;
+
+/// Сколько сервер ждет признаков жизни от сессии, прежде чем удалить её,
+/// 0 без удаления (по умолчанию, `CHAT_IDLE_TIMEOUT_SECS`)
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Через сколько без сообщений и входов удаляется комната, 0 без удаления
+/// (по умолчанию, `CHAT_ROOM_TTL_SECS`)
+const ROOM_TTL: Duration = Duration::from_secs(0);
+
+/// Сколько при остановке ждать закрытия сессий
+/// (по умолчанию, `CHAT_SHUTDOWN_TIMEOUT_SECS`)
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Как часто сервер ищет молчащие сессии (по умолчанию, `CHAT_IDLE_SWEEP_SECS`)
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Файл с сообщением дня, если не задан `CHAT_MOTD`
+const MOTD_FILE: &str = "motd.txt";
+
+/// Сколько действует приглашение в комнату (по умолчанию, `CHAT_INVITE_TTL_SECS`)
+const INVITE_TTL: Duration = Duration::from_secs(300);
+
+/// Максимальный размер загружаемого файла в байтах (по умолчанию, `CHAT_MAX_UPLOAD_BYTES`)
+const MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+/// Сколько хранится загруженный файл (по умолчанию, `CHAT_UPLOAD_TTL_SECS`)
+const UPLOAD_TTL: Duration = Duration::from_secs(3600);
+
+/// Сколько действует сбой, если `ttl` не указан
+#[cfg(feature = "chaos")]
+const CHAOS_TTL: Duration = Duration::from_secs(60);
+
+/// Прочитать значение из переменной окружения, при ошибке вернуть значение по умолчанию
+fn env_or<T: FromStr + fmt::Debug>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                warn!("Invalid {} {:?}, using {:?}", name, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// Прочитать длительность в секундах из переменной окружения
+fn env_secs(name: &str, default: Duration) -> Duration {
+    Duration::from_secs(env_or(name, default.as_secs()))
+}
+
+/// Unix-время в секундах
+fn unix_time(ts: SystemTime) -> u64 {
+    ts.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Текст приходит с префиксом "имя: ", в событиях имя передается отдельно
+fn strip_author<'a>(from: Option<&str>, text: &'a str) -> &'a str {
+    from.and_then(|from| text.strip_prefix(from))
+        .and_then(|text| text.strip_prefix(": "))
+        .unwrap_or(text)
+}
+
+/// Номер сообщения в командах: `42` или `#42`
+fn parse_msg_id(arg: &str) -> Option<u64> {
+    arg.strip_prefix('#').unwrap_or(arg).parse().ok()
+}
+
+/// Время в формате `HH:MM:SS` (UTC)
+fn format_time(ts: SystemTime) -> String {
+    let secs = unix_time(ts);
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Общее состояние всех воркеров `HttpServer`.
+///
+/// Создается один раз до запуска воркеров и регистрируется через `app_data`:
+/// если создавать его внутри фабрики `App`, у каждого воркера окажется свой
+/// `ChatServer`, и клиенты разных воркеров перестанут видеть друг друга
+struct AppState {
+    /// Части сервера чата
+    server: server::Router,
+    /// Счетчики посещений и подключенных сессий
+    counters: Arc<server::Counters>,
+}
 
 /// Точка входа для нашего маршрута websocket
 async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
-    srv: web::Data<Addr<server::ChatServer>>,
+    state: web::Data<AppState>,
+    config: web::Data<SessionConfig>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(
+    let resume = req
+        .query_string()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("resume="))
+        .map(str::to_owned);
+
+    // неизвестные подпротоколы не мешают подключиться в прежнем режиме
+    let negotiated = req
+        .headers()
+        .get("sec-websocket-protocol")
+        .and_then(|offered| offered.to_str().ok())
+        .and_then(Format::negotiate);
+    let (protocols, format): (&[&str], Format) = match &negotiated {
+        Some((name, format)) => (std::slice::from_ref(name), *format),
+        None => (&[], Format::from_query(req.query_string())),
+    };
+
+    ws::start_with_protocols(
         WsChatSession {
             id: 0,
             hb: Instant::now(),
-            room: "Main".to_owned(),
+            hb_interval: config.hb_interval,
+            client_timeout: config.client_timeout,
+            hb_handle: None,
+            max_message_len: config.max_message_len,
+            require_name: config.require_name,
+            admin: false,
+            admin_token: config.admin_token.clone(),
+            #[cfg(feature = "chaos")]
+            chaos: config.chaos.clone(),
+            room: config.default_room.clone(),
             name: None,
-            addr: srv.get_ref().clone(),
+            ignored: HashSet::new(),
+            away: None,
+            commands: TokenBucket::new(COMMAND_BURST, COMMAND_REFILL, Instant::now()),
+            last_typing: None,
+            format,
+            fragments: None,
+            disconnected: false,
+            resume,
+            peer: config.peer_addr(&req),
+            user_agent: req
+                .headers()
+                .get("User-Agent")
+                .and_then(|value| value.to_str().ok())
+                .map(|agent| agent.chars().take(MAX_USER_AGENT_LEN).collect()),
+            addr: state.server.clone(),
         },
+        protocols,
         &req,
         stream,
     )
 }
 
-/// Отображает и влияет на состояние
-async fn get_count(count: web::Data<Arc<AtomicUsize>>) -> impl Responder {
-    let current_count = count.fetch_add(1, Ordering::SeqCst);
-    format!("Visitors: {}", current_count)
+/// Проверка согласованности состояния сервера, `?repair` исправляет нарушения.
+/// Требует заголовок `Authorization: Bearer <CHAT_ADMIN_TOKEN>`
+async fn fsck_route(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    config: web::Data<SessionConfig>,
+) -> Result<HttpResponse, Error> {
+    if !config.is_authorized(&req) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let repair = req.query_string() == "repair";
+    let violations = state
+        .server
+        .fsck(repair)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    if violations.is_empty() {
+        Ok(HttpResponse::Ok().body("ok"))
+    } else {
+        Ok(HttpResponse::Ok().body(violations.join("\n")))
+    }
+}
+
+/// Подключенные сессии с адресами клиентов в JSON.
+/// Требует заголовок `Authorization: Bearer <CHAT_ADMIN_TOKEN>`
+async fn sessions_route(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    config: web::Data<SessionConfig>,
+) -> Result<HttpResponse, Error> {
+    if !config.is_authorized(&req) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let mut sessions = state
+        .server
+        .list_sessions()
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    sessions.sort_by_key(|session| session.connected);
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+/// Объявление всем подключенным пользователям, текст в теле запроса.
+/// Требует заголовок `Authorization: Bearer <CHAT_ADMIN_TOKEN>`
+async fn broadcast_route(
+    req: HttpRequest,
+    body: String,
+    state: web::Data<AppState>,
+    config: web::Data<SessionConfig>,
+) -> Result<HttpResponse, Error> {
+    if !config.is_authorized(&req) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let text = body.trim();
+    if text.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("announcement text is required"));
+    }
+    if text.len() > config.max_message_len {
+        return Ok(HttpResponse::PayloadTooLarge().body(format!(
+            "message too long (max {} bytes)",
+            config.max_message_len
+        )));
+    }
+
+    state
+        .server
+        .broadcast(text.to_owned())
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Параметры `POST /api/chaos`
+#[cfg(feature = "chaos")]
+#[derive(serde::Deserialize)]
+struct ChaosParams {
+    /// `latency`, `drop`, `disconnect`, `slow` или `clear`
+    fault: String,
+    ms: Option<u64>,
+    percent: Option<u8>,
+    per_min: Option<u32>,
+    /// Id сессий через запятую, без него сбой касается всех сессий
+    sessions: Option<String>,
+    /// Через сколько секунд сбой выключится
+    ttl: Option<u64>,
+}
+
+/// Включить или выключить управляемый сбой.
+/// Требует заголовок `Authorization: Bearer <CHAT_ADMIN_TOKEN>`
+#[cfg(feature = "chaos")]
+async fn chaos_route(
+    req: HttpRequest,
+    params: web::Query<ChaosParams>,
+    config: web::Data<SessionConfig>,
+) -> HttpResponse {
+    if !config.is_authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let sessions = match &params.sessions {
+        Some(ids) => match ids.split(',').map(str::parse).collect() {
+            Ok(ids) => ids,
+            Err(_) => return HttpResponse::BadRequest().body("invalid sessions"),
+        },
+        None => HashSet::new(),
+    };
+    let delay = Duration::from_millis(params.ms.unwrap_or(0));
+    let fault = match params.fault.as_str() {
+        "latency" => Fault::Latency(delay),
+        "drop" => Fault::Drop {
+            percent: params.percent.unwrap_or(100),
+            sessions,
+        },
+        "disconnect" => Fault::Disconnect {
+            per_min: params.per_min.unwrap_or(1),
+        },
+        "slow" => Fault::Slow { delay, sessions },
+        "clear" => {
+            config.chaos.clear();
+            return HttpResponse::Ok().body("ok");
+        }
+        _ => return HttpResponse::BadRequest().body("unknown fault"),
+    };
+
+    let ttl = Duration::from_secs(params.ttl.unwrap_or(CHAOS_TTL.as_secs()));
+    match config.chaos.arm(fault, ttl) {
+        Ok(()) => HttpResponse::Ok().body("ok"),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+/// Список комнат в JSON: `[{"name":"Main","members":3,"protected":false}]`
+async fn rooms_route(
+    state: web::Data<AppState>,
+) -> Result<web::Json<Vec<server::RoomInfo>>, Error> {
+    let page = state
+        .server
+        .list_rooms(server::ListRooms {
+            filter: None,
+            offset: 0,
+            limit: usize::MAX,
+            sort: server::RoomSort::Name,
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(web::Json(page.rooms))
+}
+
+/// Тело `POST /rooms/{room}/message`
+#[derive(serde::Deserialize)]
+struct ExternalMessageBody {
+    from: String,
+    text: String,
+}
+
+/// Отправить сообщение в комнату без вебсокета, для ботов и интеграций.
+/// Требует заголовок `Authorization: Bearer <CHAT_INTEGRATION_TOKEN>` или токен администратора
+async fn post_message_route(
+    req: HttpRequest,
+    room: web::Path<String>,
+    body: web::Json<ExternalMessageBody>,
+    state: web::Data<AppState>,
+    config: web::Data<SessionConfig>,
+) -> Result<HttpResponse, Error> {
+    if !config.is_integration(&req) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let body = body.into_inner();
+    if body.from.trim().is_empty() || body.text.trim().is_empty() {
+        return Ok(HttpResponse::BadRequest().body("from and text are required"));
+    }
+    if body.text.len() > config.max_message_len {
+        return Ok(HttpResponse::PayloadTooLarge().body(format!(
+            "message too long (max {} bytes)",
+            config.max_message_len
+        )));
+    }
+
+    let room = room.into_inner();
+    let sent = state
+        .server
+        .shard(&room)
+        .send(server::ExternalMessage {
+            room,
+            from: body.from,
+            text: body.text,
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    match sent {
+        Ok(()) => Ok(HttpResponse::Accepted().finish()),
+        Err(err @ server::ExternalError::NoSuchRoom) => {
+            Ok(HttpResponse::NotFound().body(err.to_string()))
+        }
+        Err(err @ server::ExternalError::Rejected(_)) => {
+            Ok(HttpResponse::UnprocessableEntity().body(err.to_string()))
+        }
+        Err(err @ server::ExternalError::RateLimited) => {
+            Ok(HttpResponse::TooManyRequests().body(err.to_string()))
+        }
+    }
+}
+
+/// Тело `POST /api/rooms/{room}/messages`
+#[derive(serde::Deserialize)]
+struct SendMessageBody {
+    text: String,
+}
+
+/// Ответ `POST /api/rooms/{room}/messages`
+#[derive(serde::Serialize)]
+struct SentMessage {
+    /// Порядковый номер сообщения в комнате
+    msg_id: u64,
+}
+
+/// Отправить сообщение в комнату по HTTP от имени подключенной сессии, например
+/// из фонового режима мобильного приложения.
+/// Требует заголовок `Authorization: Bearer <токен возобновления сессии>`
+async fn send_message_route(
+    req: HttpRequest,
+    room: web::Path<String>,
+    body: web::Json<SendMessageBody>,
+    state: web::Data<AppState>,
+    config: web::Data<SessionConfig>,
+) -> Result<HttpResponse, Error> {
+    let token = match bearer_token(&req) {
+        Some(token) => token.to_owned(),
+        None => return Ok(HttpResponse::Unauthorized().finish()),
+    };
+    let text = body.into_inner().text;
+    if text.trim().is_empty() {
+        return Ok(HttpResponse::BadRequest().body("text is required"));
+    }
+    if text.len() > config.max_message_len {
+        return Ok(HttpResponse::PayloadTooLarge().body(format!(
+            "message too long (max {} bytes)",
+            config.max_message_len
+        )));
+    }
+
+    let room = room.into_inner();
+    let sent = state
+        .server
+        .shard(&room)
+        .send(server::RestMessage {
+            token,
+            room,
+            text,
+            require_name: config.require_name,
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let err = match sent {
+        Ok(msg_id) => return Ok(HttpResponse::Ok().json(SentMessage { msg_id })),
+        Err(err) => err,
+    };
+    let mut resp = match &err {
+        server::RestError::Unauthorized => HttpResponse::Unauthorized(),
+        server::RestError::NotMember | server::RestError::NameRequired => HttpResponse::Forbidden(),
+        server::RestError::Refused(refusal) => match refusal {
+            server::Refusal::TooLong(_) => HttpResponse::PayloadTooLarge(),
+            server::Refusal::Muted => HttpResponse::Forbidden(),
+            server::Refusal::SlowMode(_) | server::Refusal::RateLimited => {
+                HttpResponse::TooManyRequests()
+            }
+            server::Refusal::Rejected(_) => HttpResponse::UnprocessableEntity(),
+        },
+    };
+    Ok(resp.body(err.to_string()))
+}
+
+/// Ответ `GET /count/`
+#[derive(serde::Serialize)]
+struct CountInfo {
+    visitors: usize,
+    active_sessions: usize,
+}
+
+/// Отображает общее число посещений и число подключенных сессий, не меняя их
+async fn get_count(state: web::Data<AppState>) -> web::Json<CountInfo> {
+    web::Json(CountInfo {
+        visitors: state.counters.visitors.load(Ordering::SeqCst),
+        active_sessions: state.counters.active_sessions.load(Ordering::SeqCst),
+    })
+}
+
+/// Метрики сервера в текстовом формате Prometheus
+async fn metrics_route(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    let stats = state
+        .server
+        .stats()
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let body = format!(
+        "# HELP chat_connected_sessions Sessions connected right now.\n\
+         # TYPE chat_connected_sessions gauge\n\
+         chat_connected_sessions {}\n\
+         # HELP chat_rooms_total Rooms that exist right now.\n\
+         # TYPE chat_rooms_total gauge\n\
+         chat_rooms_total {}\n\
+         # HELP chat_messages_total Messages posted to rooms since start.\n\
+         # TYPE chat_messages_total counter\n\
+         chat_messages_total {}\n",
+        stats.sessions, stats.rooms, stats.messages
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
 }
 
 struct WsChatSession {
     /// уникальный идентификатор сессии
     id: usize,
-    /// Клиент должен отправлять ping не реже одного раза в 10 секунд (CLIENT_TIMEOUT), иначе мы разрываем соединение.
+    /// Клиент должен отправлять ping не реже одного раза за `client_timeout`, иначе мы разрываем соединение.
     hb: Instant,
+    /// Как часто отправляются пинги сердцебиения
+    hb_interval: Duration,
+    /// Через какое время отсутствие ответа клиента приводит к тайм-ауту
+    client_timeout: Duration,
+    /// Таймер сердцебиения
+    hb_handle: Option<SpawnHandle>,
+    /// Более длинные сообщения отклоняются без рассылки
+    max_message_len: usize,
+    /// Сообщения без выбранного имени отклоняются
+    require_name: bool,
+    /// Сессия подтвердила токен администратора через `/admin`
+    admin: bool,
+    admin_token: Option<Arc<str>>,
     /// объединённая комната
     room: String,
     /// имя
     name: Option<String>,
-    /// Сервер чата
-    addr: Addr<server::ChatServer>,
+    /// Имена в нижнем регистре, сообщения от которых не показываются
+    ignored: HashSet<String>,
+    /// Пользователь отошел, см. `/away`
+    away: Option<server::Away>,
+    /// Управляемые сбои
+    #[cfg(feature = "chaos")]
+    chaos: Arc<Chaos>,
+    /// Ограничение частоты slash-команд
+    commands: TokenBucket,
+    /// Когда сессия последний раз отправила уведомление о наборе
+    last_typing: Option<Instant>,
+    /// Формат исходящих кадров: согласованный подпротокол, иначе `?protocol=json`
+    /// включает JSON-события, `?encoding=msgpack` события в MessagePack
+    format: Format,
+    /// Фрагменты сообщения, которое еще не пришло целиком
+    fragments: Option<Fragments>,
+    /// Сервер уже знает об отключении сессии
+    disconnected: bool,
+    /// Токен возобновления из `?resume=`, расходуется при подключении
+    resume: Option<String>,
+    /// Адрес клиента
+    peer: Option<SocketAddr>,
+    /// Заголовок `User-Agent` клиента
+    user_agent: Option<String>,
+    /// Части сервера чата, сообщения о комнате идут части, которая ею владеет
+    addr: server::Router,
+}
+
+/// Собираемое из фрагментов сообщение
+enum Fragments {
+    Text(Vec<u8>),
+    Binary(Vec<u8>),
 }
 
 impl Actor for WsChatSession {
@@ -67,15 +780,38 @@ impl Actor for WsChatSession {
         // HttpContext::state() является экземпляром WsChatSessionState, состояние разделяется между всеми маршрутами внутри приложения
         let addr = ctx.address();
         self.addr
-            .send(server::Connect {
-                addr: addr.recipient(),
+            .connect(server::Connect {
+                addr: addr.clone().recipient(),
+                control: addr.recipient(),
+                resume: self.resume.take(),
+                peer: self.peer,
+                user_agent: self.user_agent.clone(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok(Ok(connected)) => {
+                        act.id = connected.id;
+                        act.name = connected.name;
+                        act.away = connected.away;
+                        act.room = connected.room;
+                        match act.format {
+                            Format::Text => ctx.text(format!("resume token {}", connected.token)),
+                            _ => act.send_event(
+                                ctx,
+                                &ServerEvent::Session {
+                                    resume_token: &connected.token,
+                                },
+                            ),
+                        }
+                    }
+                    Ok(Err(full)) => {
+                        // сервер не зарегистрировал сессию, сообщать об отключении некому
+                        act.disconnected = true;
+                        act.close(ctx, ws::CloseCode::Again, &full.to_string());
+                    }
                     // что-то не так с сервером чата
-                    _ => ctx.stop(),
+                    _ => act.close(ctx, ws::CloseCode::Error, "chat server is unavailable"),
                 }
                 fut::ready(())
             })
@@ -84,36 +820,171 @@ impl Actor for WsChatSession {
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         // уведомлять сервер чата
-        self.addr.do_send(server::Disconnect { id: self.id });
+        self.disconnect();
         Running::Stop
     }
 }
 
-/// Обработка сообщений от сервера чата, мы просто отправляем их на одноранговый вебсокет
+/// Обработка сообщений от сервера чата, мы просто отправляем их на одноранговый вебсокет.
+///
+/// Порядковые номера событий комнаты получают только клиенты JSON и MessagePack,
+/// текстовые клиенты получают строки как раньше
 impl Handler<server::Message> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        // системные сообщения не фильтруются
+        if let Some(from) = &msg.from {
+            if self.ignored.contains(&server::name_key(from)) {
+                return;
+            }
+        }
+
+        #[cfg(feature = "chaos")]
+        if self.chaos.should_drop(self.id) {
+            return;
+        }
+
+        self.deliver(ctx, &msg);
+
+        // медленный потребитель не принимает следующие сообщения до конца задержки
+        #[cfg(feature = "chaos")]
+        if let Some(delay) = self.chaos.slow_delay(self.id) {
+            ctx.wait(actix::clock::delay_for(delay).into_actor(self));
+        }
+    }
+}
+
+/// Управляющие сообщения сервера чата
+impl Handler<server::Control> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Control, ctx: &mut Self::Context) {
+        match msg {
+            // сервер уже перевел сессию в другую комнату
+            server::Control::Kicked { to_room } => {
+                self.fail(
+                    ctx,
+                    ErrorCode::Kicked,
+                    format!("you were kicked, moved to {}", to_room),
+                );
+                self.room = to_room;
+            }
+            server::Control::Removed { room } => {
+                self.fail(
+                    ctx,
+                    ErrorCode::Kicked,
+                    format!("you were kicked from {}", room),
+                );
+                self.close(ctx, CLOSE_KICKED.into(), "kicked");
+            }
+            server::Control::Flooded => {
+                self.fail(
+                    ctx,
+                    ErrorCode::RateLimited,
+                    "disconnected for sending too many messages",
+                );
+                self.close(ctx, ws::CloseCode::Policy, "flooding");
+            }
+            server::Control::Announcement { text } => match self.format {
+                Format::Text => ctx.text(format!("[ANNOUNCEMENT] {}", text)),
+                _ => self.send_event(ctx, &ServerEvent::Announcement { text: &text }),
+            },
+            // сервер останавливается, уведомление уже в очереди перед этим сообщением
+            server::Control::Shutdown => {
+                if let Some(handle) = self.hb_handle.take() {
+                    ctx.cancel_future(handle);
+                }
+                self.close(ctx, ws::CloseCode::Away, "server shutting down");
+            }
+            // уведомления о наборе не смешиваются с сообщениями комнаты
+            server::Control::Typing { room, name, typing } => match self.format {
+                Format::Text if typing => ctx.text(format!("~ {} is typing…", name)),
+                Format::Text => ctx.text(format!("~ {} stopped typing", name)),
+                _ => self.send_event(
+                    ctx,
+                    &ServerEvent::Typing {
+                        room: &room,
+                        from: &name,
+                        typing,
+                    },
+                ),
+            },
+            server::Control::Ack {
+                correlation_id,
+                msg_id,
+            } => match self.format {
+                Format::Text => ctx.text(format!("ack {} #{}", correlation_id, msg_id)),
+                _ => self.send_event(
+                    ctx,
+                    &ServerEvent::Ack {
+                        correlation_id: &correlation_id,
+                        msg_id,
+                    },
+                ),
+            },
+            server::Control::Nack {
+                correlation_id,
+                reason,
+            } => self.nack(ctx, correlation_id, reason),
+            server::Control::Error { code, message } => self.fail(ctx, code, message),
+            server::Control::Edited {
+                room,
+                msg_id,
+                from,
+                text,
+            } => match self.format {
+                Format::Text => ctx.text(format!("[#{} edited] {}", msg_id, text)),
+                _ => self.send_event(
+                    ctx,
+                    &ServerEvent::Edit {
+                        room: &room,
+                        msg_id,
+                        from: from.as_deref(),
+                        text: strip_author(from.as_deref(), &text),
+                    },
+                ),
+            },
+            server::Control::Deleted { room, msg_id } => match self.format {
+                Format::Text => ctx.text(format!("[#{} deleted]", msg_id)),
+                _ => self.send_event(
+                    ctx,
+                    &ServerEvent::Delete {
+                        room: &room,
+                        msg_id,
+                    },
+                ),
+            },
+            server::Control::RoomClosed { room, to_room } => {
+                self.reply(
+                    ctx,
+                    format!("room {} closed for inactivity, moved to {}", room, to_room),
+                );
+                self.room = to_room;
+            }
+            // сервер уже удалил сессию, повторный `Disconnect` ничего не изменит
+            server::Control::Idle => self.close(ctx, CLOSE_IDLE.into(), "idle timeout"),
+            #[cfg(feature = "chaos")]
+            server::Control::Dropped => self.close(ctx, ws::CloseCode::Error, "dropped by chaos"),
+        }
     }
 }
 
 /// Обработчик сообщений WebSocket
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
-    fn handle(
-        &mut self,
-        msg: Result<ws::Message, ws::ProtocolError>,
-        ctx: &mut Self::Context,
-    ) {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
             Err(_) => {
-                ctx.stop();
+                self.close(ctx, ws::CloseCode::Protocol, "protocol error");
                 return;
             }
             Ok(msg) => msg,
         };
 
-        println!("WEBSOCKET MESSAGE: {:?}", msg);
+        trace!("session {} in {}: frame {:?}", self.id, self.room, msg);
+        self.addr
+            .shard(&self.room)
+            .do_send(server::Touch { id: self.id });
         match msg {
             ws::Message::Ping(msg) => {
                 self.hb = Instant::now();
@@ -122,127 +993,2338 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Text(text) => {
-                let m = text.trim();
-                // мы проверяем сообщения типа /sss
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Отправьте сообщение ListRooms на сервер чата и дождитесь ответа
-                            println!("List rooms");
-                            self.addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
-                                        }
-                                        _ => println!("Something is wrong"),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) приостанавливает все события в контексте, поэтому актор не будет получать новые сообщения, пока не получит список комнат обратно
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                self.room = v[1].to_owned();
-                                self.addr.do_send(server::Join {
-                                    id: self.id,
-                                    name: self.room.clone(),
-                                });
-
-                                ctx.text("joined");
-                            } else {
-                                ctx.text("!!! room name is required");
-                            }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {:?}", m)),
-                    }
-                } else {
-                    let msg = if let Some(ref name) = self.name {
-                        format!("{}: {}", name, m)
-                    } else {
-                        m.to_owned()
-                    };
-                    // отправить сообщение на сервер чата
-                    self.addr.do_send(server::ClientMessage {
-                        id: self.id,
-                        msg,
-                        room: self.room.clone(),
-                    })
-                }
-            }
-            ws::Message::Binary(_) => println!("Unexpected binary"),
+            ws::Message::Text(text) => self.handle_text(&text, ctx),
+            ws::Message::Binary(bin) => self.handle_binary(&bin, ctx),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
             }
-            ws::Message::Continuation(_) => {
-                ctx.stop();
-            }
+            ws::Message::Continuation(item) => self.handle_continuation(item, ctx),
             ws::Message::Nop => (),
         }
     }
 }
 
 impl WsChatSession {
-    /// вспомогательный метод, который отправляет ping клиенту каждую секунду.
-    /// также этот метод проверяет сердцебиение клиента
-    fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            // проверять сердцебиение клиента
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                // сердцебиение прервано
-                println!("Websocket Client heartbeat failed, disconnecting!");
-
-                // уведомлять сервер чата
-                act.addr.do_send(server::Disconnect { id: act.id });
-
-                // остановить актёра
-                ctx.stop();
-
-                // не пытайтесь посылать ping
-                return;
-            }
+    /// Сообщить серверу об отключении сессии, но только один раз
+    fn disconnect(&mut self) {
+        if !self.disconnected {
+            self.disconnected = true;
+            self.addr.disconnect(self.id);
+        }
+    }
 
-            ctx.ping(b"");
-        });
+    /// Отправить клиенту кадр закрытия с кодом и причиной и остановить сессию,
+    /// чтобы клиент отличал отказ сервера от обрыва сети
+    fn close(&mut self, ctx: &mut ws::WebsocketContext<Self>, code: ws::CloseCode, reason: &str) {
+        ctx.close(Some(ws::CloseReason {
+            code,
+            description: Some(reason.to_owned()),
+        }));
+        self.disconnect();
+        ctx.stop();
     }
-}
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    /// Текст ошибки о слишком длинном сообщении, с пределом в байтах
+    fn too_long(&self) -> String {
+        format!("message too long (max {} bytes)", self.max_message_len)
+    }
 
-    // Состояние приложения
-    // Мы ведем подсчет количества посетителей
-    let app_state = Arc::new(AtomicUsize::new(0));
+    /// Обработать текстовое сообщение клиента
+    fn handle_text(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if text.len() > self.max_message_len {
+            // отправитель события с `correlation_id` ждет ответа именно на него
+            match protocol::parse(text.trim()) {
+                Ok(Some(ClientEvent::Send {
+                    correlation_id: Some(id),
+                    ..
+                })) => self.nack(ctx, id, server::NackReason::TooLong),
+                _ => self.fail(ctx, ErrorCode::MessageTooLong, self.too_long()),
+            }
+            return;
+        }
 
-    // Запуск актера сервера чата
-    let server = server::ChatServer::new(app_state.clone()).start();
+        let m = text.trim();
+        // структурированные события в JSON
+        match protocol::parse(m) {
+            Ok(Some(event)) => {
+                self.dispatch(event, ctx);
+                return;
+            }
+            Err(err) if self.format != Format::Text => {
+                self.fail(
+                    ctx,
+                    ErrorCode::MalformedEvent,
+                    format!("malformed event: {}", err),
+                );
+                return;
+            }
+            // в текстовом режиме не-событие остается обычным сообщением
+            _ => (),
+        }
+        // мы проверяем сообщения типа /sss
+        if m.starts_with('/') {
+            if !self.commands.check(Instant::now()) {
+                self.fail(ctx, ErrorCode::RateLimited, "too many commands, slow down");
+                return;
+            }
 
-    // Создание Http-сервера с поддержкой вебсокета
-    HttpServer::new(move || {
-        App::new()
-            .data(app_state.clone())
-            .data(server.clone())
-            .route("/count/", web::get().to(get_count))
-            // websocket
-            .service(web::resource("/ws/").to(chat_route))
-    })
-    .bind("127.0.0.1:8081")?
-    .run()
-    .await
-}
\ No newline at end of file
+            let v: Vec<&str> = m.splitn(2, ' ').collect();
+            match v[0] {
+                "/list" => {
+                    let filter = v.get(1).map(|f| f.trim()).filter(|f| !f.is_empty());
+                    self.list_rooms(filter, ctx)
+                }
+                // все уже известно сессии, сервер не нужен
+                "/whoami" => {
+                    let name = self.name.as_deref().unwrap_or("(unset)");
+                    let line = format!("id {}, name {}, room {}", self.id, name, self.room);
+                    self.reply(ctx, line);
+                }
+                // комната узнает об уходе сразу, а не по таймауту
+                "/quit" => self.close(ctx, ws::CloseCode::Normal, "bye"),
+                "/help" => {
+                    let help: Vec<String> = COMMANDS
+                        .iter()
+                        .map(|(cmd, about)| format!("{} - {}", cmd, about))
+                        .collect();
+                    self.reply(ctx, help.join("\n"));
+                }
+                "/ignore" => {
+                    if v.len() == 2 {
+                        self.ignored.insert(server::name_key(v[1]));
+                        self.reply(ctx, format!("ignoring {}", v[1]));
+                    } else {
+                        let mut ignored: Vec<&str> =
+                            self.ignored.iter().map(String::as_str).collect();
+                        ignored.sort_unstable();
+                        self.reply(ctx, format!("ignored: {}", ignored.join(", ")));
+                    }
+                }
+                "/unignore" => {
+                    if v.len() == 2 {
+                        if self.ignored.remove(&server::name_key(v[1])) {
+                            self.reply(ctx, format!("no longer ignoring {}", v[1]));
+                        } else {
+                            self.fail(ctx, ErrorCode::Conflict, format!("{} is not ignored", v[1]));
+                        }
+                    } else {
+                        self.fail(ctx, ErrorCode::MissingArgument, "name is required");
+                    }
+                }
+                "/history" => {
+                    let limit = match v.get(1).map(|n| n.trim().parse()) {
+                        None => HISTORY_DEFAULT,
+                        Some(Ok(n)) => n,
+                        Some(Err(_)) => {
+                            self.fail(ctx, ErrorCode::InvalidArgument, "usage: /history [n]");
+                            return;
+                        }
+                    };
+                    self.addr
+                        .shard(&self.room)
+                        .send(server::GetHistory {
+                            room: self.room.clone(),
+                            limit,
+                        })
+                        .into_actor(self)
+                        .then(|res, act, ctx| {
+                            match res {
+                                Ok(history) => {
+                                    for entry in history {
+                                        act.reply(
+                                            ctx,
+                                            format!(
+                                                "[#{}] [{}] {}",
+                                                entry.msg_id,
+                                                format_time(entry.ts),
+                                                entry.text
+                                            ),
+                                        );
+                                    }
+                                }
+                                _ => {
+                                    error!("session {}: chat server is unavailable", act.id)
+                                }
+                            }
+                            fut::ready(())
+                        })
+                        .wait(ctx)
+                }
+                "/resync" => match v.get(1).map(|id| id.trim().parse()) {
+                    Some(Ok(since_id)) => self.resync(since_id, ctx),
+                    _ => self.fail(ctx, ErrorCode::InvalidArgument, "usage: /resync <id>"),
+                },
+                "/admin" => {
+                    let valid = v.len() == 2
+                        && self
+                            .admin_token
+                            .as_deref()
+                            .is_some_and(|admin| server::secrets_match(admin, v[1]));
+                    if valid {
+                        self.admin = true;
+                        self.reply(ctx, "admin mode enabled");
+                    } else {
+                        self.fail(ctx, ErrorCode::NotPermitted, "not authorized");
+                    }
+                }
+                "/fsck" => {
+                    if !self.admin {
+                        self.fail(ctx, ErrorCode::NotPermitted, "not authorized");
+                        return;
+                    }
+                    let repair = v.get(1) == Some(&"repair");
+                    self.addr
+                        .fsck(repair)
+                        .into_actor(self)
+                        .then(|res, act, ctx| {
+                            match res {
+                                Ok(violations) if violations.is_empty() => {
+                                    act.reply(ctx, "fsck: ok")
+                                }
+                                Ok(violations) => {
+                                    for violation in violations {
+                                        act.reply(ctx, format!("fsck: {}", violation));
+                                    }
+                                }
+                                _ => {
+                                    error!("session {}: chat server is unavailable", act.id)
+                                }
+                            }
+                            fut::ready(())
+                        })
+                        .wait(ctx)
+                }
+                "/status" => {
+                    let status = match v.get(1).map(|s| s.trim()) {
+                        Some("clear") => None,
+                        Some(status) => Some(status.to_owned()),
+                        None => {
+                            self.fail(
+                                ctx,
+                                ErrorCode::MissingArgument,
+                                "usage: /status <text> or /status clear",
+                            );
+                            return;
+                        }
+                    };
+                    self.addr
+                        .shard(&self.room)
+                        .send(server::SetStatus {
+                            id: self.id,
+                            status,
+                        })
+                        .into_actor(self)
+                        .then(|res, act, ctx| {
+                            match res {
+                                Ok(Ok(())) => act.reply(ctx, "status updated"),
+                                Ok(Err(err)) => act.fail(ctx, err.code(), err),
+                                _ => {
+                                    error!("session {}: chat server is unavailable", act.id)
+                                }
+                            }
+                            fut::ready(())
+                        })
+                        .wait(ctx)
+                }
+                "/who" => self.who(ctx),
+                "/typing" => self.typing(),
+                "/file" => match v.get(1).map(|url| url.trim()) {
+                    Some(url)
+                        if url.starts_with("/files/")
+                            || url.starts_with("http://")
+                            || url.starts_with("https://") =>
+                    {
+                        self.send_chat(&format!("shared a file: {}", url), None, ctx)
+                    }
+                    Some(_) => self.fail(ctx, ErrorCode::InvalidArgument, "not a file link"),
+                    None => self.fail(ctx, ErrorCode::MissingArgument, "usage: /file <url>"),
+                },
+                "/me" => match (&self.name, v.get(1)) {
+                    (None, _) => {
+                        self.fail(ctx, ErrorCode::NotPermitted, "set a name first with /name")
+                    }
+                    (Some(name), Some(action)) => {
+                        let msg = format!("* {} {}", name, action.trim());
+                        self.post(msg, None);
+                    }
+                    (Some(_), None) => {
+                        self.fail(ctx, ErrorCode::MissingArgument, "usage: /me <action>")
+                    }
+                },
+                "/stats" => match v.get(1) {
+                    Some(room) => self.room_stats(room.trim().to_owned(), ctx),
+                    None => self.server_stats(ctx),
+                },
+                "/away" | "/back" => {
+                    // без причины `/away` возвращает из отсутствия
+                    let away = match v.get(1).map(|r| r.trim()) {
+                        _ if v[0] == "/back" => None,
+                        None | Some("") if self.away.is_some() => None,
+                        None | Some("") => Some(server::Away { reason: None }),
+                        Some(reason) => Some(server::Away {
+                            reason: Some(reason.to_owned()),
+                        }),
+                    };
+                    self.addr
+                        .shard(&self.room)
+                        .send(server::SetAway {
+                            id: self.id,
+                            away: away.clone(),
+                        })
+                        .into_actor(self)
+                        .then(|res, act, ctx| {
+                            match res {
+                                Ok(Ok(())) => {
+                                    act.reply(
+                                        ctx,
+                                        if away.is_some() {
+                                            "you are away"
+                                        } else {
+                                            "welcome back"
+                                        },
+                                    );
+                                    act.away = away;
+                                }
+                                Ok(Err(err)) => act.fail(ctx, err.code(), err),
+                                _ => {
+                                    error!("session {}: chat server is unavailable", act.id)
+                                }
+                            }
+                            fut::ready(())
+                        })
+                        .wait(ctx)
+                }
+                "/join" => {
+                    if v.len() == 2 {
+                        let mut args = v[1].trim_start().splitn(2, ' ');
+                        let room = args.next().unwrap_or_default().to_owned();
+                        let mut rest = args.next();
+                        // необязательный "max=N" перед паролем задает вместимость
+                        let mut capacity = None;
+                        if let Some(max) = rest.and_then(|r| r.strip_prefix("max=")) {
+                            let mut max = max.splitn(2, ' ');
+                            match max.next().unwrap_or_default().parse() {
+                                Ok(n) => capacity = Some(n),
+                                Err(_) => {
+                                    self.fail(
+                                        ctx,
+                                        ErrorCode::InvalidArgument,
+                                        "usage: /join <room> [max=N] [password]",
+                                    );
+                                    return;
+                                }
+                            }
+                            rest = max.next();
+                        }
+                        let password = rest.map(str::to_owned);
+                        self.join(room, password, capacity, ctx);
+                    } else {
+                        self.fail(ctx, ErrorCode::MissingArgument, "room name is required");
+                    }
+                }
+                "/name" | "/nick" => {
+                    if v.len() == 2 {
+                        self.set_name(v[1].to_owned(), ctx);
+                    } else {
+                        self.fail(ctx, ErrorCode::MissingArgument, "name is required");
+                    }
+                }
+                "/kick" => {
+                    if v.len() == 2 {
+                        let msg = server::Kick {
+                            by: self.id,
+                            room: self.room.clone(),
+                            target_name: v[1].to_owned(),
+                        };
+                        self.moderate(msg, ctx);
+                    } else {
+                        self.fail(ctx, ErrorCode::MissingArgument, "name is required");
+                    }
+                }
+                "/ban" => {
+                    if v.len() == 2 {
+                        let msg = server::Ban {
+                            by: self.id,
+                            room: self.room.clone(),
+                            target_name: v[1].to_owned(),
+                        };
+                        self.moderate(msg, ctx);
+                    } else {
+                        self.fail(ctx, ErrorCode::MissingArgument, "name is required");
+                    }
+                }
+                "/unban" => {
+                    if v.len() == 2 {
+                        let msg = server::Unban {
+                            by: self.id,
+                            room: self.room.clone(),
+                            target_name: v[1].to_owned(),
+                        };
+                        self.moderate(msg, ctx);
+                    } else {
+                        self.fail(ctx, ErrorCode::MissingArgument, "name is required");
+                    }
+                }
+                "/mute" => {
+                    if v.len() == 2 {
+                        let msg = server::Mute {
+                            by: self.id,
+                            room: self.room.clone(),
+                            target_name: v[1].to_owned(),
+                        };
+                        self.moderate(msg, ctx);
+                    } else {
+                        self.fail(ctx, ErrorCode::MissingArgument, "name is required");
+                    }
+                }
+                "/promote" | "/demote" => {
+                    if v.len() == 2 {
+                        let (by, room, target_name) = (self.id, self.room.clone(), v[1].to_owned());
+                        if v[0] == "/promote" {
+                            self.moderate(
+                                server::Promote {
+                                    by,
+                                    room,
+                                    target_name,
+                                },
+                                ctx,
+                            );
+                        } else {
+                            self.moderate(
+                                server::Demote {
+                                    by,
+                                    room,
+                                    target_name,
+                                },
+                                ctx,
+                            );
+                        }
+                    } else {
+                        self.fail(ctx, ErrorCode::MissingArgument, "name is required");
+                    }
+                }
+                "/edit" => {
+                    let args = v.get(1).map(|args| args.trim()).unwrap_or("");
+                    let (id, text) = args.split_once(' ').unwrap_or((args, ""));
+                    match (parse_msg_id(id), text.trim()) {
+                        (Some(msg_id), text) if !text.is_empty() => {
+                            self.edit(msg_id, Some(text.to_owned()), ctx)
+                        }
+                        _ => self.fail(ctx, ErrorCode::MissingArgument, "usage: /edit <id> <text>"),
+                    }
+                }
+                "/delete" => match v.get(1).and_then(|id| parse_msg_id(id.trim())) {
+                    Some(msg_id) => self.edit(msg_id, None, ctx),
+                    None => self.fail(ctx, ErrorCode::MissingArgument, "usage: /delete <id>"),
+                },
+                "/clear" => {
+                    let msg = server::ClearHistory {
+                        room: self.room.clone(),
+                        requester_id: self.id,
+                    };
+                    self.moderate(msg, ctx);
+                }
+                "/topic" => match v.get(1).map(|topic| topic.trim()) {
+                    None | Some("") => self.show_topic(ctx),
+                    Some(topic) if topic.graphemes(true).count() > MAX_TOPIC_LEN => self.fail(
+                        ctx,
+                        ErrorCode::InvalidArgument,
+                        format!("topic is longer than {} characters", MAX_TOPIC_LEN),
+                    ),
+                    Some(topic) => {
+                        let msg = server::SetTopic {
+                            by: self.id,
+                            room: self.room.clone(),
+                            topic: topic.chars().filter(|c| !c.is_control()).collect(),
+                        };
+                        self.moderate(msg, ctx);
+                    }
+                },
+                "/slowmode" => {
+                    let interval = match v.get(1).map(|arg| arg.trim()) {
+                        Some("off") | Some("0") => Ok(None),
+                        Some(secs) => match secs.parse::<u64>() {
+                            Ok(secs) if (1..=MAX_SLOW_MODE.as_secs()).contains(&secs) => {
+                                Ok(Some(Duration::from_secs(secs)))
+                            }
+                            _ => Err(format!(
+                                "interval must be 1 to {} seconds, or 0 or off",
+                                MAX_SLOW_MODE.as_secs()
+                            )),
+                        },
+                        None => Err("usage: /slowmode <seconds|off>".to_owned()),
+                    };
+                    match interval {
+                        Ok(interval) => {
+                            let msg = server::SetSlowMode {
+                                by: self.id,
+                                room: self.room.clone(),
+                                interval,
+                            };
+                            self.moderate(msg, ctx);
+                        }
+                        Err(err) => self.fail(ctx, ErrorCode::InvalidArgument, err),
+                    }
+                }
+                "/unmute" => {
+                    if v.len() == 2 {
+                        let msg = server::Unmute {
+                            by: self.id,
+                            room: self.room.clone(),
+                            target_name: v[1].to_owned(),
+                        };
+                        self.moderate(msg, ctx);
+                    } else {
+                        self.fail(ctx, ErrorCode::MissingArgument, "name is required");
+                    }
+                }
+                "/invite" => {
+                    if v.len() == 2 {
+                        let target_name = v[1].to_owned();
+                        self.addr
+                            .shard(&self.room)
+                            .send(server::Invite {
+                                by: self.id,
+                                room: self.room.clone(),
+                                target_name: target_name.clone(),
+                            })
+                            .into_actor(self)
+                            .then(move |res, act, ctx| {
+                                match res {
+                                    Ok(Ok(())) => act.reply(
+                                        ctx,
+                                        format!("invited {} to {}", target_name, act.room),
+                                    ),
+                                    Ok(Err(err)) => act.fail(ctx, err.code(), err),
+                                    _ => error!("session {}: chat server is unavailable", act.id),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx);
+                    } else {
+                        self.fail(ctx, ErrorCode::MissingArgument, "name is required");
+                    }
+                }
+                "/roomopt" => {
+                    let args: Vec<&str> = v
+                        .get(1)
+                        .map_or(Vec::new(), |a| a.split_whitespace().collect());
+                    let option = match args.as_slice() {
+                        ["announcements", mode] => {
+                            mode.parse().ok().map(server::RoomOption::Announcements)
+                        }
+                        ["statuses", "on"] => Some(server::RoomOption::Statuses(true)),
+                        ["statuses", "off"] => Some(server::RoomOption::Statuses(false)),
+                        _ => None,
+                    };
+                    match option {
+                        Some(option) => self
+                            .addr
+                            .shard(&self.room)
+                            .send(server::SetRoomOption {
+                                by: self.id,
+                                room: self.room.clone(),
+                                option,
+                            })
+                            .into_actor(self)
+                            .then(|res, act, ctx| {
+                                match res {
+                                    Ok(Ok(())) => act.reply(ctx, "room option updated"),
+                                    Ok(Err(err)) => act.fail(ctx, err.code(), err),
+                                    _ => error!("session {}: chat server is unavailable", act.id),
+                                }
+                                fut::ready(())
+                            })
+                            .wait(ctx),
+                        None => self.fail(
+                            ctx,
+                            ErrorCode::InvalidArgument,
+                            "usage: /roomopt announcements full|counts_only|silent \
+                             or /roomopt statuses on|off",
+                        ),
+                    }
+                }
+                _ => self.fail(
+                    ctx,
+                    ErrorCode::UnknownCommand,
+                    format!("unknown command: {:?}", m),
+                ),
+            }
+        } else {
+            self.send_chat(m, None, ctx);
+        }
+    }
+
+    /// Обработать бинарное сообщение клиента, они бывают только в режиме MessagePack
+    fn handle_binary(&mut self, bin: &[u8], ctx: &mut ws::WebsocketContext<Self>) {
+        if self.format != Format::MsgPack {
+            warn!(
+                "session {} in {}: unexpected binary frame",
+                self.id, self.room
+            );
+            return;
+        }
+        match rmp_serde::from_slice::<ClientEvent>(bin) {
+            Ok(event) => self.dispatch(event, ctx),
+            Err(err) => self.fail(
+                ctx,
+                ErrorCode::MalformedEvent,
+                format!("malformed event: {}", err),
+            ),
+        }
+    }
+
+    /// Собрать фрагментированное сообщение и обработать его целиком.
+    /// Если сборка превышает `max_message_len` (`CHAT_MAX_MESSAGE_LEN`), клиент
+    /// получает ошибку и сессия закрывается с кодом нарушения политики
+    fn handle_continuation(&mut self, item: Item, ctx: &mut ws::WebsocketContext<Self>) {
+        let (chunk, last) = match item {
+            Item::FirstText(chunk) => {
+                self.fragments = Some(Fragments::Text(Vec::new()));
+                (chunk, false)
+            }
+            Item::FirstBinary(chunk) => {
+                self.fragments = Some(Fragments::Binary(Vec::new()));
+                (chunk, false)
+            }
+            Item::Continue(chunk) => (chunk, false),
+            Item::Last(chunk) => (chunk, true),
+        };
+
+        let buf = match &mut self.fragments {
+            Some(Fragments::Text(buf)) | Some(Fragments::Binary(buf)) => buf,
+            // продолжение без начала
+            None => {
+                self.close(
+                    ctx,
+                    ws::CloseCode::Protocol,
+                    "unexpected continuation frame",
+                );
+                return;
+            }
+        };
+        if buf.len() + chunk.len() > self.max_message_len {
+            warn!(
+                "session {} in {}: fragmented message too long",
+                self.id, self.room
+            );
+            self.fragments = None;
+            self.fail(ctx, ErrorCode::MessageTooLong, self.too_long());
+            self.close(ctx, ws::CloseCode::Policy, "message too long");
+            return;
+        }
+        buf.extend_from_slice(&chunk);
+
+        if !last {
+            return;
+        }
+        match self.fragments.take() {
+            Some(Fragments::Text(buf)) => match String::from_utf8(buf) {
+                Ok(text) => self.handle_text(&text, ctx),
+                Err(_) => self.close(ctx, ws::CloseCode::Invalid, "invalid utf-8"),
+            },
+            Some(Fragments::Binary(buf)) => self.handle_binary(&buf, ctx),
+            None => (),
+        }
+    }
+
+    /// Запросить первые `LIST_LIMIT` комнат, самые населенные первыми
+    fn list_rooms(&mut self, filter: Option<&str>, ctx: &mut ws::WebsocketContext<Self>) {
+        // Отправьте сообщение ListRooms на сервер чата и дождитесь ответа
+        debug!("session {}: list rooms", self.id);
+        self.addr
+            .list_rooms(server::ListRooms {
+                filter: filter.map(str::to_owned),
+                offset: 0,
+                limit: LIST_LIMIT,
+                sort: server::RoomSort::Members,
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(page) => {
+                        let hidden = page.total - page.rooms.len();
+                        for room in page.rooms {
+                            let lock = if room.protected { " 🔒" } else { "" };
+                            let members = match room.capacity {
+                                Some(capacity) => format!("{}/{}", room.members, capacity),
+                                None => room.members.to_string(),
+                            };
+                            act.reply(ctx, format!("{} ({}){}", room.name, members, lock));
+                        }
+                        if hidden > 0 {
+                            act.reply(ctx, format!("... and {} more", hidden));
+                        }
+                    }
+                    _ => error!("session {}: chat server is unavailable", act.id),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+        // .wait(ctx) приостанавливает все события в контексте, поэтому актор не будет получать новые сообщения, пока не получит список комнат обратно
+    }
+
+    /// Снимок участников с номером события, на котором он сделан
+    fn who(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        self.addr
+            .shard(&self.room)
+            .send(server::Who {
+                room: self.room.clone(),
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Some(who)) => {
+                        let members: Vec<String> = who
+                            .members
+                            .into_iter()
+                            .map(|member| {
+                                let mut line = match member.role {
+                                    server::Role::Owner => format!("{} [owner]", member.name),
+                                    server::Role::Moderator => format!("{} [mod]", member.name),
+                                    server::Role::Member => member.name,
+                                };
+                                if let Some(status) = member.status {
+                                    line = format!("{} ({})", line, status);
+                                }
+                                match member.away.map(|away| away.reason) {
+                                    Some(Some(reason)) => format!("{} [away: {}]", line, reason),
+                                    Some(None) => format!("{} [away]", line),
+                                    None => line,
+                                }
+                            })
+                            .collect();
+                        act.reply(
+                            ctx,
+                            format!(
+                                "[#{}] members: {} (+{} guests)",
+                                who.seq,
+                                members.join(", "),
+                                who.guests
+                            ),
+                        )
+                    }
+                    Ok(None) => act.fail(ctx, ErrorCode::RoomNotFound, "room not found"),
+                    _ => error!("session {}: chat server is unavailable", act.id),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    /// Ответить общими счетчиками сервера, а затем счетчиками текущей комнаты
+    fn server_stats(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        self.addr
+            .stats()
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(stats) => {
+                        act.reply(
+                            ctx,
+                            format!(
+                                "server: {} sessions, {} rooms, {} messages",
+                                stats.sessions, stats.rooms, stats.messages
+                            ),
+                        );
+                        act.room_stats(act.room.clone(), ctx);
+                    }
+                    _ => error!("session {}: chat server is unavailable", act.id),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    /// Ответить счетчиками комнаты
+    fn room_stats(&mut self, room: String, ctx: &mut ws::WebsocketContext<Self>) {
+        self.addr
+            .shard(&room)
+            .send(server::RoomStats { room: room.clone() })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Some(stats)) => act.reply(
+                        ctx,
+                        format!(
+                            "{}: {} members, {} messages, created {}, last activity {}",
+                            room,
+                            stats.members,
+                            stats.messages,
+                            format_time(stats.created),
+                            format_time(stats.last_activity)
+                        ),
+                    ),
+                    Ok(None) => act.fail(ctx, ErrorCode::RoomNotFound, "room not found"),
+                    _ => error!("session {}: chat server is unavailable", act.id),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    /// Перейти в комнату. Комната меняется только если сервер пустил в неё
+    fn join(
+        &mut self,
+        room: String,
+        password: Option<String>,
+        capacity: Option<usize>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let room = match server::normalize_room_name(&room) {
+            Some(room) => room.to_owned(),
+            None => {
+                self.fail(ctx, ErrorCode::InvalidArgument, "invalid room name");
+                return;
+            }
+        };
+        self.addr
+            .shard(&self.room)
+            .send(server::Join {
+                id: self.id,
+                name: room.clone(),
+                password,
+                capacity,
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {
+                        act.room = room;
+                        act.reply(ctx, "joined");
+                    }
+                    Ok(Err(err)) => act.fail(ctx, err.code(), err),
+                    _ => error!("session {}: chat server is unavailable", act.id),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    /// Сменить имя. Имя меняется только после подтверждения сервером
+    fn set_name(&mut self, name: String, ctx: &mut ws::WebsocketContext<Self>) {
+        let name = match server::normalize_user_name(&name) {
+            Some(name) => name.to_owned(),
+            None => {
+                self.fail(ctx, ErrorCode::InvalidArgument, "invalid name");
+                return;
+            }
+        };
+        self.addr
+            .shard(&self.room)
+            .send(server::SetName {
+                id: self.id,
+                name: name.clone(),
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(server::SetNameResult::Ok) => act.name = Some(name),
+                    Ok(server::SetNameResult::Taken) => {
+                        act.fail(ctx, ErrorCode::NameTaken, "name already taken")
+                    }
+                    Ok(server::SetNameResult::Invalid) => {
+                        act.fail(ctx, ErrorCode::InvalidArgument, "invalid name")
+                    }
+                    Ok(server::SetNameResult::Unavailable) => {
+                        act.fail(ctx, ErrorCode::Conflict, "session is busy, try again")
+                    }
+                    _ => error!("session {}: chat server is unavailable", act.id),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    /// Отправить сообщение в текущую комнату. С `correlation_id` клиент
+    /// получит подтверждение или отказ
+    fn send_chat(
+        &mut self,
+        text: &str,
+        correlation_id: Option<String>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        // кадр уже проверен, но событие в MessagePack приходит бинарным кадром
+        if text.len() > self.max_message_len {
+            match correlation_id {
+                Some(id) => self.nack(ctx, id, server::NackReason::TooLong),
+                None => self.fail(ctx, ErrorCode::MessageTooLong, self.too_long()),
+            }
+            return;
+        }
+
+        let msg = match self.name {
+            Some(ref name) => format!("{}: {}", name, text),
+            None if self.require_name => {
+                match correlation_id {
+                    Some(id) => self.nack(ctx, id, server::NackReason::NameRequired),
+                    None => self.fail(
+                        ctx,
+                        ErrorCode::NotPermitted,
+                        "choose a name with /name first",
+                    ),
+                }
+                return;
+            }
+            None => text.to_owned(),
+        };
+        self.post(msg, correlation_id);
+    }
+
+    /// Повторить сообщения текущей комнаты после `since_id`. Если часть из них
+    /// уже вытеснена из истории, сначала приходит пометка `history truncated`
+    fn resync(&mut self, since_id: u64, ctx: &mut ws::WebsocketContext<Self>) {
+        self.addr
+            .shard(&self.room)
+            .send(server::Resync {
+                room: self.room.clone(),
+                since_id,
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(replay) => {
+                        if replay.truncated {
+                            act.reply(ctx, "history truncated");
+                        }
+                        for entry in replay.entries {
+                            let msg = server::Message {
+                                text: entry.text,
+                                msg_id: Some(entry.msg_id),
+                                prev_id: None,
+                                ts: entry.ts,
+                                from: entry.from,
+                                replay: true,
+                                mention: None,
+                                via: None,
+                            };
+                            act.deliver(ctx, &msg);
+                        }
+                    }
+                    _ => error!("session {}: chat server is unavailable", act.id),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Сообщить комнате, что пользователь набирает сообщение, не чаще раза в `TYPING_INTERVAL`
+    fn typing(&mut self) {
+        let now = Instant::now();
+        if self
+            .last_typing
+            .is_some_and(|last| now.duration_since(last) < TYPING_INTERVAL)
+        {
+            return;
+        }
+        self.last_typing = Some(now);
+        self.addr.shard(&self.room).do_send(server::Typing {
+            id: self.id,
+            room: self.room.clone(),
+        });
+    }
+
+    /// Отправить готовую строку в текущую комнату, частоту ограничивает сервер
+    fn post(&mut self, msg: String, correlation_id: Option<String>) {
+        // сервер сам вернет из отсутствия
+        self.away = None;
+
+        // отправить сообщение на сервер чата
+        self.addr.shard(&self.room).do_send(server::ClientMessage {
+            id: self.id,
+            msg,
+            room: self.room.clone(),
+            correlation_id,
+        })
+    }
+
+    /// Отказать в сообщении с `correlation_id`
+    fn nack(
+        &self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        correlation_id: String,
+        reason: server::NackReason,
+    ) {
+        match self.format {
+            Format::Text => ctx.text(format!("!!! nack {}: {}", correlation_id, reason)),
+            _ => self.send_event(
+                ctx,
+                &ServerEvent::Nack {
+                    correlation_id: &correlation_id,
+                    reason,
+                },
+            ),
+        }
+    }
+
+    /// Выполнить событие клиента так же, как соответствующую slash-команду
+    fn dispatch(&mut self, event: ClientEvent, ctx: &mut ws::WebsocketContext<Self>) {
+        // сообщения ограничиваются в send_chat, набор сервер ограничивает сам,
+        // остальное как команды
+        let limited = !matches!(event, ClientEvent::Send { .. } | ClientEvent::Typing);
+        if limited && !self.commands.check(Instant::now()) {
+            self.fail(ctx, ErrorCode::RateLimited, "too many commands, slow down");
+            return;
+        }
+        match event {
+            ClientEvent::Send {
+                text,
+                correlation_id,
+            } => self.send_chat(&text, correlation_id, ctx),
+            ClientEvent::Join {
+                room,
+                password,
+                capacity,
+            } => self.join(room, password, capacity, ctx),
+            ClientEvent::Name { name } => self.set_name(name, ctx),
+            ClientEvent::List { filter } => self.list_rooms(filter.as_deref(), ctx),
+            ClientEvent::Users => self.who(ctx),
+            ClientEvent::Typing => self.typing(),
+            ClientEvent::Resync { since_id } => self.resync(since_id, ctx),
+            ClientEvent::Edit { msg_id, text } => self.edit(msg_id, Some(text), ctx),
+            ClientEvent::Delete { msg_id } => self.edit(msg_id, None, ctx),
+        }
+    }
+
+    /// Отправить клиенту сообщение сервера чата в формате сессии
+    fn deliver(&self, ctx: &mut ws::WebsocketContext<Self>, msg: &server::Message) {
+        match self.format {
+            // упоминание из другой комнаты называет её
+            Format::Text if msg.mention.is_some() => match msg.mention.as_deref() {
+                Some(room) if room != self.room => {
+                    ctx.text(format!("[mention in {}] {}", room, msg.text))
+                }
+                _ => ctx.text(format!("[mention] {}", msg.text)),
+            },
+            Format::Text if msg.replay => ctx.text(format!("[replay] {}", msg.text)),
+            // номер ломает построчных клиентов, которые номеров не ждут
+            Format::Text => ctx.text(&*msg.text),
+            Format::Json | Format::MsgPack => {
+                let from = msg.from.as_deref();
+                let text = strip_author(from, &msg.text);
+                let event = ServerEvent::Message {
+                    room: msg
+                        .msg_id
+                        .map(|_| msg.mention.as_deref().unwrap_or(&self.room)),
+                    from,
+                    text,
+                    msg_id: msg.msg_id,
+                    prev_id: msg.prev_id,
+                    ts: unix_time(msg.ts),
+                    replay: msg.replay,
+                    mention: msg.mention.is_some(),
+                    via: msg.via,
+                };
+                self.send_event(ctx, &event);
+            }
+        }
+    }
+
+    /// Отправить событие в кодировке сессии
+    fn send_event(&self, ctx: &mut ws::WebsocketContext<Self>, event: &ServerEvent) {
+        match self.format {
+            Format::MsgPack => ctx.binary(event.to_msgpack()),
+            _ => ctx.text(event.to_json()),
+        }
+    }
+
+    /// Отправить клиенту строку ответа в формате сессии
+    fn reply(&self, ctx: &mut ws::WebsocketContext<Self>, line: impl Into<String>) {
+        let line = line.into();
+        match self.format {
+            Format::Text => ctx.text(line),
+            _ => self.send_event(ctx, &ServerEvent::Notice { text: &line }),
+        }
+    }
+
+    /// Сообщить клиенту об ошибке: событием `error` с кодом или, в
+    /// текстовом режиме, прежней строкой `!!! ...`
+    fn fail(
+        &self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        code: ErrorCode,
+        message: impl fmt::Display,
+    ) {
+        let message = message.to_string();
+        match self.format {
+            Format::Text => ctx.text(format!("!!! {}", message)),
+            _ => self.send_event(
+                ctx,
+                &ServerEvent::Error {
+                    code,
+                    message: &message,
+                },
+            ),
+        }
+    }
+
+    /// Показать тему текущей комнаты
+    fn show_topic(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        self.addr
+            .shard(&self.room)
+            .send(server::GetTopic {
+                room: self.room.clone(),
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Some(topic)) => act.reply(ctx, format!("topic: {}", topic)),
+                    Ok(None) => act.reply(ctx, "no topic set"),
+                    _ => error!("session {}: chat server is unavailable", act.id),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Исправить (`text`) или удалить свое сообщение `msg_id` текущей комнаты
+    fn edit(&mut self, msg_id: u64, text: Option<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        if text
+            .as_ref()
+            .is_some_and(|text| text.len() > self.max_message_len)
+        {
+            self.fail(ctx, ErrorCode::MessageTooLong, self.too_long());
+            return;
+        }
+        self.addr
+            .shard(&self.room)
+            .send(server::EditMessage {
+                id: self.id,
+                room: self.room.clone(),
+                msg_id,
+                text,
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => (),
+                    Ok(Err(err)) => act.fail(ctx, err.code(), err),
+                    _ => error!("session {}: chat server is unavailable", act.id),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// Отправить команду модерации на сервер чата и сообщить клиенту об отказе
+    fn moderate<M>(&mut self, msg: M, ctx: &mut ws::WebsocketContext<Self>)
+    where
+        M: Message<Result = Result<(), server::ModerationError>> + Send + 'static,
+        server::ChatServer: Handler<M>,
+    {
+        self.addr
+            .shard(&self.room)
+            .send(msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => (),
+                    Ok(Err(err)) => act.fail(ctx, err.code(), err),
+                    _ => error!("session {}: chat server is unavailable", act.id),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// вспомогательный метод, который отправляет ping клиенту каждую секунду.
+    /// также этот метод проверяет сердцебиение клиента
+    fn hb(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let handle = ctx.run_interval(self.hb_interval, |act, ctx| {
+            // проверять сердцебиение клиента
+            if Instant::now().duration_since(act.hb) > act.client_timeout {
+                // сердцебиение прервано
+                info!(
+                    "session {} in {}: heartbeat failed, disconnecting",
+                    act.id, act.room
+                );
+
+                // уведомить сервер чата и остановить актёра
+                act.close(ctx, ws::CloseCode::Policy, "heartbeat timeout");
+
+                // не пытайтесь посылать ping
+                return;
+            }
+
+            ctx.ping(b"");
+        });
+        self.hb_handle = Some(handle);
+    }
+}
+
+/// Общее состояние и маршруты приложения. `state` и `session_config`
+/// создаются один раз до запуска воркеров, фабрика каждого воркера только
+/// клонирует их
+fn configure(
+    cfg: &mut web::ServiceConfig,
+    state: &web::Data<AppState>,
+    session_config: &web::Data<SessionConfig>,
+    upload_config: &uploads::UploadConfig,
+) {
+    cfg.app_data(state.clone())
+        .app_data(session_config.clone())
+        .route("/count/", web::get().to(get_count))
+        .route("/metrics", web::get().to(metrics_route))
+        .route("/api/fsck", web::get().to(fsck_route))
+        .route("/api/sessions", web::get().to(sessions_route))
+        .route("/admin/broadcast", web::post().to(broadcast_route))
+        .route("/rooms", web::get().to(rooms_route))
+        .route("/rooms/{room}/message", web::post().to(post_message_route))
+        .route(
+            "/api/rooms/{room}/messages",
+            web::post().to(send_message_route),
+        )
+        .service(
+            web::resource("/upload")
+                .data(upload_config.clone())
+                .app_data(web::PayloadConfig::new(upload_config.max_size))
+                .route(web::post().to(uploads::upload_route)),
+        )
+        .service(
+            web::resource("/files/{id}")
+                .data(upload_config.clone())
+                .route(web::get().to(uploads::file_route)),
+        );
+    #[cfg(feature = "chaos")]
+    cfg.route("/api/chaos", web::post().to(chaos_route));
+    // websocket
+    cfg.service(web::resource("/ws/").to(chat_route));
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init();
+
+    // Мы ведем подсчет посетителей и подключенных сессий
+    let counters = Arc::new(server::Counters::default());
+
+    let session_config = SessionConfig {
+        hb_interval: env_secs("CHAT_HEARTBEAT_SECS", HEARTBEAT_INTERVAL),
+        client_timeout: env_secs("CHAT_TIMEOUT_SECS", CLIENT_TIMEOUT),
+        max_message_len: env_or("CHAT_MAX_MESSAGE_LEN", MAX_MESSAGE_LEN),
+        require_name: env_or("CHAT_REQUIRE_NAME", false),
+        default_room: match std::env::var("CHAT_DEFAULT_ROOM") {
+            Ok(room) => server::normalize_room_name(&room)
+                .unwrap_or_else(|| panic!("invalid CHAT_DEFAULT_ROOM {:?}", room))
+                .to_owned(),
+            Err(_) => server::DEFAULT_ROOM.to_owned(),
+        },
+        admin_token: std::env::var("CHAT_ADMIN_TOKEN").ok().map(Arc::from),
+        integration_token: std::env::var("CHAT_INTEGRATION_TOKEN").ok().map(Arc::from),
+        trust_proxy: env_or("CHAT_TRUST_PROXY", false),
+        #[cfg(feature = "chaos")]
+        chaos: Arc::default(),
+    };
+    if session_config.client_timeout <= session_config.hb_interval {
+        warn!(
+            "Client timeout {:?} is not greater than heartbeat interval {:?}",
+            session_config.client_timeout, session_config.hb_interval
+        );
+    }
+
+    // Режим уведомлений о входе и выходе для новых комнат
+    let announcements = env_or("CHAT_ROOM_ANNOUNCEMENTS", server::Announcements::Full);
+
+    let history_len = env_or("CHAT_HISTORY_LEN", HISTORY_LEN);
+    let replay_len = env_or("CHAT_REPLAY_LEN", REPLAY_LEN);
+    let invite_ttl = env_secs("CHAT_INVITE_TTL_SECS", INVITE_TTL);
+    let max_connections = env_or("CHAT_MAX_CONNECTIONS", MAX_CONNECTIONS);
+    let room_capacity = Some(env_or("CHAT_ROOM_CAPACITY", ROOM_CAPACITY)).filter(|&cap| cap > 0);
+
+    let upload_config = uploads::UploadConfig {
+        dir: std::env::var_os("CHAT_UPLOAD_DIR")
+            .map(Into::into)
+            .unwrap_or_else(|| std::env::temp_dir().join("chat-uploads")),
+        max_size: env_or("CHAT_MAX_UPLOAD_BYTES", MAX_UPLOAD_BYTES),
+        ttl: env_secs("CHAT_UPLOAD_TTL_SECS", UPLOAD_TTL),
+    };
+    uploads::start(&upload_config)?;
+
+    // История комнат в SQLite, если задан путь к базе
+    let store = match std::env::var("CHAT_DB_PATH") {
+        Ok(path) => {
+            let rooms = store::Store::open(&path)
+                .and_then(|db| db.load(history_len))
+                .unwrap_or_else(|err| panic!("failed to open database {}: {}", path, err));
+            info!("Restored {} rooms from {}", rooms.len(), path);
+            let store = SyncArbiter::start(1, move || {
+                store::Store::open(&path).expect("database was opened at startup")
+            });
+            Some((store, rooms))
+        }
+        Err(_) => None,
+    };
+    // Сообщение дня из `CHAT_MOTD` или файла motd.txt
+    let motd = std::env::var("CHAT_MOTD")
+        .ok()
+        .or_else(|| std::fs::read_to_string(MOTD_FILE).ok())
+        .map(|motd| motd.trim().to_owned())
+        .filter(|motd| !motd.is_empty());
+    let flood_limit = server::FloodLimit {
+        burst: env_or("CHAT_FLOOD_BURST", FLOOD_BURST),
+        window: env_secs("CHAT_FLOOD_WINDOW_SECS", FLOOD_WINDOW),
+        max_warnings: env_or("CHAT_FLOOD_MAX_WARNINGS", FLOOD_MAX_WARNINGS),
+    };
+    // Слова через запятую: с ними сообщение отклоняется или они заменяются звездочками
+    let block_words = std::env::var("CHAT_BLOCK_WORDS").ok();
+    let filter_words = std::env::var("CHAT_FILTER_WORDS").ok();
+    let idle_timeout = env_secs("CHAT_IDLE_TIMEOUT_SECS", IDLE_TIMEOUT);
+    let idle_reaping = if idle_timeout.is_zero() {
+        None
+    } else {
+        if idle_timeout <= session_config.client_timeout {
+            warn!(
+                "Idle timeout {:?} is not greater than client timeout {:?}",
+                idle_timeout, session_config.client_timeout
+            );
+        }
+        Some(server::IdleReaping {
+            interval: env_secs("CHAT_IDLE_SWEEP_SECS", IDLE_SWEEP_INTERVAL)
+                .max(Duration::from_secs(1)),
+            timeout: idle_timeout,
+        })
+    };
+    let room_ttl = Some(env_secs("CHAT_ROOM_TTL_SECS", ROOM_TTL)).filter(|ttl| !ttl.is_zero());
+
+    // Запуск частей сервера чата, по одной на воркер. Каждая часть получает
+    // свой экземпляр сервера со всеми настройками
+    let workers = env_or(
+        "CHAT_WORKERS",
+        std::thread::available_parallelism().map_or(1, |n| n.get()),
+    );
+    let default_room = session_config.default_room.clone();
+    let max_message_len = session_config.max_message_len;
+    #[cfg(feature = "chaos")]
+    let chaos = session_config.chaos.clone();
+    let shard_counters = counters.clone();
+    let server = server::Router::start(workers, default_room.clone(), move || {
+        let server = server::ChatServer::new(
+            shard_counters.clone(),
+            announcements,
+            history_len,
+            replay_len,
+            invite_ttl,
+            max_connections,
+            room_capacity,
+        )
+        .with_default_room(default_room.clone());
+        let server = match &store {
+            Some((store, rooms)) => server.with_store(store.clone(), rooms.clone()),
+            None => server,
+        };
+        let server = match &motd {
+            Some(motd) => server.with_motd(motd.clone()),
+            None => server,
+        };
+        let server = server.with_max_message_len(max_message_len);
+        let server = server.with_flood_limit(flood_limit);
+        let mut filters: Vec<Box<dyn filter::MessageFilter>> = Vec::new();
+        if let Some(words) = &block_words {
+            filters.push(Box::new(filter::WordFilter::rejecting(words.split(','))));
+        }
+        if let Some(words) = &filter_words {
+            filters.push(Box::new(filter::WordFilter::masking(words.split(','))));
+        }
+        let server = server.with_filters(filters);
+        let server = match idle_reaping {
+            Some(idle_reaping) => server.with_idle_reaping(idle_reaping),
+            None => server,
+        };
+        #[cfg(feature = "chaos")]
+        let server = server.with_chaos(chaos.clone());
+        match room_ttl {
+            Some(ttl) => server.with_room_ttl(ttl),
+            None => server,
+        }
+    });
+
+    // Состояние создается здесь, а не в фабрике, чтобы его разделяли все воркеры
+    let chat_server = server.clone();
+    let state = web::Data::new(AppState { server, counters });
+    let session_config = web::Data::new(session_config);
+
+    // TLS проверяется до запуска, чтобы ошибка в сертификате была понятна сразу
+    let tls_config =
+        tls::from_env().unwrap_or_else(|err| panic!("failed to load TLS certificate: {}", err));
+    if tls_config.is_some() {
+        info!("TLS enabled");
+    }
+
+    let shutdown_timeout = env_secs("CHAT_SHUTDOWN_TIMEOUT_SECS", SHUTDOWN_TIMEOUT);
+
+    // Создание Http-сервера с поддержкой вебсокета
+    let http_server = HttpServer::new(move || {
+        App::new().configure(|cfg| configure(cfg, &state, &session_config, &upload_config))
+    })
+    // сигналы обрабатываются ниже, чтобы сначала предупредить клиентов
+    .workers(workers)
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs().max(1));
+    let http_server = match tls_config {
+        Some(config) => http_server.bind_rustls(BIND_ADDR, config)?,
+        None => http_server.bind(BIND_ADDR)?,
+    }
+    .run();
+
+    let running = http_server.clone();
+    actix_web::rt::spawn(async move {
+        shutdown_signal().await;
+        chat_server.shutdown(shutdown_timeout).await;
+        // закрывающие кадры уже в очереди, мягкая остановка дает им уйти
+        running.stop(true).await;
+        System::current().stop();
+    });
+
+    http_server.await
+}
+
+/// Дождаться SIGINT или SIGTERM
+async fn shutdown_signal() {
+    use actix_web::rt::signal::{self, unix};
+
+    match unix::signal(unix::SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            let ctrl_c = signal::ctrl_c();
+            futures::pin_mut!(ctrl_c);
+            futures::future::select(ctrl_c, Box::pin(terminate.recv())).await;
+        }
+        Err(_) => {
+            let _ = signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_http::ws::{Frame, Message as WsMessage};
+    use actix_web::client::Client;
+    use actix_web::middleware::DefaultHeaders;
+    use futures::{SinkExt, StreamExt};
+
+    pub(crate) fn test_config() -> SessionConfig {
+        SessionConfig {
+            hb_interval: HEARTBEAT_INTERVAL,
+            client_timeout: CLIENT_TIMEOUT,
+            max_message_len: MAX_MESSAGE_LEN,
+            require_name: false,
+            default_room: server::DEFAULT_ROOM.to_owned(),
+            admin_token: None,
+            integration_token: None,
+            trust_proxy: false,
+            #[cfg(feature = "chaos")]
+            chaos: Arc::default(),
+        }
+    }
+
+    pub(crate) fn test_state() -> AppState {
+        let counters = Arc::new(server::Counters::default());
+        let shard_counters = counters.clone();
+        let server = server::Router::start(1, server::DEFAULT_ROOM.to_owned(), move || {
+            server::ChatServer::new(
+                shard_counters.clone(),
+                server::Announcements::Full,
+                HISTORY_LEN,
+                REPLAY_LEN,
+                INVITE_TTL,
+                MAX_CONNECTIONS,
+                None,
+            )
+        });
+        AppState { server, counters }
+    }
+
+    pub(crate) fn test_upload_config() -> uploads::UploadConfig {
+        uploads::UploadConfig {
+            dir: std::env::temp_dir().join("chat-uploads-test"),
+            max_size: MAX_UPLOAD_BYTES,
+            ttl: UPLOAD_TTL,
+        }
+    }
+
+    /// Прочитать текстовые кадры, пока не придет кадр с `needle`
+    async fn wait_for<S>(frames: &mut S, needle: &str) -> bool
+    where
+        S: futures::Stream<Item = Result<Frame, actix_http::ws::ProtocolError>> + Unpin,
+    {
+        next_with(frames, needle).await.is_some()
+    }
+
+    /// Первый текстовый кадр, содержащий `needle`, в пределах 5 секунд
+    async fn next_with<S>(frames: &mut S, needle: &str) -> Option<String>
+    where
+        S: futures::Stream<Item = Result<Frame, actix_http::ws::ProtocolError>> + Unpin,
+    {
+        let read = async {
+            while let Some(Ok(frame)) = frames.next().await {
+                if let Frame::Text(text) = frame {
+                    let text = String::from_utf8_lossy(&text);
+                    if text.contains(needle) {
+                        return Some(text.into_owned());
+                    }
+                }
+            }
+            None
+        };
+        let timeout = actix::clock::delay_for(Duration::from_secs(5));
+        futures::pin_mut!(read);
+        match futures::future::select(read, timeout).await {
+            futures::future::Either::Left((found, _)) => found,
+            futures::future::Either::Right(_) => None,
+        }
+    }
+
+    /// Тестовый HTTP-сервер чата с настройками сессий `session_config`
+    fn start_chat(session_config: SessionConfig) -> actix_web::test::TestServer {
+        let state = web::Data::new(test_state());
+        let session_config = web::Data::new(session_config);
+        let upload_config = test_upload_config();
+        actix_web::test::start(move || {
+            App::new().configure(|cfg| configure(cfg, &state, &session_config, &upload_config))
+        })
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected_and_not_broadcast() {
+        System::new("test").block_on(async {
+            let srv = start_chat(test_config());
+            let (_, mut sender) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+            let (_, mut listener) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+            assert!(wait_for(&mut sender, "Welcome!").await);
+            assert!(wait_for(&mut listener, "Welcome!").await);
+
+            let oversized = format!("spam {}", "x".repeat(4995));
+            assert_eq!(oversized.len(), 5000);
+            sender.send(WsMessage::Text(oversized)).await.unwrap();
+            let reply = next_with(&mut sender, "!!!").await;
+            assert_eq!(
+                reply.as_deref(),
+                Some("!!! message too long (max 2048 bytes)")
+            );
+
+            // первое сообщение со "spam" у слушателя уже следующее, короткое
+            sender
+                .send(WsMessage::Text("spam is over".into()))
+                .await
+                .unwrap();
+            let next = next_with(&mut listener, "spam").await.unwrap();
+            assert!(next.ends_with("spam is over"), "{}", next);
+        });
+    }
+
+    /// Дождаться кадра, равного `text`
+    async fn wait_exact<S>(frames: &mut S, text: &str) -> bool
+    where
+        S: futures::Stream<Item = Result<Frame, actix_http::ws::ProtocolError>> + Unpin,
+    {
+        while let Some(frame) = next_with(frames, text).await {
+            if frame == text {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn message_length_is_counted_in_bytes_at_the_boundary() {
+        System::new("test").block_on(async {
+            let srv = start_chat(test_config());
+            let (_, mut sender) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+            let (_, mut listener) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+            assert!(wait_for(&mut sender, "Welcome!").await);
+            assert!(wait_for(&mut listener, "Welcome!").await);
+
+            // по два и по три байта на символ: символов меньше предела, байтов больше
+            let exact = "я".repeat(MAX_MESSAGE_LEN / 2);
+            let over_by_one = format!("{}!", exact);
+            let three_bytes = "€".repeat(MAX_MESSAGE_LEN / 3 + 1);
+            assert_eq!(exact.len(), MAX_MESSAGE_LEN);
+            assert_eq!(over_by_one.len(), MAX_MESSAGE_LEN + 1);
+            assert!(three_bytes.chars().count() < MAX_MESSAGE_LEN);
+            for text in [&over_by_one, &three_bytes] {
+                sender.send(WsMessage::Text(text.clone())).await.unwrap();
+                let error = next_with(&mut sender, "!!!").await;
+                assert_eq!(
+                    error.as_deref(),
+                    Some("!!! message too long (max 2048 bytes)")
+                );
+            }
+
+            sender.send(WsMessage::Text(exact.clone())).await.unwrap();
+            // первое длинное сообщение у слушателя - разрешенное
+            let received = next_with(&mut listener, "яяя").await.unwrap();
+            assert_eq!(received, exact);
+        });
+    }
+
+    #[test]
+    fn plain_messages_need_a_name_only_when_required() {
+        System::new("test").block_on(async {
+            for require_name in [true, false] {
+                let srv = start_chat(SessionConfig {
+                    require_name,
+                    ..test_config()
+                });
+                let (_, mut sender) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+                let (_, mut listener) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+                assert!(wait_for(&mut sender, "Welcome!").await);
+                assert!(wait_for(&mut listener, "Welcome!").await);
+
+                sender
+                    .send(WsMessage::Text("nameless hello".into()))
+                    .await
+                    .unwrap();
+                if require_name {
+                    let error = next_with(&mut sender, "!!!").await;
+                    assert_eq!(error.as_deref(), Some("!!! choose a name with /name first"));
+                }
+                // команды доступны и без имени
+                sender.send(WsMessage::Text("/help".into())).await.unwrap();
+                assert!(wait_for(&mut sender, "/name").await);
+                sender.send(WsMessage::Text("/list".into())).await.unwrap();
+                assert!(wait_for(&mut sender, "Main").await);
+                sender
+                    .send(WsMessage::Text("/name alice".into()))
+                    .await
+                    .unwrap();
+                sender
+                    .send(WsMessage::Text("named hello".into()))
+                    .await
+                    .unwrap();
+
+                let first = next_with(&mut listener, "hello").await.unwrap();
+                if require_name {
+                    assert_eq!(first, "alice: named hello");
+                } else {
+                    assert!(first.ends_with("nameless hello"), "{}", first);
+                    let second = next_with(&mut listener, "hello").await.unwrap();
+                    assert_eq!(second, "alice: named hello");
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn reading_the_count_does_not_change_it() {
+        System::new("test").block_on(async {
+            let srv = start_chat(test_config());
+            let read = || async {
+                let mut resp = srv.get("/count/").send().await.unwrap();
+                assert!(resp.status().is_success());
+                resp.json::<serde_json::Value>().await.unwrap()
+            };
+            let expected = |visitors: u64, active: u64| {
+                serde_json::json!({"visitors": visitors, "active_sessions": active})
+            };
+            for _ in 0..5 {
+                assert_eq!(read().await, expected(0, 0));
+            }
+
+            let (_, mut first) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+            let (_, mut second) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+            assert!(wait_for(&mut first, "Total visitors 1").await);
+            assert!(wait_for(&mut second, "Total visitors 2").await);
+            for _ in 0..5 {
+                assert_eq!(read().await, expected(2, 2));
+            }
+
+            first
+                .send(WsMessage::Close(Some(ws::CloseCode::Normal.into())))
+                .await
+                .unwrap();
+            assert!(wait_for(&mut second, "disconnected").await);
+            for _ in 0..5 {
+                assert_eq!(read().await, expected(2, 1));
+            }
+        });
+    }
+
+    #[test]
+    fn joining_one_past_the_capacity_is_refused() {
+        System::new("test").block_on(async {
+            let srv = start_chat(test_config());
+            let mut clients = Vec::new();
+            for _ in 0..3 {
+                let (_, mut frames) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+                assert!(wait_for(&mut frames, "Welcome!").await);
+                clients.push(frames);
+            }
+
+            for (n, frames) in clients.iter_mut().take(2).enumerate() {
+                let command = match n {
+                    0 => "/join small max=2",
+                    _ => "/join small",
+                };
+                frames.send(WsMessage::Text(command.into())).await.unwrap();
+                assert!(wait_exact(frames, "joined").await);
+            }
+            clients[2]
+                .send(WsMessage::Text("/join small".into()))
+                .await
+                .unwrap();
+            let refused = next_with(&mut clients[2], "!!!").await;
+            assert_eq!(refused.as_deref(), Some("!!! room is full"));
+        });
+    }
+
+    /// Причина из кадра закрытия, который должен прийти в пределах 5 секунд
+    async fn close_reason<S>(frames: &mut S) -> ws::CloseReason
+    where
+        S: futures::Stream<Item = Result<Frame, actix_http::ws::ProtocolError>> + Unpin,
+    {
+        let close = async {
+            while let Some(Ok(frame)) = frames.next().await {
+                if let Frame::Close(reason) = frame {
+                    return reason;
+                }
+            }
+            None
+        };
+        let timeout = actix::clock::delay_for(Duration::from_secs(5));
+        futures::pin_mut!(close);
+        let reason = match futures::future::select(close, timeout).await {
+            futures::future::Either::Left((reason, _)) => reason,
+            futures::future::Either::Right(_) => panic!("no close frame"),
+        };
+        reason.expect("close frame without a reason")
+    }
+
+    #[test]
+    fn fragmented_text_is_reassembled_within_the_length_limit() {
+        use actix_http::ws::Item;
+
+        System::new("test").block_on(async {
+            let srv = start_chat(test_config());
+            let (_, mut sender) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+            let (_, mut listener) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+            assert!(wait_for(&mut sender, "Welcome!").await);
+            assert!(wait_for(&mut listener, "Welcome!").await);
+
+            for item in [
+                Item::FirstText("hello ".into()),
+                Item::Continue("fragmented ".into()),
+                Item::Last("world".into()),
+            ] {
+                sender.send(WsMessage::Continuation(item)).await.unwrap();
+            }
+            let text = next_with(&mut listener, "hello").await.unwrap();
+            assert!(text.ends_with("hello fragmented world"), "{}", text);
+
+            // сборка длиннее предела закрывает сессию, не дожидаясь конца
+            let chunk = "x".repeat(MAX_MESSAGE_LEN / 2 + 1);
+            sender
+                .send(WsMessage::Continuation(Item::FirstText(
+                    chunk.clone().into(),
+                )))
+                .await
+                .unwrap();
+            sender
+                .send(WsMessage::Continuation(Item::Continue(chunk.into())))
+                .await
+                .unwrap();
+            let error = next_with(&mut sender, "!!!").await;
+            assert_eq!(
+                error.as_deref(),
+                Some("!!! message too long (max 2048 bytes)")
+            );
+            let reason = close_reason(&mut sender).await;
+            assert_eq!(reason.code, ws::CloseCode::Policy);
+            assert_eq!(reason.description.as_deref(), Some("message too long"));
+        });
+    }
+
+    /// Разбить текст на кадры продолжения по `size` байт, как делает
+    /// фрагментирующий клиент
+    fn fragments(text: &str, size: usize) -> Vec<WsMessage> {
+        use actix_http::ws::Item;
+
+        let chunks: Vec<&[u8]> = text.as_bytes().chunks(size).collect();
+        let last = chunks.len() - 1;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(n, chunk)| {
+                let chunk = web::Bytes::copy_from_slice(chunk);
+                WsMessage::Continuation(match n {
+                    0 => Item::FirstText(chunk),
+                    n if n == last => Item::Last(chunk),
+                    _ => Item::Continue(chunk),
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fragmenting_clients_get_acks_and_a_structured_error_when_too_long() {
+        System::new("test").block_on(async {
+            let srv = start_chat(test_config());
+            let url = srv.url("/ws/?protocol=json");
+            let (_, mut sender) = Client::new().ws(&url).connect().await.unwrap();
+            assert!(wait_for(&mut sender, "Welcome!").await);
+
+            // событие режется посреди JSON и даже посреди символа, между
+            // кадрами успевает пройти пинг
+            let event =
+                serde_json::json!({"type": "send", "text": "привет по кусочкам", "ref": "f1"});
+            let mut frames = fragments(&event.to_string(), 3);
+            frames.insert(2, WsMessage::Ping("mid".into()));
+            for frame in frames {
+                sender.send(frame).await.unwrap();
+            }
+            let ack = next_event(&mut sender, "ack").await;
+            assert_eq!(ack["correlation_id"], "f1");
+
+            let oversized = "y".repeat(MAX_MESSAGE_LEN + 1);
+            for frame in fragments(&oversized, 512) {
+                if sender.send(frame).await.is_err() {
+                    break;
+                }
+            }
+            let error = next_event(&mut sender, "error").await;
+            assert_eq!(error["code"], "message_too_long");
+            let reason = close_reason(&mut sender).await;
+            assert_eq!(reason.code, ws::CloseCode::Policy);
+        });
+    }
+
+    /// Первое JSON-событие типа `kind`
+    async fn next_event<S>(frames: &mut S, kind: &str) -> serde_json::Value
+    where
+        S: futures::Stream<Item = Result<Frame, actix_http::ws::ProtocolError>> + Unpin,
+    {
+        let needle = format!("\"type\":\"{}\"", kind);
+        let frame = next_with(frames, &needle).await.expect(kind);
+        serde_json::from_str(&frame).unwrap()
+    }
+
+    #[test]
+    fn correlated_messages_are_acked_or_nacked_to_the_sender() {
+        System::new("test").block_on(async {
+            let srv = start_chat(test_config());
+            let url = srv.url("/ws/?protocol=json");
+            let (_, mut sender) = Client::new().ws(&url).connect().await.unwrap();
+            let (_, mut listener) = Client::new().ws(&url).connect().await.unwrap();
+            assert!(wait_for(&mut sender, "Welcome!").await);
+            assert!(wait_for(&mut listener, "Welcome!").await);
+            let send = |text: &str, correlation_id: &str| {
+                let event =
+                    serde_json::json!({"type": "send", "text": text, "ref": correlation_id});
+                WsMessage::Text(event.to_string())
+            };
+
+            sender.send(send("hello", "c1")).await.unwrap();
+            let ack = next_event(&mut sender, "ack").await;
+            assert_eq!(ack["correlation_id"], "c1");
+            let message = next_with(&mut listener, "hello").await.unwrap();
+            let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+            assert_eq!(message["msg_id"], ack["msg_id"]);
+
+            let too_long = "x".repeat(MAX_MESSAGE_LEN + 1);
+            sender.send(send(&too_long, "c2")).await.unwrap();
+            let nack = next_event(&mut sender, "nack").await;
+            assert_eq!(nack["correlation_id"], "c2");
+            assert_eq!(nack["reason"], "too_long");
+
+            // "hello" уже потратил одно сообщение из запаса
+            let burst = server::FloodLimit::default().burst;
+            for n in 1..burst {
+                sender.send(send("more", &format!("m{}", n))).await.unwrap();
+                assert_eq!(
+                    next_event(&mut sender, "ack").await["correlation_id"],
+                    format!("m{}", n)
+                );
+            }
+            sender.send(send("one too many", "c3")).await.unwrap();
+            let nack = next_event(&mut sender, "nack").await;
+            assert_eq!(nack["correlation_id"], "c3");
+            assert_eq!(nack["reason"], "rate_limited");
+        });
+    }
+
+    #[test]
+    fn every_command_failure_carries_its_error_code() {
+        System::new("test").block_on(async {
+            let srv = start_chat(test_config());
+            let url = srv.url("/ws/?protocol=json");
+            let (_, mut alice) = Client::new().ws(&url).connect().await.unwrap();
+            alice
+                .send(WsMessage::Text("/name alice".into()))
+                .await
+                .unwrap();
+            alice.send(WsMessage::Text("/whoami".into())).await.unwrap();
+            assert!(wait_for(&mut alice, "name alice").await);
+
+            let long_status = format!("/status {}", "s".repeat(81));
+            let long_away = format!("/away {}", "a".repeat(81));
+            let long_topic = format!("/topic {}", "t".repeat(MAX_TOPIC_LEN + 1));
+            let cases: &[(&str, &str)] = &[
+                ("/nope", "unknown_command"),
+                ("/unignore", "missing_argument"),
+                ("/unignore bob", "conflict"),
+                ("/history many", "invalid_argument"),
+                ("/resync soon", "invalid_argument"),
+                ("/admin guess", "not_permitted"),
+                ("/fsck", "not_permitted"),
+                ("/status", "missing_argument"),
+                (&long_status, "invalid_argument"),
+                (&long_away, "invalid_argument"),
+                ("/file", "missing_argument"),
+                ("/file ftp://example.com/x", "invalid_argument"),
+                ("/me waves", "not_permitted"),
+                ("/stats nowhere", "room_not_found"),
+                ("/join", "missing_argument"),
+                ("/join dev max=lots", "invalid_argument"),
+                ("/join no/such", "invalid_argument"),
+                ("/name", "missing_argument"),
+                ("/name \u{7}", "invalid_argument"),
+                ("/name Alice", "name_taken"),
+                ("/kick", "missing_argument"),
+                ("/kick alice", "not_permitted"),
+                ("/ban", "missing_argument"),
+                ("/ban alice", "not_permitted"),
+                ("/unban", "missing_argument"),
+                ("/unban alice", "not_permitted"),
+                ("/mute", "missing_argument"),
+                ("/mute alice", "not_permitted"),
+                ("/unmute", "missing_argument"),
+                ("/unmute alice", "not_permitted"),
+                ("/promote", "missing_argument"),
+                ("/promote alice", "not_permitted"),
+                ("/demote", "missing_argument"),
+                ("/demote alice", "not_permitted"),
+                ("/edit", "missing_argument"),
+                ("/edit #7 fixed", "message_not_found"),
+                ("/delete", "missing_argument"),
+                ("/delete 7", "message_not_found"),
+                ("/clear", "not_permitted"),
+                (&long_topic, "invalid_argument"),
+                ("/topic hello", "not_permitted"),
+                ("/slowmode", "invalid_argument"),
+                ("/slowmode forever", "invalid_argument"),
+                ("/slowmode 5", "not_permitted"),
+                ("/invite", "missing_argument"),
+                ("/invite nobody", "user_not_found"),
+                ("/roomopt", "invalid_argument"),
+                ("/roomopt statuses on", "not_permitted"),
+            ];
+
+            // свежая сессия на каждую пачку, чтобы не упереться в предел команд
+            for batch in cases.chunks(COMMAND_BURST as usize) {
+                let (_, mut frames) = Client::new().ws(&url).connect().await.unwrap();
+                assert!(wait_for(&mut frames, "Welcome!").await);
+                for (command, code) in batch {
+                    frames
+                        .send(WsMessage::Text(command.to_string()))
+                        .await
+                        .unwrap();
+                    let error = next_event(&mut frames, "error").await;
+                    assert_eq!(error["code"], *code, "{}: {}", command, error["message"]);
+                }
+            }
+
+            let (_, mut frames) = Client::new().ws(&url).connect().await.unwrap();
+            for _ in 0..COMMAND_BURST {
+                frames
+                    .send(WsMessage::Text("/whoami".into()))
+                    .await
+                    .unwrap();
+            }
+            frames
+                .send(WsMessage::Text("/whoami".into()))
+                .await
+                .unwrap();
+            assert_eq!(
+                next_event(&mut frames, "error").await["code"],
+                "rate_limited"
+            );
+        });
+    }
+
+    #[test]
+    fn join_trims_room_names_and_rejects_invalid_ones() {
+        System::new("test").block_on(async {
+            let srv = start_chat(test_config());
+            let (_, mut frames) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+            assert!(wait_for(&mut frames, "Welcome!").await);
+            frames
+                .send(WsMessage::Text("/join   dev".into()))
+                .await
+                .unwrap();
+            assert!(wait_exact(&mut frames, "joined").await);
+
+            for name in ["dev.ops", "dév", &"r".repeat(65)] {
+                frames
+                    .send(WsMessage::Text(format!("/join {}", name)))
+                    .await
+                    .unwrap();
+                let error = next_with(&mut frames, "!!!").await;
+                assert_eq!(error.as_deref(), Some("!!! invalid room name"), "{}", name);
+            }
+            frames
+                .send(WsMessage::Text("/whoami".into()))
+                .await
+                .unwrap();
+            let whoami = next_with(&mut frames, "room").await.unwrap();
+            assert!(whoami.ends_with("room dev"), "{}", whoami);
+        });
+    }
+
+    #[test]
+    fn each_offered_subprotocol_selects_its_wire_format() {
+        System::new("test").block_on(async {
+            let srv = start_chat(test_config());
+            let cases: &[(&[&str], Option<&str>)] = &[
+                (&["chat.v1.text"], Some("chat.v1.text")),
+                (&["chat.v2.json"], Some("chat.v2.json")),
+                (&["chat.v2.msgpack"], Some("chat.v2.msgpack")),
+                (&["chat.v1.text", "chat.v2.json"], Some("chat.v2.json")),
+                (&["chat.v9.future"], None),
+            ];
+            for (offered, expected) in cases {
+                let (resp, mut frames) = Client::new()
+                    .ws(srv.url("/ws/"))
+                    .protocols(offered.iter())
+                    .connect()
+                    .await
+                    .unwrap();
+                let negotiated = resp
+                    .headers()
+                    .get("sec-websocket-protocol")
+                    .map(|value| value.to_str().unwrap());
+                assert_eq!(negotiated, *expected, "{:?}", offered);
+
+                // первый кадр сессии - токен возобновления в выбранном формате
+                let (format, first) = match frames.next().await {
+                    Some(Ok(Frame::Text(text))) => {
+                        let text = String::from_utf8(text.to_vec()).unwrap();
+                        match serde_json::from_str::<serde_json::Value>(&text) {
+                            Ok(event) => ("json", event["type"].to_string()),
+                            Err(_) => ("text", text),
+                        }
+                    }
+                    Some(Ok(Frame::Binary(bin))) => {
+                        let event: serde_json::Value = rmp_serde::from_slice(&bin).unwrap();
+                        ("msgpack", event["type"].to_string())
+                    }
+                    other => panic!("{:?}: unexpected frame {:?}", offered, other),
+                };
+                match *expected {
+                    Some("chat.v2.json") => {
+                        assert_eq!((format, first.as_str()), ("json", "\"session\""))
+                    }
+                    Some("chat.v2.msgpack") => {
+                        assert_eq!((format, first.as_str()), ("msgpack", "\"session\""))
+                    }
+                    _ => assert!(
+                        first.starts_with("resume token "),
+                        "{:?}: {}",
+                        offered,
+                        first
+                    ),
+                }
+            }
+        });
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn chaos_route_arms_bounded_faults_for_admins_only() {
+        use actix_web::http::StatusCode;
+        use actix_web::test;
+
+        System::new("test").block_on(async {
+            let state = web::Data::new(test_state());
+            let session_config = web::Data::new(SessionConfig {
+                admin_token: Some(Arc::from("secret")),
+                ..test_config()
+            });
+            let chaos = session_config.chaos.clone();
+            let upload_config = test_upload_config();
+            let mut app = test::init_service(
+                App::new().configure(|cfg| configure(cfg, &state, &session_config, &upload_config)),
+            )
+            .await;
+            let post = |query: &str, token: Option<&str>| {
+                let req = test::TestRequest::post().uri(&format!("/api/chaos?{}", query));
+                match token {
+                    Some(token) => req.header("Authorization", format!("Bearer {}", token)),
+                    None => req,
+                }
+                .to_request()
+            };
+
+            let unauthorized = test::call_service(&mut app, post("fault=latency&ms=10", None));
+            assert_eq!(unauthorized.await.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(chaos.latency(), None);
+
+            let too_slow = post("fault=latency&ms=60000", Some("secret"));
+            let too_slow = test::call_service(&mut app, too_slow).await;
+            assert_eq!(too_slow.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(chaos.latency(), None);
+
+            let armed = post("fault=latency&ms=10&ttl=1", Some("secret"));
+            assert_eq!(
+                test::call_service(&mut app, armed).await.status(),
+                StatusCode::OK
+            );
+            assert_eq!(chaos.latency(), Some(Duration::from_millis(10)));
+
+            let cleared = post("fault=clear", Some("secret"));
+            assert_eq!(
+                test::call_service(&mut app, cleared).await.status(),
+                StatusCode::OK
+            );
+            assert_eq!(chaos.latency(), None);
+        });
+    }
+
+    #[test]
+    fn rest_messages_need_a_token_and_are_marked_as_bot() {
+        use actix_web::http::StatusCode;
+        use actix_web::test;
+
+        System::new("test").block_on(async {
+            let state = web::Data::new(test_state());
+            let server = state.server.clone();
+            let session_config = web::Data::new(SessionConfig {
+                integration_token: Some(Arc::from("ci")),
+                ..test_config()
+            });
+            let upload_config = test_upload_config();
+            let mut app = test::init_service(
+                App::new().configure(|cfg| configure(cfg, &state, &session_config, &upload_config)),
+            )
+            .await;
+            let post = |from: &str, token: Option<&str>| {
+                let req = test::TestRequest::post()
+                    .uri("/rooms/Main/message")
+                    .set_json(&serde_json::json!({"from": from, "text": "build failed"}));
+                match token {
+                    Some(token) => req.header("Authorization", format!("Bearer {}", token)),
+                    None => req,
+                }
+                .to_request()
+            };
+            let texts = || async {
+                let history = server
+                    .shard(server::DEFAULT_ROOM)
+                    .send(server::GetHistory {
+                        room: server::DEFAULT_ROOM.to_owned(),
+                        limit: usize::MAX,
+                    })
+                    .await
+                    .unwrap();
+                history
+                    .iter()
+                    .map(|entry| entry.text.to_string())
+                    .collect::<Vec<_>>()
+            };
+
+            for token in [None, Some("wrong")] {
+                let denied = test::call_service(&mut app, post("alice", token)).await;
+                assert_eq!(denied.status(), StatusCode::UNAUTHORIZED);
+            }
+            assert!(texts().await.is_empty());
+
+            let sent = test::call_service(&mut app, post("ci-bot", Some("ci"))).await;
+            assert_eq!(sent.status(), StatusCode::ACCEPTED);
+            assert_eq!(texts().await, ["ci-bot (bot): build failed"]);
+
+            // все интеграции делят один предел частоты
+            let limited = loop {
+                let resp = test::call_service(&mut app, post("ci-bot", Some("ci"))).await;
+                if resp.status() != StatusCode::ACCEPTED {
+                    break resp.status();
+                }
+            };
+            assert_eq!(limited, StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(
+                texts().await.len(),
+                server::FloodLimit::default().burst as usize
+            );
+        });
+    }
+
+    #[test]
+    fn peer_addr_trusts_only_the_hop_added_by_the_proxy() {
+        use actix_web::test::TestRequest;
+
+        let config = SessionConfig {
+            trust_proxy: true,
+            ..test_config()
+        };
+        let req = TestRequest::default()
+            .header("X-Forwarded-For", "203.0.113.7, 198.51.100.2")
+            .peer_addr("127.0.0.1:4000".parse().unwrap())
+            .to_http_request();
+        assert_eq!(
+            config.peer_addr(&req),
+            Some("198.51.100.2:0".parse().unwrap())
+        );
+
+        let direct = SessionConfig {
+            trust_proxy: false,
+            ..test_config()
+        };
+        assert_eq!(
+            direct.peer_addr(&req),
+            Some("127.0.0.1:4000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn heartbeat_timeout_closes_with_policy() {
+        System::new("test").block_on(async {
+            let state = web::Data::new(test_state());
+            let session_config = web::Data::new(SessionConfig {
+                hb_interval: Duration::from_millis(50),
+                client_timeout: Duration::from_millis(200),
+                ..test_config()
+            });
+            let upload_config = test_upload_config();
+            let srv = actix_web::test::start(move || {
+                App::new().configure(|cfg| configure(cfg, &state, &session_config, &upload_config))
+            });
+
+            // клиент не отвечает на пинги
+            let (_, mut frames) = Client::new().ws(srv.url("/ws/")).connect().await.unwrap();
+            let reason = close_reason(&mut frames).await;
+            assert_eq!(reason.code, ws::CloseCode::Policy);
+            assert_eq!(reason.description.as_deref(), Some("heartbeat timeout"));
+        });
+    }
+
+    #[test]
+    fn shutdown_sends_every_client_an_announcement_and_a_close_frame() {
+        System::new("test").block_on(async {
+            let state = web::Data::new(test_state());
+            let chat_server = state.server.clone();
+            let session_config = web::Data::new(test_config());
+            let upload_config = test_upload_config();
+            let http_server = HttpServer::new(move || {
+                App::new().configure(|cfg| configure(cfg, &state, &session_config, &upload_config))
+            })
+            .workers(2)
+            .disable_signals()
+            .bind("127.0.0.1:0")
+            .unwrap();
+            let addr = http_server.addrs()[0];
+            let running = http_server.run();
+
+            let url = format!("ws://{}/ws/", addr);
+            let mut clients = Vec::new();
+            for room in ["Main", "Main", "elsewhere"] {
+                let (_, mut frames) = Client::new().ws(&url).connect().await.unwrap();
+                assert!(wait_for(&mut frames, "Welcome!").await);
+                if room != "Main" {
+                    frames
+                        .send(WsMessage::Text(format!("/join {}", room)))
+                        .await
+                        .unwrap();
+                    assert!(wait_exact(&mut frames, "joined").await);
+                }
+                clients.push(frames);
+            }
+
+            // ответ приходит, когда закрылись все сессии, задолго до предела
+            let started = Instant::now();
+            chat_server.shutdown(Duration::from_secs(5)).await;
+            assert!(started.elapsed() < Duration::from_secs(5));
+            for frames in &mut clients {
+                assert!(wait_for(frames, "[ANNOUNCEMENT] Server shutting down").await);
+                let reason = close_reason(frames).await;
+                assert_eq!(reason.code, ws::CloseCode::Away);
+                assert_eq!(reason.description.as_deref(), Some("server shutting down"));
+            }
+
+            running.stop(true).await;
+        });
+    }
+
+    #[test]
+    fn sessions_on_different_workers_share_one_chat_server() {
+        System::new("test").block_on(async {
+            let state = web::Data::new(test_state());
+            let session_config = web::Data::new(test_config());
+            let upload_config = test_upload_config();
+            // каждый ответ помечен потоком воркера, который его обработал
+            let http_server = HttpServer::new(move || {
+                let worker = format!("{:?}", std::thread::current().id());
+                App::new()
+                    .wrap(DefaultHeaders::new().header("x-worker", worker))
+                    .configure(|cfg| configure(cfg, &state, &session_config, &upload_config))
+            })
+            .workers(4)
+            .disable_signals()
+            .bind("127.0.0.1:0")
+            .unwrap();
+            let addr = http_server.addrs()[0];
+            let running = http_server.run();
+
+            let url = format!("ws://{}/ws/", addr);
+            let client = Client::new();
+            let (first_resp, mut first) = client.ws(&url).connect().await.unwrap();
+            let first_worker = first_resp.headers().get("x-worker").cloned();
+            // воркеры обычно принимают подключения по очереди, но занятый
+            // воркер пропускается, поэтому второго ищем среди нескольких попыток
+            let mut second = None;
+            for _ in 0..16 {
+                let (resp, frames) = client.ws(&url).connect().await.unwrap();
+                if resp.headers().get("x-worker") != first_worker.as_ref() {
+                    second = Some(frames);
+                    break;
+                }
+            }
+            let mut second = second.expect("all connections were handled by one worker");
+
+            assert!(wait_for(&mut first, "Welcome!").await);
+            assert!(wait_for(&mut second, "Welcome!").await);
+            first
+                .send(WsMessage::Text("hello across workers".into()))
+                .await
+                .unwrap();
+            assert!(wait_for(&mut second, "hello across workers").await);
+
+            running.stop(false).await;
+        });
+    }
+
+    #[test]
+    fn text_clients_get_messages_without_msg_ids() {
+        System::new("test").block_on(async {
+            let state = web::Data::new(test_state());
+            let session_config = web::Data::new(test_config());
+            let upload_config = test_upload_config();
+            let http_server = HttpServer::new(move || {
+                App::new().configure(|cfg| configure(cfg, &state, &session_config, &upload_config))
+            })
+            .workers(1)
+            .disable_signals()
+            .bind("127.0.0.1:0")
+            .unwrap();
+            let addr = http_server.addrs()[0];
+            let running = http_server.run();
+
+            let client = Client::new();
+            let url = format!("ws://{}/ws/", addr);
+            let (_, mut text) = client.ws(&url).connect().await.unwrap();
+            let json_url = format!("ws://{}/ws/?protocol=json", addr);
+            let (_, mut json) = client.ws(&json_url).connect().await.unwrap();
+            let (_, mut sender) = client.ws(&url).connect().await.unwrap();
+            assert!(wait_for(&mut sender, "Welcome!").await);
+            sender
+                .send(WsMessage::Text("numbered or not".into()))
+                .await
+                .unwrap();
+
+            let line = next_with(&mut text, "numbered or not").await.unwrap();
+            assert_eq!(line, "numbered or not");
+            let event = next_with(&mut json, "numbered or not").await.unwrap();
+            assert!(event.contains("\"msg_id\""), "{}", event);
+
+            running.stop(false).await;
+        });
+    }
+}
@@ -0,0 +1,273 @@
+//! Структурированный протокол поверх текстовых кадров.
+//!
+//! Клиент отправляет JSON-события `{"type":"send","text":"..."}`,
+//! `{"type":"join","room":"..."}` и т.д. Прежняя форма `{"cmd":"join",...}`
+//! тоже принимается. Обычный текст по-прежнему работает: строки с `/` это
+//! slash-команды, остальное считается событием `send`.
+//!
+//! Сервер отвечает JSON-событиями только сессиям, подключенным с
+//! `?protocol=json`, остальные получают строки как раньше. С `?encoding=msgpack`
+//! те же события идут в MessagePack бинарными кадрами в обе стороны.
+//! Формат можно согласовать и заголовком `Sec-WebSocket-Protocol`
+//! (`chat.v1.text`, `chat.v2.json`, `chat.v2.msgpack`), он важнее строки запроса.
+//!
+//! Ошибки приходят событием `error` с кодом `ErrorCode`, текстовые клиенты
+//! получают прежние строки `!!! ...`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::server::NackReason;
+
+/// Машиночитаемый код в событии `error`, текст ошибки только для людей
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Неизвестная slash-команда
+    UnknownCommand,
+    /// У команды не хватает аргумента
+    MissingArgument,
+    /// Аргумент команды не подходит
+    InvalidArgument,
+    /// JSON или MessagePack событие не разобрать
+    MalformedEvent,
+    RoomNotFound,
+    UserNotFound,
+    NameTaken,
+    /// Нет прав на действие
+    NotPermitted,
+    PasswordRequired,
+    WrongPassword,
+    RoomFull,
+    RateLimited,
+    MessageTooLong,
+    /// Пользователь лишен права голоса в комнате
+    Muted,
+    /// Действие уже выполнено или противоречит текущему состоянию
+    Conflict,
+    /// Сессию выгнали из комнаты
+    Kicked,
+    /// В комнате включен медленный режим, писать пока рано
+    SlowMode,
+    /// Сообщение отклонено фильтром
+    Rejected,
+    /// Сообщения с таким номером нет в истории комнаты
+    MessageNotFound,
+}
+
+/// Формат исходящих кадров сессии
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Строки с префиксом `!!!` для ошибок, без номеров сообщений
+    Text,
+    /// JSON-события `ServerEvent`
+    Json,
+    /// События `ServerEvent` в MessagePack, бинарные кадры
+    MsgPack,
+}
+
+/// Подпротоколы `Sec-WebSocket-Protocol` в порядке предпочтения сервера
+pub const SUBPROTOCOLS: &[(&str, Format)] = &[
+    ("chat.v2.msgpack", Format::MsgPack),
+    ("chat.v2.json", Format::Json),
+    ("chat.v1.text", Format::Text),
+];
+
+impl Format {
+    /// Выбрать лучший подпротокол из заголовка `Sec-WebSocket-Protocol`.
+    /// `None`, если клиент не предложил ни одного известного
+    pub fn negotiate(offered: &str) -> Option<(&'static str, Format)> {
+        let offered: Vec<&str> = offered.split(',').map(str::trim).collect();
+        SUBPROTOCOLS
+            .iter()
+            .find(|(name, _)| offered.contains(name))
+            .copied()
+    }
+
+    /// Формат из строки запроса `/ws/`
+    pub fn from_query(query: &str) -> Format {
+        let mut format = Format::Text;
+        for pair in query.split('&') {
+            match pair {
+                "encoding=msgpack" => return Format::MsgPack,
+                "protocol=json" => format = Format::Json,
+                _ => (),
+            }
+        }
+        format
+    }
+}
+
+/// Событие от клиента
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    /// Сообщение в текущую комнату. С `correlation_id` (или `ref`) сервер
+    /// ответит событием `ack` или `nack`
+    Send {
+        text: String,
+        #[serde(default, alias = "ref")]
+        correlation_id: Option<String>,
+    },
+    /// Перейти в комнату, как `/join <room> [max=N] [password]`
+    Join {
+        room: String,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        capacity: Option<usize>,
+    },
+    /// Сменить имя, как `/name <name>`
+    Name { name: String },
+    /// Список комнат, как `/list [filter]`
+    List {
+        #[serde(default)]
+        filter: Option<String>,
+    },
+    /// Участники текущей комнаты, как `/who`
+    Users,
+    /// Пользователь набирает сообщение, как `/typing`
+    Typing,
+    /// Повторить сообщения текущей комнаты после `since_id`, как `/resync <id>`
+    Resync { since_id: u64 },
+    /// Исправить свое сообщение, как `/edit <id> <text>`
+    Edit { msg_id: u64, text: String },
+    /// Удалить свое сообщение, как `/delete <id>`
+    Delete { msg_id: u64 },
+}
+
+/// Прежняя форма команд с тегом `cmd`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum ClientCommand {
+    Join {
+        room: String,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    Name {
+        name: String,
+    },
+    List,
+    Users,
+    Msg {
+        text: String,
+        #[serde(default, rename = "ref")]
+        correlation_id: Option<String>,
+    },
+    Typing,
+}
+
+impl From<ClientCommand> for ClientEvent {
+    fn from(cmd: ClientCommand) -> ClientEvent {
+        match cmd {
+            ClientCommand::Join { room, password } => ClientEvent::Join {
+                room,
+                password,
+                capacity: None,
+            },
+            ClientCommand::Name { name } => ClientEvent::Name { name },
+            ClientCommand::List => ClientEvent::List { filter: None },
+            ClientCommand::Users => ClientEvent::Users,
+            ClientCommand::Typing => ClientEvent::Typing,
+            ClientCommand::Msg {
+                text,
+                correlation_id,
+            } => ClientEvent::Send {
+                text,
+                correlation_id,
+            },
+        }
+    }
+}
+
+/// Разобрать кадр как JSON-событие. `Ok(None)` означает, что кадр не похож
+/// на JSON и обрабатывается как обычный текст
+pub fn parse(frame: &str) -> Result<Option<ClientEvent>, serde_json::Error> {
+    if !frame.starts_with('{') {
+        return Ok(None);
+    }
+    match serde_json::from_str::<ClientEvent>(frame) {
+        Ok(event) => Ok(Some(event)),
+        Err(err) => match serde_json::from_str::<ClientCommand>(frame) {
+            Ok(cmd) => Ok(Some(cmd.into())),
+            Err(_) => Err(err),
+        },
+    }
+}
+
+/// Событие для клиента
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent<'a> {
+    /// Сообщение комнаты или личное сообщение (без `msg_id`)
+    Message {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        from: Option<&'a str>,
+        text: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        msg_id: Option<u64>,
+        /// Номер предыдущего события комнаты, для поиска пропусков
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prev_id: Option<u64>,
+        /// Unix-время в секундах
+        ts: u64,
+        /// Сообщение из истории комнаты, повторенное при входе
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        replay: bool,
+        /// Копия сообщения для упомянутого в нем участника, уже полученного
+        /// обычным сообщением с тем же `msg_id`
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        mention: bool,
+        /// `rest`, если автор отправил сообщение по HTTP, а не через вебсокет
+        #[serde(skip_serializing_if = "Option::is_none")]
+        via: Option<&'a str>,
+    },
+    /// Участник комнаты начал или перестал набирать сообщение
+    Typing {
+        room: &'a str,
+        from: &'a str,
+        typing: bool,
+    },
+    /// Сообщение клиента принято и разослано под номером `msg_id`
+    Ack {
+        correlation_id: &'a str,
+        msg_id: u64,
+    },
+    /// Сообщение клиента отклонено
+    Nack {
+        correlation_id: &'a str,
+        reason: NackReason,
+    },
+    /// Автор исправил сообщение `msg_id` комнаты
+    Edit {
+        room: &'a str,
+        msg_id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        from: Option<&'a str>,
+        text: &'a str,
+    },
+    /// Автор удалил сообщение `msg_id` комнаты
+    Delete { room: &'a str, msg_id: u64 },
+    /// Объявление оператора для всего сервера
+    Announcement { text: &'a str },
+    /// Сессия зарегистрирована. С `resume_token` в `/ws/?resume=` клиент вернет
+    /// прежнюю сессию, если переподключится вскоре после обрыва
+    Session { resume_token: &'a str },
+    /// Ответ сервера на команду
+    Notice { text: &'a str },
+    /// Отказ или ошибка
+    Error { code: ErrorCode, message: &'a str },
+}
+
+impl<'a> ServerEvent<'a> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("server events always serialize")
+    }
+
+    /// MessagePack с именами полей, чтобы тег `type` оставался ключом словаря
+    pub fn to_msgpack(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self).expect("server events always serialize")
+    }
+}
@@ -2,27 +2,213 @@
 //! И управляет свободными номерами. Пиры отправляют сообщения другим пирам в той же комнате через `ChatServer`.
 
 use actix::prelude::*;
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use log::{info, warn};
 use rand::{self, rngs::ThreadRng, Rng};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
     Arc,
 };
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::filter::{FilterDecision, MessageFilter};
+use crate::limiter::{Limiter, TokenBucket};
+use crate::protocol::ErrorCode;
+use crate::store::{ClearRoom, SaveMessage, Store, StoredRoom, UpdateMessage};
+
+mod fsck;
+mod shards;
+
+pub use fsck::Fsck;
+pub use shards::Router;
+use shards::{Admit, Directory, Home, Relocate, Transfer};
+
+/// Сколько новых комнат одна сессия может создать за `ROOM_CREATION_WINDOW`
+const ROOM_CREATION_LIMIT: usize = 5;
+const ROOM_CREATION_WINDOW: Duration = Duration::from_secs(60);
+/// Сколько отключенная сессия может вернуться по токену возобновления
+const RESUME_GRACE: Duration = Duration::from_secs(30);
+/// Сколько пустая комната ждет участников, прежде чем сервер её удалит
+const ROOM_GRACE: Duration = Duration::from_secs(30);
+/// Комната, в которую попадают новые сессии, если не задана другая
+pub const DEFAULT_ROOM: &str = "Main";
+/// Текст, которым заменяется удаленное сообщение в истории
+const DELETED_TEXT: &str = "[deleted]";
+/// Как часто сервер удаляет просроченные приглашения, токены возобновления
+/// и пустые комнаты
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+/// Как часто сервер проверяет, что акторы сессий еще живы
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(30);
+/// Как часто один отправитель получает автоответ об отсутствии одного пользователя
+const AWAY_REPLY_INTERVAL: Duration = Duration::from_secs(60);
+/// Как часто одна сессия может уведомлять комнату о наборе сообщения
+const TYPING_DEBOUNCE: Duration = Duration::from_secs(3);
+/// Через сколько после последнего уведомления о наборе объявляется его окончание
+const TYPING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Сколько сессий одновременно отслеживает ограничитель создания комнат
+const ROOM_CREATION_KEYS: usize = 10_000;
+/// Максимальная длина названия комнаты
+const MAX_ROOM_NAME_LEN: usize = 64;
+/// Самое длинное имя пользователя в графемах
+const MAX_USER_NAME_LEN: usize = 32;
+/// Максимальная длина статуса в графемах
+const MAX_STATUS_LEN: usize = 80;
+/// Смена статуса объявляется в комнате не чаще одного раза за этот интервал
+const STATUS_DEBOUNCE: Duration = Duration::from_secs(5);
+/// Id, под которым фильтры и ограничитель частоты видят все интеграции.
+/// Сессиям такой id не выдается
+const EXTERNAL_SENDER: usize = 0;
 
 /// Сервер чата отправляет эти сообщения в сессию
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
+pub struct Message {
+    /// Текст, общий для всех получателей рассылки
+    pub text: Arc<str>,
+    /// Порядковый номер события в комнате, `None` для личных сообщений сервера.
+    /// Пара (комната, `msg_id`) однозначно задает сообщение
+    pub msg_id: Option<u64>,
+    /// Номер предыдущего события комнаты (0 перед первым). Если он не совпадает
+    /// с последним полученным `msg_id`, клиент пропустил сообщения, см. `Resync`
+    pub prev_id: Option<u64>,
+    /// Когда сервер разослал сообщение
+    pub ts: SystemTime,
+    /// Имя автора сообщения, `None` для системных сообщений
+    pub from: Option<Arc<str>>,
+    /// Сообщение из истории комнаты, повторенное при входе
+    pub replay: bool,
+    /// Копия сообщения для упомянутого в нем участника: комната, где его упомянули
+    pub mention: Option<Arc<str>>,
+    /// Как сообщение пришло, если не через вебсокет автора: `rest`
+    pub via: Option<&'static str>,
+}
+
+/// Личное системное сообщение сервера
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message {
+            text: text.into(),
+            msg_id: None,
+            prev_id: None,
+            ts: SystemTime::now(),
+            from: None,
+            replay: false,
+            mention: None,
+            via: None,
+        }
+    }
+}
+
+/// Служебные сообщения сервера чата вне потока сообщений комнаты
+#[derive(Message)]
+#[rtype(result = "()")]
+pub enum Control {
+    /// Участник комнаты `room` начал (`typing`) или перестал набирать сообщение
+    Typing {
+        room: String,
+        name: String,
+        typing: bool,
+    },
+    /// Сессию выгнали из комнаты и переместили в `to_room`
+    Kicked { to_room: String },
+    /// Владелец комнаты `room` выгнал сессию, она должна закрыться
+    Removed { room: String },
+    /// Сессия повторно превысила лимит сообщений, она должна закрыться
+    Flooded,
+    /// Сообщение с `correlation_id` разослано под номером `msg_id`
+    Ack { correlation_id: String, msg_id: u64 },
+    /// Сообщение с `correlation_id` отклонено
+    Nack {
+        correlation_id: String,
+        reason: NackReason,
+    },
+    /// Отказ в действии сессии
+    Error { code: ErrorCode, message: String },
+    /// Автор исправил сообщение `msg_id` комнаты `room`
+    Edited {
+        room: String,
+        msg_id: u64,
+        from: Option<Arc<str>>,
+        text: Arc<str>,
+    },
+    /// Автор удалил сообщение `msg_id` комнаты `room`
+    Deleted { room: String, msg_id: u64 },
+    /// Объявление оператора для всех сессий, не сообщение комнаты
+    Announcement { text: Arc<str> },
+    /// Сервер останавливается
+    Shutdown,
+    /// Сессия слишком долго не подавала признаков жизни и уже удалена
+    Idle,
+    /// Комнату `room` удалили за неактивность, сессия перемещена в `to_room`
+    RoomClosed { room: String, to_room: String },
+    /// Сессия отключается сбоем `chaos`
+    #[cfg(feature = "chaos")]
+    Dropped,
+}
+
+/// Объявление оператора всем подключенным сессиям, во всех комнатах
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Broadcast {
+    pub text: String,
+}
 
-/// Сообщение для связи с сервером чата
+/// Остановка сервера: все сессии получают объявление и закрываются. Ответ
+/// приходит, когда отключится последняя сессия, но не позже `timeout`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub timeout: Duration,
+}
 
-/// Создается новый сеанс чата
+/// Сообщение для связи с сервером чата: создается новый сеанс чата
 #[derive(Message)]
-#[rtype(usize)]
+#[rtype(result = "Result<Connected, ServerFull>")]
 pub struct Connect {
     pub addr: Recipient<Message>,
+    pub control: Recipient<Control>,
+    /// Токен из `Connected` прошлого подключения
+    pub resume: Option<String>,
+    /// Адрес клиента, с доверенным прокси из `X-Forwarded-For`
+    pub peer: Option<SocketAddr>,
+    /// Заголовок `User-Agent`
+    pub user_agent: Option<String>,
+}
+
+/// Подключено уже максимальное число сессий
+#[derive(Debug)]
+pub struct ServerFull;
+
+impl fmt::Display for ServerFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server full")
+    }
+}
+
+/// Сессия зарегистрирована
+pub struct Connected {
+    pub id: usize,
+    /// С этим токеном сессия может вернуться в течение `RESUME_GRACE` после отключения
+    pub token: String,
+    /// Имя восстановленной сессии, если его не успели занять
+    pub name: Option<String>,
+    /// Отсутствие восстановленной сессии
+    pub away: Option<Away>,
+    /// Комната, в которой оказалась сессия
+    pub room: String,
 }
 
 /// Сессия отключена
@@ -42,173 +228,5800 @@ pub struct ClientMessage {
     pub msg: String,
     /// Название номера
     pub room: String,
+    /// Идентификатор клиента для подтверждения доставки
+    pub correlation_id: Option<String>,
+}
+
+/// Почему сообщение с `correlation_id` не принято
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NackReason {
+    /// Отправителю запрещено писать в комнату
+    Muted,
+    /// Отправитель превысил ограничение частоты
+    RateLimited,
+    /// Сообщение длиннее допустимого
+    TooLong,
+    /// В комнате медленный режим, предыдущее сообщение было недавно
+    SlowMode,
+    /// Сервер требует выбрать имя до первого сообщения
+    NameRequired,
+    /// Сообщение отклонено фильтром
+    Rejected,
+}
+
+impl fmt::Display for NackReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NackReason::Muted => write!(f, "muted"),
+            NackReason::RateLimited => write!(f, "rate_limited"),
+            NackReason::TooLong => write!(f, "too_long"),
+            NackReason::SlowMode => write!(f, "slow_mode"),
+            NackReason::NameRequired => write!(f, "name_required"),
+            NackReason::Rejected => write!(f, "rejected"),
+        }
+    }
+}
+
+/// Почему сервер не разослал сообщение сессии
+#[derive(Debug)]
+pub enum Refusal {
+    /// Текст длиннее предела в байтах
+    TooLong(usize),
+    Muted,
+    /// В медленном режиме осталось ждать
+    SlowMode(Duration),
+    /// Причина отказа фильтра
+    Rejected(String),
+    RateLimited,
+}
+
+impl Refusal {
+    pub fn reason(&self) -> NackReason {
+        match self {
+            Refusal::TooLong(_) => NackReason::TooLong,
+            Refusal::Muted => NackReason::Muted,
+            Refusal::SlowMode(_) => NackReason::SlowMode,
+            Refusal::Rejected(_) => NackReason::Rejected,
+            Refusal::RateLimited => NackReason::RateLimited,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Refusal::TooLong(_) => ErrorCode::MessageTooLong,
+            Refusal::Muted => ErrorCode::Muted,
+            Refusal::SlowMode(_) => ErrorCode::SlowMode,
+            Refusal::Rejected(_) => ErrorCode::Rejected,
+            Refusal::RateLimited => ErrorCode::RateLimited,
+        }
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::TooLong(max) => write!(f, "message too long (max {} bytes)", max),
+            Refusal::Muted => write!(f, "you are muted in this room"),
+            // округление вверх, чтобы не советовать подождать 0s
+            Refusal::SlowMode(wait) => write!(
+                f,
+                "slow mode is on, wait {}s",
+                wait.as_millis().div_ceil(1000)
+            ),
+            Refusal::Rejected(reason) => write!(f, "message rejected: {}", reason),
+            Refusal::RateLimited => write!(f, "rate limited"),
+        }
+    }
+}
+
+/// Сообщение в комнату по HTTP от имени подключенной сессии. Сессия
+/// подтверждает личность своим токеном возобновления
+#[derive(Message)]
+#[rtype(result = "Result<u64, RestError>")]
+pub struct RestMessage {
+    pub token: String,
+    /// Название комнаты, отправитель должен в ней находиться
+    pub room: String,
+    pub text: String,
+    /// Без имени писать нельзя (`CHAT_REQUIRE_NAME`)
+    pub require_name: bool,
+}
+
+/// Почему сообщение по HTTP не разослано
+#[derive(Debug)]
+pub enum RestError {
+    /// Нет подключенной сессии с таким токеном
+    Unauthorized,
+    /// Отправителя нет в комнате
+    NotMember,
+    NameRequired,
+    Refused(Refusal),
+}
+
+impl fmt::Display for RestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestError::Unauthorized => write!(f, "unknown session token"),
+            RestError::NotMember => write!(f, "you are not in this room"),
+            RestError::NameRequired => write!(f, "choose a name with /name first"),
+            RestError::Refused(refusal) => refusal.fmt(f),
+        }
+    }
+}
+
+/// Сообщение в комнату от внешней интеграции, без сессии
+#[derive(Message)]
+#[rtype(result = "Result<(), ExternalError>")]
+pub struct ExternalMessage {
+    /// Название комнаты
+    pub room: String,
+    /// Имя интеграции, в комнате показывается с пометкой `(bot)`
+    pub from: String,
+    pub text: String,
+}
+
+/// Почему сообщение интеграции не разослано
+#[derive(Debug)]
+pub enum ExternalError {
+    /// Комнаты с таким названием нет
+    NoSuchRoom,
+    /// Сообщение отклонено фильтром
+    Rejected(String),
+    /// Интеграции превысили общий предел частоты сообщений
+    RateLimited,
+}
+
+impl fmt::Display for ExternalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExternalError::NoSuchRoom => write!(f, "no such room"),
+            ExternalError::Rejected(reason) => write!(f, "message rejected: {}", reason),
+            ExternalError::RateLimited => write!(f, "rate limited"),
+        }
+    }
 }
 
-/// Список доступных номеров
-pub struct ListRooms;
+/// Страница списка комнат, отсортированного по `sort`
+pub struct ListRooms {
+    /// Только комнаты, название которых содержит эту подстроку без учета регистра
+    pub filter: Option<String>,
+    /// Сколько комнат пропустить
+    pub offset: usize,
+    /// Сколько комнат вернуть
+    pub limit: usize,
+    pub sort: RoomSort,
+}
 
 impl actix::Message for ListRooms {
-    type Result = Vec<String>;
+    type Result = RoomPage;
+}
+
+/// Порядок комнат в `ListRooms`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoomSort {
+    /// По названию
+    Name,
+    /// Сначала самые населенные, при равенстве по названию
+    Members,
+}
+
+/// Ответ на `ListRooms`
+pub struct RoomPage {
+    pub rooms: Vec<RoomInfo>,
+    /// Сколько всего комнат подходит под фильтр
+    pub total: usize,
+}
+
+/// Запросить подключенные сессии вместе с адресами клиентов. Только для
+/// админ API: адреса и `User-Agent` не попадают в сообщения комнат
+pub struct ListSessions;
+
+impl actix::Message for ListSessions {
+    type Result = Vec<SessionInfo>;
+}
+
+/// Сведения о сессии для `ListSessions`
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub name: Option<String>,
+    pub peer: Option<SocketAddr>,
+    pub user_agent: Option<String>,
+    /// Unix-время подключения в секундах
+    pub connected: u64,
+    /// Комнаты, в которых находится сессия
+    pub rooms: Vec<String>,
+}
+
+/// Сведения о комнате для `ListRooms`
+#[derive(Serialize)]
+pub struct RoomInfo {
+    /// Название комнаты
+    pub name: String,
+    /// Количество участников в комнате
+    pub members: usize,
+    /// Для входа нужен пароль
+    pub protected: bool,
+    /// Максимальное число участников, если задано
+    pub capacity: Option<usize>,
 }
 
 /// Присоединитесь к комнате, если комната не существует, создайте новую.
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<(), JoinError>")]
 pub struct Join {
     /// Client id
     pub id: usize,
     /// Room name
     pub name: String,
+    /// Пароль комнаты. При создании новой комнаты задает её пароль
+    pub password: Option<String>,
+    /// Наибольшее число участников новой комнаты, `None` без ограничения
+    pub capacity: Option<usize>,
 }
 
-/// `ChatServer` управляет чатами и отвечает за координацию сеансов чата. реализация супер примитивна
-pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<Message>>,
-    rooms: HashMap<String, HashSet<usize>>,
-    rng: ThreadRng,
-    visitor_count: Arc<AtomicUsize>,
+/// Причина отказа в `Join`
+#[derive(Debug)]
+pub enum JoinError {
+    /// Пользователь забанен в комнате
+    Banned,
+    /// Сессия создает новые комнаты слишком часто
+    TooManyRooms,
+    /// Комната защищена паролем, а пароль не указан
+    PasswordRequired,
+    /// Указан неверный пароль
+    WrongPassword,
+    /// В комнате уже максимальное число участников
+    Full,
+    /// Название комнаты пустое, слишком длинное или с недопустимыми символами
+    InvalidName,
+    /// Сессия как раз переезжает между частями сервера, вход стоит повторить
+    Unavailable,
 }
 
-impl ChatServer {
-    pub fn new(visitor_count: Arc<AtomicUsize>) -> ChatServer {
-        // комната по умолчанию
-        let mut rooms = HashMap::new();
-        rooms.insert("Main".to_owned(), HashSet::new());
-
-        ChatServer {
-            sessions: HashMap::new(),
-            rooms,
-            rng: rand::thread_rng(),
-            visitor_count,
+impl JoinError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            JoinError::Banned => ErrorCode::NotPermitted,
+            JoinError::TooManyRooms => ErrorCode::RateLimited,
+            JoinError::PasswordRequired => ErrorCode::PasswordRequired,
+            JoinError::WrongPassword => ErrorCode::WrongPassword,
+            JoinError::Full => ErrorCode::RoomFull,
+            JoinError::InvalidName => ErrorCode::InvalidArgument,
+            JoinError::Unavailable => ErrorCode::Conflict,
         }
     }
 }
 
-impl ChatServer {
-    /// Отправить сообщение всем пользователям в комнате
-    fn send_message(&self, room: &str, message: &str, skip_id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions {
-                if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        let _ = addr.do_send(Message(message.to_owned()));
-                    }
-                }
-            }
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Banned => write!(f, "you are banned from this room"),
+            JoinError::TooManyRooms => write!(f, "you are creating rooms too fast"),
+            JoinError::PasswordRequired => write!(f, "this room requires a password"),
+            JoinError::WrongPassword => write!(f, "wrong password"),
+            JoinError::Full => write!(f, "room is full"),
+            JoinError::InvalidName => write!(f, "invalid room name"),
+            JoinError::Unavailable => write!(f, "room is unavailable, try again"),
         }
     }
 }
 
-/// Создайте актера из `ChatServer`
-impl Actor for ChatServer {
-    /// Мы будем использовать простой Контекст, нам просто необходимо умение общаться с другими актерами.
-    type Context = Context<Self>;
+/// Запросить список участников комнаты
+pub struct Who {
+    /// Название комнаты
+    pub room: String,
 }
 
-/// Обработчик для сообщения Connect.
-///
-/// Зарегистрируйте новую сессию и присвойте ей уникальный идентификатор
-impl Handler<Connect> for ChatServer {
-    type Result = usize;
-
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        println!("Someone joined");
+impl actix::Message for Who {
+    type Result = Option<WhoSnapshot>;
+}
 
-        // оповестить всех пользователей в одной комнате
-        self.send_message(&"Main".to_owned(), "Someone joined", 0);
+/// Список участников комнаты на момент события `seq`.
+///
+/// События комнаты с номером не больше `seq` уже учтены в списке
+pub struct WhoSnapshot {
+    pub seq: u64,
+    /// Участники с именами, безымянные участники не включаются
+    pub members: Vec<MemberInfo>,
+    /// Количество безымянных участников
+    pub guests: usize,
+}
 
-        // зарегистрировать сессию со случайным идентификатором
-        let id = self.rng.gen::<usize>();
-        self.sessions.insert(id, msg.addr);
+/// Участник комнаты в `WhoSnapshot`
+pub struct MemberInfo {
+    pub name: String,
+    /// Статус, если комната показывает статусы
+    pub status: Option<String>,
+    /// Пользователь отошел
+    pub away: Option<Away>,
+    pub role: Role,
+}
 
-        // автоматическое присоединение сеанса к основной комнате
-        self.rooms
-            .entry("Main".to_owned())
-            .or_insert_with(HashSet::new)
-            .insert(id);
+/// Роль участника в комнате, от младшей к старшей
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Role {
+    Member,
+    Moderator,
+    Owner,
+}
 
-        let count = self.visitor_count.fetch_add(1, Ordering::SeqCst);
-        self.send_message("Main", &format!("Total visitors {}", count), 0);
+/// Пометка «отошел» с необязательной причиной
+#[derive(Clone, Debug)]
+pub struct Away {
+    pub reason: Option<String>,
+}
 
-        // вернуть идентификатор
-        id
-    }
+/// Пометить сессию как отошедшую или вернувшуюся (`None`).
+/// Причина проверяется так же, как статус
+#[derive(Message)]
+#[rtype(result = "Result<(), StatusError>")]
+pub struct SetAway {
+    /// Client id
+    pub id: usize,
+    pub away: Option<Away>,
 }
 
-/// Обработчик сообщения об отключении.
-impl Handler<Disconnect> for ChatServer {
-    type Result = ();
+/// Установить или сбросить (`None`) статус сессии
+#[derive(Message)]
+#[rtype(result = "Result<(), StatusError>")]
+pub struct SetStatus {
+    /// Client id
+    pub id: usize,
+    pub status: Option<String>,
+}
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        println!("Someone disconnected");
+/// Причина отказа в `SetStatus`
+#[derive(Debug)]
+pub enum StatusError {
+    /// После очистки статус оказался пустым
+    Empty,
+    /// Статус длиннее `MAX_STATUS_LEN` графем
+    TooLong,
+    /// Сессии нет в этой части сервера, например она переходит в другую комнату
+    Unavailable,
+}
 
-        let mut rooms: Vec<String> = Vec::new();
+impl StatusError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            StatusError::Empty => ErrorCode::MissingArgument,
+            StatusError::TooLong => ErrorCode::InvalidArgument,
+            StatusError::Unavailable => ErrorCode::Conflict,
+        }
+    }
+}
 
-        // remove address
-        if self.sessions.remove(&msg.id).is_some() {
-            // remove session from all rooms
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
-                }
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusError::Empty => write!(f, "status is empty"),
+            StatusError::TooLong => {
+                write!(f, "status is longer than {} characters", MAX_STATUS_LEN)
             }
-        }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
+            StatusError::Unavailable => write!(f, "session is busy, try again"),
         }
     }
 }
 
-/// Handler for Message message.
-impl Handler<ClientMessage> for ChatServer {
-    type Result = ();
+/// Текст сообщения сессии без префикса автора, который добавляет сессия:
+/// `"name: "` у обычных сообщений и `"* name "` у `/me`
+fn message_body<'a>(name: Option<&str>, text: &'a str) -> &'a str {
+    let name = match name {
+        Some(name) => name,
+        None => return text,
+    };
+    text.strip_prefix(name)
+        .and_then(|rest| rest.strip_prefix(": "))
+        .or_else(|| {
+            text.strip_prefix("* ")
+                .and_then(|rest| rest.strip_prefix(name))
+                .and_then(|rest| rest.strip_prefix(' '))
+        })
+        .unwrap_or(text)
+}
 
-    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        self.send_message(&msg.room, msg.msg.as_str(), msg.id);
-    }
+/// Ключ имени пользователя для сравнения: NFKC и нижний регистр, чтобы
+/// "Alice", "ALICE" и "Ａｌｉｃｅ" считались одним именем
+pub fn name_key(name: &str) -> String {
+    name.nfkc().collect::<String>().to_lowercase()
 }
 
-/// Handler for `ListRooms` message.
-impl Handler<ListRooms> for ChatServer {
-    type Result = MessageResult<ListRooms>;
+/// Название комнаты без пробелов по краям. Допустимы только `[A-Za-z0-9_-]`
+/// и не больше `MAX_ROOM_NAME_LEN` символов
+pub fn normalize_room_name(name: &str) -> Option<&str> {
+    let name = name.trim();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    Some(name).filter(|_| valid)
+}
 
-    fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
-        let mut rooms = Vec::new();
+/// Имя пользователя без пробелов по краям. Имя не пустое, без управляющих
+/// символов и не длиннее `MAX_USER_NAME_LEN` графем
+pub fn normalize_user_name(name: &str) -> Option<&str> {
+    let name = name.trim();
+    let valid = !name.is_empty()
+        && !name.chars().any(char::is_control)
+        && name.graphemes(true).count() <= MAX_USER_NAME_LEN;
+    Some(name).filter(|_| valid)
+}
 
-        for key in self.rooms.keys() {
-            rooms.push(key.to_owned())
-        }
+/// Убрать управляющие символы и лишние пробелы из статуса
+fn sanitize_status(status: &str) -> Result<String, StatusError> {
+    let words: Vec<&str> = status.split_whitespace().collect();
+    let cleaned: String = words
+        .join(" ")
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
 
-        MessageResult(rooms)
+    if cleaned.is_empty() {
+        Err(StatusError::Empty)
+    } else if cleaned.graphemes(true).count() > MAX_STATUS_LEN {
+        Err(StatusError::TooLong)
+    } else {
+        Ok(cleaned)
     }
 }
 
-/// Присоединиться к комнате, отправить сообщение о разъединении в старую комнату
-/// отправить сообщение о присоединении в новую комнату
-impl Handler<Join> for ChatServer {
-    type Result = ();
+/// Сессия получила кадр от клиента, она жива
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Touch {
+    pub id: usize,
+}
 
-    fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
-        let Join { id, name } = msg;
-        let mut rooms = Vec::new();
+/// Сессия набирает сообщение в комнате
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Typing {
+    /// Client id
+    pub id: usize,
+    /// Название комнаты
+    pub room: String,
+}
 
-        // remove session from all rooms
-        for (n, sessions) in &mut self.rooms {
-            if sessions.remove(&id) {
-                rooms.push(n.to_owned());
-            }
+/// Запросить общие счетчики сервера
+pub struct GetStats;
+
+impl actix::Message for GetStats {
+    type Result = ServerStats;
+}
+
+/// Общие счетчики сервера на момент запроса
+pub struct ServerStats {
+    /// Сколько сессий подключено сейчас
+    pub sessions: usize,
+    /// Сколько комнат существует сейчас
+    pub rooms: usize,
+    /// Сколько сообщений разослано в комнаты с запуска сервера
+    pub messages: u64,
+}
+
+/// Запросить счетчики комнаты
+pub struct RoomStats {
+    /// Название комнаты
+    pub room: String,
+}
+
+impl actix::Message for RoomStats {
+    type Result = Option<RoomStatsSnapshot>;
+}
+
+/// Счетчики комнаты на момент запроса
+pub struct RoomStatsSnapshot {
+    pub members: usize,
+    /// Сколько сообщений отправлено с момента создания комнаты
+    pub messages: u64,
+    pub created: SystemTime,
+    /// Последнее сообщение или вход в комнату
+    pub last_activity: SystemTime,
+}
+
+/// Исправить или удалить свое сообщение комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), EditError>")]
+pub struct EditMessage {
+    /// Id сессии автора
+    pub id: usize,
+    pub room: String,
+    pub msg_id: u64,
+    /// Новый текст, `None` удаляет сообщение
+    pub text: Option<String>,
+}
+
+/// Почему сообщение нельзя исправить или удалить
+#[derive(Debug)]
+pub enum EditError {
+    /// Сообщения нет в истории комнаты или оно уже вытеснено
+    NotFound,
+    /// Сообщение написал кто-то другой
+    NotAuthor,
+    /// Сообщение уже удалено
+    Deleted,
+    /// Новый текст длиннее допустимого
+    TooLong(usize),
+    /// Новый текст отклонен фильтром
+    Rejected(String),
+}
+
+impl EditError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            EditError::NotFound => ErrorCode::MessageNotFound,
+            EditError::NotAuthor => ErrorCode::NotPermitted,
+            EditError::Deleted => ErrorCode::Conflict,
+            EditError::TooLong(_) => ErrorCode::MessageTooLong,
+            EditError::Rejected(_) => ErrorCode::Rejected,
         }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
+    }
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditError::NotFound => write!(f, "no such message in the room history"),
+            EditError::NotAuthor => write!(f, "you can only change your own messages"),
+            EditError::Deleted => write!(f, "message was deleted"),
+            EditError::TooLong(max) => write!(f, "message too long (max {} bytes)", max),
+            EditError::Rejected(reason) => write!(f, "message rejected: {}", reason),
         }
+    }
+}
 
-        self.rooms
-            .entry(name.clone())
-            .or_insert_with(HashSet::new)
-            .insert(id);
+/// Запросить последние `limit` сообщений комнаты, самое старое первым
+pub struct GetHistory {
+    /// Название комнаты
+    pub room: String,
+    pub limit: usize,
+}
 
-        self.send_message(&name, "Someone connected", id);
-    }
-}
\ No newline at end of file
+impl actix::Message for GetHistory {
+    type Result = Vec<HistoryEntry>;
+}
+
+/// Запросить сообщения комнаты новее `since_id`, чтобы восполнить пропуск
+pub struct Resync {
+    /// Название комнаты
+    pub room: String,
+    pub since_id: u64,
+}
+
+/// Сообщения для `Resync`, самое старое первым
+pub struct ResyncReplay {
+    /// Часть сообщений после `since_id` уже вытеснена из истории
+    pub truncated: bool,
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl actix::Message for Resync {
+    type Result = ResyncReplay;
+}
+
+/// Установить имя сессии. Имена уникальны в пределах сервера
+pub struct SetName {
+    /// Client id
+    pub id: usize,
+    /// Новое имя
+    pub name: String,
+}
+
+impl actix::Message for SetName {
+    type Result = SetNameResult;
+}
+
+/// Результат `SetName`
+pub enum SetNameResult {
+    /// Имя установлено
+    Ok,
+    /// Имя уже занято другой сессией
+    Taken,
+    /// Имя пустое, слишком длинное или с управляющими символами
+    Invalid,
+    /// Сессии нет в этой части сервера, например она переходит в другую комнату
+    Unavailable,
+}
+
+/// Выгнать пользователя из комнаты и отключить его. Доступно владельцу и модераторам комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), ModerationError>")]
+pub struct Kick {
+    /// Id сессии, которая выгоняет
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    /// Имя пользователя, которого выгоняют
+    pub target_name: String,
+}
+
+/// Забанить пользователя в комнате по имени. Доступно владельцу и модераторам комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), ModerationError>")]
+pub struct Ban {
+    /// Id сессии, которая банит
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    /// Имя пользователя
+    pub target_name: String,
+}
+
+/// Снять бан с пользователя в комнате. Доступно владельцу и модераторам комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), ModerationError>")]
+pub struct Unban {
+    /// Id сессии, которая снимает бан
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    /// Имя пользователя
+    pub target_name: String,
+}
+
+/// Лишить пользователя права писать в комнату. Доступно владельцу и модераторам комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), ModerationError>")]
+pub struct Mute {
+    /// Id сессии, которая отключает голос
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    /// Имя пользователя
+    pub target_name: String,
+}
+
+/// Вернуть пользователю право писать в комнату. Доступно владельцу и модераторам комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), ModerationError>")]
+pub struct Unmute {
+    /// Id сессии, которая возвращает голос
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    /// Имя пользователя
+    pub target_name: String,
+}
+
+/// Включить медленный режим комнаты (`Some(interval)`) или выключить его.
+/// Доступно владельцу и модераторам комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), ModerationError>")]
+pub struct SetSlowMode {
+    /// Id сессии, которая меняет режим
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    /// Как часто участник может писать в комнату
+    pub interval: Option<Duration>,
+}
+
+/// Сменить тему комнаты. Доступно владельцу и модераторам комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), ModerationError>")]
+pub struct SetTopic {
+    /// Id сессии, которая меняет тему
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    pub topic: String,
+}
+
+/// Запросить тему комнаты
+#[derive(Message)]
+#[rtype(result = "Option<String>")]
+pub struct GetTopic {
+    /// Название комнаты
+    pub room: String,
+}
+
+/// Очистить историю комнаты. Доступно только владельцу комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), ModerationError>")]
+pub struct ClearHistory {
+    /// Название комнаты
+    pub room: String,
+    /// Id сессии владельца
+    pub requester_id: usize,
+}
+
+/// Назначить участника модератором комнаты. Доступно только владельцу комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), ModerationError>")]
+pub struct Promote {
+    /// Id сессии владельца
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    /// Имя участника
+    pub target_name: String,
+}
+
+/// Снять с участника права модератора. Доступно только владельцу комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), ModerationError>")]
+pub struct Demote {
+    /// Id сессии владельца
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    /// Имя участника
+    pub target_name: String,
+}
+
+/// Причина отказа в командах модерации
+#[derive(Debug)]
+pub enum ModerationError {
+    /// Отправитель не является владельцем комнаты
+    NotOwner,
+    /// Отправитель не владелец и не модератор комнаты
+    NotModerator,
+    /// Роль участника не ниже роли отправителя
+    Outranked,
+    /// В комнате нет пользователя с таким именем
+    NoSuchUser,
+    /// Команда направлена на самого отправителя
+    SelfTarget,
+    /// Пользователь не забанен
+    NotBanned,
+    /// Пользователь уже без права голоса
+    AlreadyMuted,
+    /// Пользователь не лишен права голоса
+    NotMuted,
+    /// Участник уже модератор
+    AlreadyModerator,
+    /// Участник не модератор
+    NotPromoted,
+}
+
+impl ModerationError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ModerationError::NotOwner
+            | ModerationError::NotModerator
+            | ModerationError::Outranked => ErrorCode::NotPermitted,
+            ModerationError::NoSuchUser => ErrorCode::UserNotFound,
+            ModerationError::SelfTarget => ErrorCode::InvalidArgument,
+            ModerationError::NotBanned
+            | ModerationError::AlreadyMuted
+            | ModerationError::NotMuted
+            | ModerationError::AlreadyModerator
+            | ModerationError::NotPromoted => ErrorCode::Conflict,
+        }
+    }
+}
+
+impl fmt::Display for ModerationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModerationError::NotOwner => write!(f, "only the room owner can do that"),
+            ModerationError::NotModerator => write!(f, "only room moderators can do that"),
+            ModerationError::Outranked => {
+                write!(f, "you can't do that to the owner or a moderator")
+            }
+            ModerationError::NoSuchUser => write!(f, "no such user in this room"),
+            ModerationError::SelfTarget => write!(f, "you can't do that to yourself"),
+            ModerationError::NotBanned => write!(f, "user is not banned"),
+            ModerationError::AlreadyMuted => write!(f, "user is already muted"),
+            ModerationError::NotMuted => write!(f, "user is not muted"),
+            ModerationError::AlreadyModerator => write!(f, "user is already a moderator"),
+            ModerationError::NotPromoted => write!(f, "user is not a moderator"),
+        }
+    }
+}
+
+/// Пригласить пользователя в комнату. Приглашенный может один раз войти
+/// в комнату без пароля
+#[derive(Message)]
+#[rtype(result = "Result<(), InviteError>")]
+pub struct Invite {
+    /// Id сессии, которая приглашает
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    /// Имя приглашаемого пользователя
+    pub target_name: String,
+}
+
+/// Причина отказа в приглашении
+#[derive(Debug)]
+pub enum InviteError {
+    /// Отправитель сам не находится в комнате
+    NotInRoom,
+    /// Пользователя с таким именем нет
+    NoSuchUser,
+    /// Пользователь уже в комнате
+    AlreadyInRoom,
+}
+
+impl InviteError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            InviteError::NotInRoom => ErrorCode::NotPermitted,
+            InviteError::NoSuchUser => ErrorCode::UserNotFound,
+            InviteError::AlreadyInRoom => ErrorCode::Conflict,
+        }
+    }
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InviteError::NotInRoom => write!(f, "you are not in this room"),
+            InviteError::NoSuchUser => write!(f, "no such user"),
+            InviteError::AlreadyInRoom => write!(f, "user is already in this room"),
+        }
+    }
+}
+
+/// Удаление сессий, от которых сервер давно ничего не получал.
+/// Не зависит от сердцебиения в самой сессии, поэтому застрявший актор
+/// сессии не остается в комнатах навсегда
+#[derive(Clone, Copy, Debug)]
+pub struct IdleReaping {
+    /// Как часто проверяются сессии
+    pub interval: Duration,
+    /// Сколько сессия может молчать до удаления
+    pub timeout: Duration,
+}
+
+/// Ограничение частоты сообщений одной сессии на сервере
+#[derive(Clone, Copy, Debug)]
+pub struct FloodLimit {
+    /// Сколько сообщений подряд разрешено
+    pub burst: u32,
+    /// За сколько пополняются все `burst` сообщений
+    pub window: Duration,
+    /// После скольких предупреждений сессия отключается, 0 без отключения
+    pub max_warnings: u32,
+}
+
+impl Default for FloodLimit {
+    fn default() -> FloodLimit {
+        FloodLimit {
+            burst: 10,
+            window: Duration::from_secs(10),
+            max_warnings: 3,
+        }
+    }
+}
+
+/// Состояние ограничения сообщений сессии
+#[derive(Clone)]
+struct Flood {
+    bucket: TokenBucket,
+    /// Когда отправлено последнее предупреждение
+    warned: Option<Instant>,
+    warnings: u32,
+}
+
+/// Какие уведомления о входе и выходе получает комната
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Announcements {
+    /// Уведомления о каждом входе и выходе и текущее количество участников
+    #[default]
+    Full,
+    /// Только текущее количество участников
+    CountsOnly,
+    /// Без уведомлений и без количества участников
+    Silent,
+}
+
+impl FromStr for Announcements {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Announcements::Full),
+            "counts_only" => Ok(Announcements::CountsOnly),
+            "silent" => Ok(Announcements::Silent),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Настройка комнаты, которую может изменить владелец
+pub enum RoomOption {
+    Announcements(Announcements),
+    /// Показывать ли статусы участников
+    Statuses(bool),
+}
+
+/// Изменить настройку комнаты. Доступно только владельцу комнаты
+#[derive(Message)]
+#[rtype(result = "Result<(), NotOwner>")]
+pub struct SetRoomOption {
+    /// Id сессии, которая меняет настройку
+    pub by: usize,
+    /// Название комнаты
+    pub room: String,
+    pub option: RoomOption,
+}
+
+/// Отправитель не является владельцем комнаты
+#[derive(Debug)]
+pub struct NotOwner;
+
+impl NotOwner {
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::NotPermitted
+    }
+}
+
+impl fmt::Display for NotOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "only the room owner can change room options")
+    }
+}
+
+/// Сессия, зарегистрированная на сервере чата
+#[derive(Clone)]
+struct Session {
+    addr: Recipient<Message>,
+    control: Recipient<Control>,
+    name: Option<String>,
+    /// Произвольный статус пользователя
+    status: Option<String>,
+    /// Пользователь отошел
+    away: Option<Away>,
+    /// Токен возобновления после отключения
+    token: String,
+    peer: Option<SocketAddr>,
+    user_agent: Option<String>,
+    /// Когда сессия подключилась
+    connected: SystemTime,
+    /// Когда от сессии пришел последний `Touch`
+    last_seen: Instant,
+}
+
+/// Отключенная сессия, которая еще может вернуться по токену
+struct Suspended {
+    id: usize,
+    name: Option<String>,
+    status: Option<String>,
+    away: Option<Away>,
+    room: Option<String>,
+    expires: Instant,
+}
+
+/// Комната чата
+struct Room {
+    /// Id сессий в комнате
+    sessions: HashSet<usize>,
+    /// Id сессии владельца: создателя комнаты или того, кому владение перешло
+    owner: Option<usize>,
+    /// Id сессий модераторов
+    moderators: HashSet<usize>,
+    /// Когда каждый участник вошел в комнату
+    joined: HashMap<usize, Instant>,
+    /// Режим уведомлений о входе и выходе
+    announcements: Announcements,
+    /// Номер последнего события, разосланного в комнату
+    seq: u64,
+    /// Id сессий, которым запрещено писать в комнату
+    muted: HashSet<usize>,
+    /// Последние сообщения комнаты, самое старое первым
+    history: VecDeque<HistoryEntry>,
+    /// Номер последнего сообщения, вытесненного из истории
+    evicted_id: u64,
+    /// Статусы участников не показываются в комнате
+    hide_statuses: bool,
+    /// SHA-256 пароля комнаты
+    password: Option<Vec<u8>>,
+    /// Сколько сообщений отправлено в комнату с момента создания
+    messages: u64,
+    /// Когда комната создана
+    created: SystemTime,
+    /// Последнее сообщение или вход в комнату
+    last_activity: SystemTime,
+    /// Когда комнату покинул последний участник
+    emptied: Option<Instant>,
+    /// Медленный режим: участник пишет не чаще раза за интервал
+    slow_mode: Option<Duration>,
+    /// Последнее сообщение каждого участника, пока включен медленный режим
+    last_post: HashMap<usize, Instant>,
+    /// Тема комнаты, её видит каждый вошедший
+    topic: Option<String>,
+}
+
+impl Default for Room {
+    fn default() -> Room {
+        let now = SystemTime::now();
+        Room {
+            sessions: HashSet::new(),
+            owner: None,
+            moderators: HashSet::new(),
+            joined: HashMap::new(),
+            announcements: Announcements::default(),
+            seq: 0,
+            muted: HashSet::new(),
+            history: VecDeque::new(),
+            evicted_id: 0,
+            hide_statuses: false,
+            password: None,
+            messages: 0,
+            created: now,
+            last_activity: now,
+            emptied: None,
+            slow_mode: None,
+            last_post: HashMap::new(),
+            topic: None,
+        }
+    }
+}
+
+/// Сессия набирает сообщение
+struct TypingState {
+    room: String,
+    /// Когда комната последний раз получила уведомление
+    notified: Instant,
+    /// Последнее событие `Typing`, таймер окончания набора сверяется с ним
+    last_event: Instant,
+}
+
+/// Хеш пароля комнаты
+fn hash_password(password: &str) -> Vec<u8> {
+    Sha256::digest(password.as_bytes()).to_vec()
+}
+
+/// Совпадают ли секреты. Время сравнения не зависит ни от длины общего
+/// префикса, ни от длины секретов: сравниваются их хеши, байт за байтом до конца
+pub fn secrets_match(expected: &str, given: &str) -> bool {
+    let expected = Sha256::digest(expected.as_bytes());
+    let given = Sha256::digest(given.as_bytes());
+    expected
+        .iter()
+        .zip(given.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Сообщение, сохраненное в истории комнаты
+#[derive(Clone)]
+pub struct HistoryEntry {
+    /// Номер сообщения в комнате
+    pub msg_id: u64,
+    /// Имя автора
+    pub from: Option<Arc<str>>,
+    /// Тот же текст, что был разослан участникам
+    pub text: Arc<str>,
+    /// Когда сервер разослал сообщение
+    pub ts: SystemTime,
+    /// Id сессии автора, сохраняется и в базе. У сообщений интеграций его нет,
+    /// такие сообщения нельзя исправить
+    pub author: Option<usize>,
+    /// Сообщение удалено, `text` содержит пометку `DELETED_TEXT`
+    pub deleted: bool,
+}
+
+impl Room {
+    /// Добавить сессию в комнату. Возвращает `true`, если сессии в ней не было.
+    /// Вход считается активностью, и комната перестает быть пустой
+    fn enter(&mut self, id: usize) -> bool {
+        let entered = self.sessions.insert(id);
+        if entered {
+            self.joined.insert(id, Instant::now());
+        }
+        self.emptied = None;
+        self.last_activity = SystemTime::now();
+        entered
+    }
+
+    /// Убрать сессию из комнаты вместе с её ограничениями и ролью.
+    /// Владение переходит к участнику, который дольше всех в комнате.
+    /// Возвращает `true`, если сессия была в комнате
+    fn leave(&mut self, id: usize) -> bool {
+        self.muted.remove(&id);
+        self.moderators.remove(&id);
+        self.joined.remove(&id);
+        self.last_post.remove(&id);
+        let left = self.sessions.remove(&id);
+        if left && self.sessions.is_empty() {
+            self.emptied = Some(Instant::now());
+        }
+        if self.owner == Some(id) {
+            self.owner = self
+                .joined
+                .iter()
+                .min_by_key(|(_, since)| **since)
+                .map(|(&member, _)| member);
+            if let Some(owner) = self.owner {
+                self.moderators.remove(&owner);
+            }
+        }
+        left
+    }
+
+    fn role(&self, id: usize) -> Role {
+        if self.owner == Some(id) {
+            Role::Owner
+        } else if self.moderators.contains(&id) {
+            Role::Moderator
+        } else {
+            Role::Member
+        }
+    }
+}
+
+/// Счетчики сервера, общие с HTTP-обработчиками.
+/// Их можно читать без сообщения `ChatServer`
+#[derive(Default)]
+pub struct Counters {
+    /// Сколько всего было новых подключений, возобновления не считаются
+    pub visitors: AtomicUsize,
+    /// Сколько сессий подключено сейчас
+    pub active_sessions: AtomicUsize,
+}
+
+/// `ChatServer` управляет чатами и отвечает за координацию сеансов чата. реализация супер примитивна
+pub struct ChatServer {
+    sessions: HashMap<usize, Session>,
+    rooms: HashMap<String, Room>,
+    /// Комната -> забаненные имена в нижнем регистре
+    banned: HashMap<String, HashSet<String>>,
+    rng: ThreadRng,
+    counters: Arc<Counters>,
+    /// Режим уведомлений для новых комнат
+    default_announcements: Announcements,
+    /// Сколько последних сообщений хранится в каждой комнате
+    history_len: usize,
+    /// Сколько сообщений истории повторяется сессии при входе в комнату
+    replay_len: usize,
+    /// Сколько сообщений разослано в комнаты с запуска сервера
+    messages_total: u64,
+    flood_limit: FloodLimit,
+    /// Комната новых сессий и выгнанных пользователей, она никогда не удаляется
+    default_room: String,
+    /// Удаление молчащих сессий, если включено
+    idle_reaping: Option<IdleReaping>,
+    /// Ждет отключения последней сессии после `Shutdown`
+    drained: Option<oneshot::Sender<()>>,
+    /// Через сколько удаляется комната без сообщений и входов, если включено
+    room_ttl: Option<Duration>,
+    /// Фильтры текста сообщений, применяются по порядку
+    filters: Vec<Box<dyn MessageFilter>>,
+    /// Сообщение дня для новых сессий
+    motd: Option<String>,
+    /// Максимальная длина сообщения сессии в байтах, без ограничения если не задана
+    max_message_len: Option<usize>,
+    /// Ограничение частоты сообщений по id сессии
+    floods: HashMap<usize, Flood>,
+    /// Сессии, смена статуса которых еще не объявлена
+    pending_status: HashSet<usize>,
+    /// Id приглашенной сессии -> комната -> когда приглашение истекает
+    invites: HashMap<usize, HashMap<String, Instant>>,
+    /// Сколько сессий может быть подключено одновременно, во всех частях сервера
+    max_connections: usize,
+    /// Вместимость новых комнат, для которых она не указана
+    default_capacity: Option<usize>,
+    /// База истории, если задан `CHAT_DB_PATH`
+    store: Option<Addr<Store>>,
+    /// Сколько действует приглашение
+    invite_ttl: Duration,
+    /// Комната -> наибольшее число участников
+    capacities: HashMap<String, usize>,
+    /// (отправитель, отошедший пользователь) -> когда отправлен последний автоответ
+    away_replies: HashMap<(usize, usize), Instant>,
+    /// Сессии, набирающие сообщение
+    typing: HashMap<usize, TypingState>,
+    /// Имена, подключения и токены, общие для всех частей сервера
+    directory: Arc<Directory>,
+    /// Номер этой части сервера в `directory`
+    shard: usize,
+    /// Управляемые сбои
+    #[cfg(feature = "chaos")]
+    chaos: Arc<Chaos>,
+}
+
+impl ChatServer {
+    pub fn new(
+        counters: Arc<Counters>,
+        default_announcements: Announcements,
+        history_len: usize,
+        replay_len: usize,
+        invite_ttl: Duration,
+        max_connections: usize,
+        default_capacity: Option<usize>,
+    ) -> ChatServer {
+        // комната по умолчанию
+        let mut rooms = HashMap::new();
+        rooms.insert(DEFAULT_ROOM.to_owned(), Room::default());
+
+        ChatServer {
+            sessions: HashMap::new(),
+            rooms,
+            banned: HashMap::new(),
+            rng: rand::thread_rng(),
+            counters,
+            default_announcements,
+            history_len,
+            replay_len,
+            messages_total: 0,
+            flood_limit: FloodLimit::default(),
+            default_room: DEFAULT_ROOM.to_owned(),
+            idle_reaping: None,
+            drained: None,
+            room_ttl: None,
+            filters: Vec::new(),
+            motd: None,
+            max_message_len: None,
+            floods: HashMap::new(),
+            pending_status: HashSet::new(),
+            invites: HashMap::new(),
+            invite_ttl,
+            max_connections,
+            default_capacity,
+            store: None,
+            capacities: HashMap::new(),
+            away_replies: HashMap::new(),
+            typing: HashMap::new(),
+            directory: Arc::new(Directory::new(1)),
+            shard: 0,
+            #[cfg(feature = "chaos")]
+            chaos: Arc::default(),
+        }
+    }
+
+    /// Сделать сервер частью `shard` из `directory`. Комнаты других частей,
+    /// включая сохраненные, отбрасываются, поэтому вызывается после остальных настроек
+    fn with_shard(mut self, directory: Arc<Directory>, shard: usize) -> ChatServer {
+        self.rooms.retain(|name, _| directory.owner(name) == shard);
+        self.directory = directory;
+        self.shard = shard;
+        self
+    }
+
+    /// Отклонять сообщения сессий длиннее `max_message_len` байт. Сессии
+    /// проверяют длину сами, сервер повторяет проверку для других источников
+    pub fn with_max_message_len(mut self, max_message_len: usize) -> ChatServer {
+        self.max_message_len = Some(max_message_len);
+        self
+    }
+
+    /// Показывать каждой новой сессии сообщение дня
+    pub fn with_motd(mut self, motd: String) -> ChatServer {
+        self.motd = Some(motd);
+        self
+    }
+
+    /// Изменить ограничение частоты сообщений сессий
+    pub fn with_flood_limit(mut self, flood_limit: FloodLimit) -> ChatServer {
+        self.flood_limit = flood_limit;
+        self
+    }
+
+    /// Заменить комнату по умолчанию на `name`. Вызывается до `with_store`,
+    /// пока прежняя комната по умолчанию пуста
+    pub fn with_default_room(mut self, name: String) -> ChatServer {
+        self.rooms.remove(&self.default_room);
+        self.rooms.entry(name.clone()).or_default();
+        self.default_room = name;
+        self
+    }
+
+    /// Пропускать сообщения пользователей и интеграций через `filters`
+    pub fn with_filters(mut self, filters: Vec<Box<dyn MessageFilter>>) -> ChatServer {
+        self.filters = filters;
+        self
+    }
+
+    /// Удалять сессии, молчащие дольше `idle_reaping.timeout`
+    pub fn with_idle_reaping(mut self, idle_reaping: IdleReaping) -> ChatServer {
+        self.idle_reaping = Some(idle_reaping);
+        self
+    }
+
+    /// Удалять комнаты без сообщений и входов дольше `ttl`, даже если в них
+    /// остались участники. Участники переходят в комнату по умолчанию
+    pub fn with_room_ttl(mut self, ttl: Duration) -> ChatServer {
+        self.room_ttl = Some(ttl);
+        self
+    }
+
+    /// Записывать сообщения комнат в `store` и восстановить сохраненные комнаты
+    pub fn with_store(mut self, store: Addr<Store>, rooms: Vec<StoredRoom>) -> ChatServer {
+        for stored in rooms {
+            let room = self.rooms.entry(stored.name).or_default();
+            // номера сообщений продолжаются после сохраненных
+            room.seq = stored.history.last().map_or(0, |entry| entry.msg_id);
+            // база отдает не больше `history_len` сообщений, при неполной
+            // истории ничего не вытеснено
+            if stored.history.len() >= self.history_len {
+                room.evicted_id = stored
+                    .history
+                    .first()
+                    .map_or(room.seq, |entry| entry.msg_id - 1);
+            }
+            room.history = stored.history.into();
+            // в восстановленной комнате никого нет, и без входа она удаляется
+            // после обычной паузы
+            if room.sessions.is_empty() {
+                room.emptied = Some(Instant::now());
+            }
+        }
+        self.store = Some(store);
+        self
+    }
+
+    /// Использовать общий набор сбоев
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, chaos: Arc<Chaos>) -> ChatServer {
+        self.chaos = chaos;
+        self
+    }
+
+    /// Задержать обработчик, если включен сбой задержки
+    #[cfg(feature = "chaos")]
+    fn chaos_latency(&self) {
+        if let Some(delay) = self.chaos.latency() {
+            std::thread::sleep(delay);
+        }
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    fn chaos_latency(&self) {}
+
+    /// Отключить случайную сессию, если включен сбой отключений
+    #[cfg(feature = "chaos")]
+    fn chaos_disconnect(&mut self) {
+        if self.sessions.is_empty() || !self.chaos.should_disconnect() {
+            return;
+        }
+        let n = self.rng.gen_range(0..self.sessions.len());
+        if let Some(session) = self.sessions.values().nth(n) {
+            let _ = session.control.do_send(Control::Dropped);
+        }
+    }
+}
+
+impl ChatServer {
+    /// Отправить сообщение всем пользователям в комнате
+    ///
+    /// Каждая рассылка, включая уведомления о входе и выходе, получает
+    /// следующий порядковый номер комнаты
+    fn send_message(&mut self, room: &str, message: &str, skip_id: usize) {
+        let _ = self.broadcast(room, Arc::from(message), None, skip_id, None);
+    }
+
+    /// Разослать сообщение в комнату от имени `from`.
+    /// Возвращает номер и время, присвоенные сообщению
+    ///
+    /// Текст и имя разделяются между всеми получателями без копирования.
+    /// Сессии с закрытым почтовым ящиком удаляются после рассылки
+    fn broadcast(
+        &mut self,
+        room: &str,
+        text: Arc<str>,
+        from: Option<Arc<str>>,
+        skip_id: usize,
+        via: Option<&'static str>,
+    ) -> Option<(u64, SystemTime)> {
+        let mut dead = Vec::new();
+        let room = self.rooms.get_mut(room)?;
+        room.seq += 1;
+        let (msg_id, ts) = (room.seq, SystemTime::now());
+        for id in &room.sessions {
+            if *id != skip_id {
+                if let Some(session) = self.sessions.get(id) {
+                    let sent = session.addr.do_send(Message {
+                        text: text.clone(),
+                        msg_id: Some(msg_id),
+                        prev_id: Some(msg_id - 1),
+                        ts,
+                        from: from.clone(),
+                        replay: false,
+                        mention: None,
+                        via,
+                    });
+                    if let Err(SendError::Closed(_)) = sent {
+                        dead.push(*id);
+                    }
+                }
+            }
+        }
+
+        for id in dead {
+            warn!("session {} is gone, removing it", id);
+            self.remove_session(id);
+        }
+        Some((msg_id, ts))
+    }
+
+    /// Отправить системное сообщение только одной сессии, в том числе
+    /// сессии другой части сервера
+    fn send_private(&self, id: usize, message: &str) {
+        if let Some(addr) = self.recipient(id) {
+            let _ = addr.do_send(Message::from(message.to_owned()));
+        }
+    }
+
+    /// Адрес сессии: своей напрямую, сессии другой части сервера через `directory`
+    fn recipient(&self, id: usize) -> Option<Recipient<Message>> {
+        match self.sessions.get(&id) {
+            Some(session) => Some(session.addr.clone()),
+            None => self
+                .directory
+                .registry()
+                .homes
+                .get(&id)
+                .map(|home| home.addr.clone()),
+        }
+    }
+
+    /// Сообщить вошедшей сессии тему комнаты, если она задана
+    fn send_topic(&self, id: usize, room: &str) {
+        if let Some(topic) = self.rooms.get(room).and_then(|room| room.topic.as_deref()) {
+            self.send_private(id, &format!("topic: {}", topic));
+        }
+    }
+
+    /// Повторить вошедшей сессии последние `replay_len` сообщений комнаты
+    fn replay_history(&self, id: usize, room: &str) {
+        let (session, room) = match (self.sessions.get(&id), self.rooms.get(room)) {
+            (Some(session), Some(room)) => (session, room),
+            _ => return,
+        };
+        let skip = room.history.len().saturating_sub(self.replay_len);
+        for entry in room.history.iter().skip(skip) {
+            let _ = session.addr.do_send(Message {
+                text: entry.text.clone(),
+                msg_id: Some(entry.msg_id),
+                prev_id: None,
+                ts: entry.ts,
+                from: entry.from.clone(),
+                replay: true,
+                mention: None,
+                via: None,
+            });
+        }
+    }
+
+    /// Отправить служебное сообщение одной сессии, в том числе сессии
+    /// другой части сервера
+    fn send_control(&self, id: usize, control: Control) {
+        match self.sessions.get(&id) {
+            Some(session) => {
+                let _ = session.control.do_send(control);
+            }
+            None => {
+                if let Some(home) = self.directory.registry().homes.get(&id) {
+                    let _ = home.control.do_send(control);
+                }
+            }
+        }
+    }
+
+    /// Сообщить сессии об отказе
+    fn send_error(&self, id: usize, code: ErrorCode, message: &str) {
+        self.send_control(
+            id,
+            Control::Error {
+                code,
+                message: message.to_owned(),
+            },
+        );
+    }
+
+    /// Является ли сессия владельцем комнаты
+    fn is_owner(&self, room: &str, id: usize) -> bool {
+        self.rooms.get(room).is_some_and(|r| r.owner == Some(id))
+    }
+
+    /// Может ли сессия модерировать комнату: выгонять, банить и лишать голоса
+    fn can_moderate(&self, room: &str, id: usize) -> bool {
+        self.rooms
+            .get(room)
+            .is_some_and(|r| r.role(id) > Role::Member)
+    }
+
+    /// Модерировать можно только участников с младшей ролью
+    fn check_outranks(&self, room: &str, by: usize, target: usize) -> Result<(), ModerationError> {
+        match self.rooms.get(room) {
+            Some(r) if r.role(target) >= r.role(by) => Err(ModerationError::Outranked),
+            _ => Ok(()),
+        }
+    }
+
+    /// Разослать объявление каждой сессии по одному разу, текст общий для всех
+    fn announce_all(&self, text: &str) {
+        let text: Arc<str> = Arc::from(text);
+        for session in self.sessions.values() {
+            let _ = session
+                .control
+                .do_send(Control::Announcement { text: text.clone() });
+        }
+    }
+
+    /// Id сессии с этим именем без учета регистра, в любой части сервера
+    fn resolve_name(&self, name: &str) -> Option<usize> {
+        self.directory
+            .registry()
+            .names
+            .get(&name_key(name))
+            .copied()
+    }
+
+    /// Владеет ли эта часть сервера комнатой `room`
+    fn owns(&self, room: &str) -> bool {
+        self.directory.owner(room) == self.shard
+    }
+
+    /// Найти участника комнаты по имени
+    fn find_member(&self, room: &str, name: &str) -> Result<usize, ModerationError> {
+        let members = &self
+            .rooms
+            .get(room)
+            .ok_or(ModerationError::NoSuchUser)?
+            .sessions;
+        self.resolve_name(name)
+            .filter(|id| members.contains(id))
+            .ok_or(ModerationError::NoSuchUser)
+    }
+
+    /// Убрать сессию из комнаты и переместить её в комнату по умолчанию,
+    /// `control` объясняет сессии причину. Комнатой по умолчанию может
+    /// владеть другая часть сервера, тогда сессия переезжает туда
+    fn move_to_default_room(&mut self, id: usize, from: &str, control: Control) {
+        if let Some(r) = self.rooms.get_mut(from) {
+            r.leave(id);
+        }
+        // сессия переезжает в другую часть сервера, в комнату по умолчанию
+        // её переведет та часть, если понадобится
+        if !self.sessions.contains_key(&id) {
+            return;
+        }
+        if self.owns(&self.default_room) {
+            self.enter_default_room(id, control);
+        } else if let Some((session, flood)) = self.release(id) {
+            self.directory.shard(&self.default_room).do_send(Relocate {
+                id,
+                session,
+                flood,
+                control,
+            });
+        }
+    }
+
+    /// Добавить сессию в комнату по умолчанию этой части сервера
+    fn enter_default_room(&mut self, id: usize, control: Control) {
+        let to_room = self.default_room.clone();
+        self.rooms.entry(to_room.clone()).or_default().enter(id);
+
+        self.send_control(id, control);
+        let notice = format!("{} joined", self.display_name(id));
+        self.announce(&to_room, &notice, id);
+    }
+
+    /// Имя сессии для уведомлений, безымянные сессии называются `guest-NNNN`
+    fn display_name(&self, id: usize) -> String {
+        self.sessions
+            .get(&id)
+            .and_then(|s| s.name.clone())
+            .unwrap_or_else(|| format!("guest-{:04}", id % 10_000))
+    }
+
+    /// Отправить уведомление о входе или выходе в соответствии с настройкой
+    /// комнаты. Число участников после изменения получают все, кроме `skip_id`,
+    /// если комната не молчит: по нему клиенты показывают заполненность комнаты
+    fn announce(&mut self, room: &str, notice: &str, skip_id: usize) {
+        let (members, announcements) = match self.rooms.get(room) {
+            Some(r) => (r.sessions.len(), r.announcements),
+            None => return,
+        };
+        if announcements == Announcements::Silent {
+            return;
+        }
+        if announcements == Announcements::Full {
+            let online = match members {
+                1 => "1 user online".to_owned(),
+                n => format!("{} users online", n),
+            };
+            let notice = format!("{} ({})", notice, online);
+            self.send_message(room, &notice, skip_id);
+        }
+        let counts = match members {
+            1 => format!("room {} now has 1 member", room),
+            n => format!("room {} now has {} members", room, n),
+        };
+        self.send_message(room, &counts, skip_id);
+    }
+}
+
+/// Создайте актера из `ChatServer`
+impl Actor for ChatServer {
+    /// Мы будем использовать простой Контекст, нам просто необходимо умение общаться с другими актерами.
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(SWEEP_INTERVAL, |act, _| {
+            act.expire_invites();
+            act.expire_suspended();
+            act.collect_rooms();
+        });
+        ctx.run_interval(PRESENCE_INTERVAL, |act, _| act.sweep_sessions());
+        if let Some(reaping) = self.idle_reaping {
+            ctx.run_interval(reaping.interval, move |act, _| {
+                act.reap_idle(reaping.timeout)
+            });
+        }
+        #[cfg(feature = "chaos")]
+        ctx.run_interval(Duration::from_secs(1), |act, _| act.chaos_disconnect());
+    }
+}
+
+/// Обработчик для сообщения Connect.
+///
+/// Зарегистрируйте новую сессию и присвойте ей уникальный идентификатор.
+/// С действующим токеном возобновления сессия получает прежний id, имя и
+/// комнату, если имя еще свободно, а комната существует и не забанила её
+impl Handler<Connect> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<Connected, ServerFull>>;
+
+    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
+        self.chaos_latency();
+        let now = Instant::now();
+        let mut registry = self.directory.registry();
+        if registry.homes.len() >= self.max_connections {
+            warn!(
+                "rejecting connection: {} sessions already connected",
+                registry.homes.len()
+            );
+            return Box::pin(fut::ready(Err(ServerFull)));
+        }
+
+        let resumed = msg
+            .resume
+            .and_then(|token| registry.suspended.remove(&token))
+            .filter(|s| s.expires > now && !registry.homes.contains_key(&s.id));
+
+        // зарегистрировать сессию со случайным идентификатором
+        let id = match &resumed {
+            Some(suspended) => suspended.id,
+            None => self.rng.gen_range(EXTERNAL_SENDER + 1..=usize::MAX),
+        };
+        info!("session {} connected", id);
+        let name = resumed
+            .as_ref()
+            .and_then(|s| s.name.clone())
+            .filter(|name| !registry.names.contains_key(&name_key(name)));
+        if let Some(name) = &name {
+            registry.names.insert(name_key(name), id);
+        }
+        let (status, away, room) = match &resumed {
+            Some(suspended) => (
+                suspended.status.clone(),
+                suspended.away.clone(),
+                suspended.room.clone(),
+            ),
+            None => (None, None, None),
+        };
+        let token = format!("{:032x}", self.rng.gen::<u128>());
+        registry.insert_home(
+            id,
+            Home {
+                shard: self.shard,
+                moving: false,
+                disconnected: false,
+                addr: msg.addr.clone(),
+                control: msg.control.clone(),
+                token: token.clone(),
+                name: name.clone(),
+                away: away.clone(),
+            },
+        );
+        drop(registry);
+
+        let session = Session {
+            addr: msg.addr,
+            control: msg.control,
+            name,
+            status,
+            away,
+            token,
+            peer: msg.peer,
+            user_agent: msg.user_agent,
+            connected: SystemTime::now(),
+            last_seen: now,
+        };
+        self.counters.active_sessions.fetch_add(1, Ordering::SeqCst);
+
+        self.admit(Admit {
+            id,
+            session,
+            room,
+            resumed: resumed.is_some(),
+        })
+    }
+}
+
+impl ChatServer {
+    /// Впустить зарегистрированную сессию в прежнюю комнату, если она есть и
+    /// не забанила сессию, иначе в комнату по умолчанию. Если комнатой владеет
+    /// другая часть сервера, сессию впускает она
+    fn admit(&mut self, admit: Admit) -> ResponseActFuture<Self, Result<Connected, ServerFull>> {
+        let key = admit.session.name.as_deref().map(name_key);
+        let room = admit.room.clone().filter(|room| {
+            !self.owns(room)
+                || (self.rooms.contains_key(room)
+                    && match (&key, self.banned.get(room)) {
+                        (Some(key), Some(banned)) => !banned.contains(key),
+                        _ => true,
+                    })
+        });
+        let to_room = room
+            .clone()
+            // автоматическое присоединение сеанса к основной комнате
+            .unwrap_or_else(|| self.default_room.clone());
+
+        if !self.owns(&to_room) {
+            let (id, name) = (admit.id, admit.session.name.clone());
+            let admitted = self
+                .directory
+                .shard(&to_room)
+                .send(Admit { room, ..admit })
+                .into_actor(self)
+                .map(move |res, act, _| {
+                    res.unwrap_or_else(|_| {
+                        // сессию некуда впустить, подключения не было
+                        act.unregister(id, name.as_deref());
+                        Err(ServerFull)
+                    })
+                });
+            return Box::pin(admitted);
+        }
+        Box::pin(fut::ready(Ok(self.enter(
+            admit.id,
+            admit.session,
+            to_room,
+            admit.resumed,
+        ))))
+    }
+
+    /// Снять регистрацию сессии, которая так и не вошла в комнату
+    fn unregister(&mut self, id: usize, name: Option<&str>) {
+        let mut registry = self.directory.registry();
+        registry.remove_home(id);
+        if let Some(name) = name {
+            registry.names.remove(&name_key(name));
+        }
+        self.counters.active_sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Добавить сессию в комнату этой части сервера при подключении
+    fn enter(&mut self, id: usize, session: Session, room: String, resumed: bool) -> Connected {
+        if let Some(home) = self.directory.registry().homes.get_mut(&id) {
+            home.shard = self.shard;
+        }
+        let connected = Connected {
+            id,
+            token: session.token.clone(),
+            name: session.name.clone(),
+            away: session.away.clone(),
+            room: room.clone(),
+        };
+        self.sessions.insert(id, session);
+
+        let r = self.rooms.entry(room.clone()).or_default();
+        r.enter(id);
+        let online = r.sessions.len();
+
+        if resumed {
+            self.send_private(id, &format!("Welcome back! {} in {}", online, room));
+            let notice = format!("{} reconnected", self.display_name(id));
+            self.announce(&room, &notice, id);
+        } else {
+            // общее число посетителей видит только новая сессия
+            let count = self.counters.visitors.fetch_add(1, Ordering::SeqCst) + 1;
+            self.send_private(
+                id,
+                &format!("Welcome! Total visitors {}, {} in {}", count, online, room),
+            );
+            if let Some(motd) = &self.motd {
+                self.send_private(id, motd);
+            }
+
+            // оповестить всех пользователей в одной комнате
+            let notice = format!("{} joined", self.display_name(id));
+            self.announce(&room, &notice, id);
+        }
+        self.send_topic(id, &room);
+        self.replay_history(id, &room);
+
+        connected
+    }
+}
+
+/// Обработчик сообщения об отключении.
+impl Handler<Disconnect> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+        self.chaos_latency();
+        if !self.sessions.contains_key(&msg.id) {
+            self.forward_disconnect(msg);
+            return;
+        }
+        info!("session {} disconnected", msg.id);
+        self.remove_session(msg.id);
+    }
+}
+
+impl ChatServer {
+    /// Забыть сессию и объявить о выходе в её комнатах
+    fn remove_session(&mut self, id: usize) {
+        let mut rooms: Vec<String> = Vec::new();
+        let notice = format!("{} disconnected", self.display_name(id));
+
+        // remove address
+        self.floods.remove(&id);
+        self.invites.remove(&id);
+        self.away_replies
+            .retain(|&(from, to), _| from != id && to != id);
+        self.typing.remove(&id);
+
+        if let Some(session) = self.sessions.remove(&id) {
+            // remove session from all rooms
+            for (name, room) in &mut self.rooms {
+                if room.leave(id) {
+                    rooms.push(name.to_owned());
+                }
+            }
+
+            // место в числе подключений освобождает только та часть,
+            // за которой сессия числится
+            let mut registry = self.directory.registry();
+            if registry.remove_home(id).is_some() {
+                self.counters.active_sessions.fetch_sub(1, Ordering::SeqCst);
+            }
+            registry.room_creation.remove(&id);
+            // освободить имя
+            if let Some(name) = &session.name {
+                registry.names.remove(&name_key(name));
+            }
+
+            // сессия может вернуться по токену в течение RESUME_GRACE
+            registry.suspended.insert(
+                session.token,
+                Suspended {
+                    id,
+                    name: session.name,
+                    status: session.status,
+                    away: session.away,
+                    room: rooms.first().cloned(),
+                    expires: Instant::now() + RESUME_GRACE,
+                },
+            );
+        }
+        // send message to other users
+        for room in rooms {
+            self.announce(&room, &notice, 0);
+        }
+
+        if self.sessions.is_empty() {
+            if let Some(drained) = self.drained.take() {
+                let _ = drained.send(());
+            }
+        }
+    }
+}
+
+/// Handler for Message message.
+impl Handler<ClientMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, mut msg: ClientMessage, _: &mut Context<Self>) {
+        self.chaos_latency();
+        // сессию уже перевели в другую комнату, а она еще не узнала об этом
+        let member = self
+            .rooms
+            .get(&msg.room)
+            .is_some_and(|room| room.sessions.contains(&msg.id));
+        if !member {
+            return;
+        }
+        if let Err(refusal) = self.check_message(msg.id, &msg.room, &mut msg.msg) {
+            match msg.correlation_id {
+                Some(correlation_id) => self.send_control(
+                    msg.id,
+                    Control::Nack {
+                        correlation_id,
+                        reason: refusal.reason(),
+                    },
+                ),
+                // о превышении частоты сессию уже предупредил `check_flood`
+                None if matches!(refusal, Refusal::RateLimited) => (),
+                None => self.send_error(msg.id, refusal.code(), &refusal.to_string()),
+            }
+            return;
+        }
+
+        let msg_id = self.relay(msg.id, &msg.room, &msg.msg, None);
+        if let (Some(correlation_id), Some(msg_id)) = (msg.correlation_id, msg_id) {
+            self.send_control(
+                msg.id,
+                Control::Ack {
+                    correlation_id,
+                    msg_id,
+                },
+            );
+        }
+    }
+}
+
+/// Обработчик сообщения `RestMessage`.
+///
+/// Сообщение проходит те же проверки и тот же ограничитель частоты, что и
+/// сообщения из вебсокета сессии, а сама сессия получает его как все участники
+impl Handler<RestMessage> for ChatServer {
+    type Result = Result<u64, RestError>;
+
+    fn handle(&mut self, msg: RestMessage, _: &mut Context<Self>) -> Self::Result {
+        let id = self
+            .directory
+            .registry()
+            .session_by_token(&msg.token)
+            .ok_or(RestError::Unauthorized)?;
+        // сессия в другой части сервера, значит и не в этой комнате
+        let session = self.sessions.get(&id).ok_or(RestError::NotMember)?;
+        let mut text = match &session.name {
+            Some(name) => format!("{}: {}", name, msg.text),
+            None if msg.require_name => return Err(RestError::NameRequired),
+            None => msg.text,
+        };
+        if !self
+            .rooms
+            .get(&msg.room)
+            .is_some_and(|room| room.sessions.contains(&id))
+        {
+            return Err(RestError::NotMember);
+        }
+
+        self.check_message(id, &msg.room, &mut text)
+            .map_err(RestError::Refused)?;
+        self.relay(id, &msg.room, &text, Some("rest"))
+            .ok_or(RestError::NotMember)
+    }
+}
+
+impl ChatServer {
+    /// Проверить сообщение сессии `id` перед рассылкой: длину, запрет писать,
+    /// медленный режим, фильтры и частоту. Фильтры могут переписать `text`
+    fn check_message(&mut self, id: usize, room: &str, text: &mut String) -> Result<(), Refusal> {
+        let name = self.sessions.get(&id).and_then(|s| s.name.as_deref());
+        let body_len = message_body(name, text).len();
+        if let Some(max) = self.max_message_len.filter(|&max| body_len > max) {
+            return Err(Refusal::TooLong(max));
+        }
+        if self.rooms.get(room).is_some_and(|r| r.muted.contains(&id)) {
+            return Err(Refusal::Muted);
+        }
+        if let Some(wait) = self.slow_mode_wait(room, id) {
+            return Err(Refusal::SlowMode(wait));
+        }
+
+        // фильтры видят текст без префикса с именем автора
+        let body = message_body(name, text);
+        match self.filter_text(room, id, body) {
+            Ok(None) => (),
+            Ok(Some(filtered)) => {
+                let prefix_len = text.len() - body.len();
+                text.replace_range(prefix_len.., &filtered);
+            }
+            Err(reason) => return Err(Refusal::Rejected(reason)),
+        }
+
+        if !self.check_flood(id) {
+            return Err(Refusal::RateLimited);
+        }
+        Ok(())
+    }
+
+    /// Разослать проверенное сообщение сессии `id` и выполнить все, что
+    /// следует за ним: медленный режим, конец набора, возврат из отсутствия,
+    /// упоминания. Возвращает номер сообщения
+    fn relay(
+        &mut self,
+        id: usize,
+        room: &str,
+        text: &str,
+        via: Option<&'static str>,
+    ) -> Option<u64> {
+        if let Some(room) = self.rooms.get_mut(room) {
+            if room.slow_mode.is_some() {
+                room.last_post.insert(id, Instant::now());
+            }
+        }
+
+        let from = self.sessions.get(&id).and_then(|s| s.name.clone());
+        // отправленное сообщение само завершает набор
+        self.typing.remove(&id);
+        // и возвращает из отсутствия
+        if self.sessions.get(&id).is_some_and(|s| s.away.is_some()) {
+            self.set_away(id, None);
+        }
+
+        let msg_id = self.post(room, text, from, Some(id), via);
+        if let Some(msg_id) = msg_id {
+            self.notify_mentions(id, room, text, msg_id);
+        }
+        self.reply_away(id, room, text);
+        msg_id
+    }
+}
+
+impl ChatServer {
+    /// Разослать сообщение пользователя и сохранить его в истории комнаты.
+    /// Автор, если он есть, не получает свое сообщение, кроме пришедших
+    /// не через его вебсокет (`via`). Возвращает номер сообщения
+    fn post(
+        &mut self,
+        name: &str,
+        text: &str,
+        from: Option<String>,
+        author: Option<usize>,
+        via: Option<&'static str>,
+    ) -> Option<u64> {
+        let text: Arc<str> = Arc::from(text);
+        let from: Option<Arc<str>> = from.map(Arc::from);
+        // сообщение не из вебсокета автора он тоже получает
+        let skip_id = author.filter(|_| via.is_none()).unwrap_or(0);
+        let sent = self.broadcast(name, text.clone(), from.clone(), skip_id, via);
+
+        if let (Some(room), Some((msg_id, ts))) = (self.rooms.get_mut(name), sent) {
+            self.messages_total += 1;
+            room.messages += 1;
+            room.last_activity = ts;
+            if self.history_len > 0 {
+                if room.history.len() == self.history_len {
+                    if let Some(evicted) = room.history.pop_front() {
+                        room.evicted_id = evicted.msg_id;
+                    }
+                }
+                room.history.push_back(HistoryEntry {
+                    msg_id,
+                    from: from.clone(),
+                    text: text.clone(),
+                    ts,
+                    author,
+                    deleted: false,
+                });
+            }
+            if let Some(store) = &self.store {
+                store.do_send(SaveMessage {
+                    room: name.to_owned(),
+                    entry: HistoryEntry {
+                        msg_id,
+                        from,
+                        text,
+                        ts,
+                        author,
+                        deleted: false,
+                    },
+                });
+            }
+        }
+        sent.map(|(msg_id, _)| msg_id)
+    }
+}
+
+/// Обработчик сообщения `ExternalMessage`
+impl Handler<ExternalMessage> for ChatServer {
+    type Result = Result<(), ExternalError>;
+
+    fn handle(&mut self, msg: ExternalMessage, _: &mut Context<Self>) -> Self::Result {
+        if !self.rooms.contains_key(&msg.room) {
+            return Err(ExternalError::NoSuchRoom);
+        }
+        let body = match self.filter_text(&msg.room, EXTERNAL_SENDER, &msg.text) {
+            Ok(filtered) => filtered.unwrap_or(msg.text),
+            Err(reason) => return Err(ExternalError::Rejected(reason)),
+        };
+        if !self.check_flood(EXTERNAL_SENDER) {
+            return Err(ExternalError::RateLimited);
+        }
+        // пометка не дает интеграции выдать себя за пользователя
+        let from = format!("{} (bot)", msg.from);
+        let text = format!("{}: {}", from, body);
+        let _ = self.post(&msg.room, &text, Some(from), None, None);
+        Ok(())
+    }
+}
+
+impl ChatServer {
+    /// Пропустить текст через фильтры по порядку. `Ok(None)`, если ни один
+    /// фильтр его не изменил, `Err` с причиной, если какой-то отклонил
+    fn filter_text(&self, room: &str, sender: usize, text: &str) -> Result<Option<String>, String> {
+        let mut rewritten: Option<String> = None;
+        for filter in &self.filters {
+            let current = rewritten.as_deref().unwrap_or(text);
+            match filter.apply(room, sender, current) {
+                FilterDecision::Pass => (),
+                FilterDecision::Rewrite(text) => rewritten = Some(text),
+                FilterDecision::Reject(reason) => return Err(reason),
+            }
+        }
+        Ok(rewritten)
+    }
+
+    /// Сколько участнику `id` ждать следующего сообщения в медленном режиме.
+    /// Модераторы не ограничены
+    fn slow_mode_wait(&self, room: &str, id: usize) -> Option<Duration> {
+        let room = self.rooms.get(room)?;
+        let interval = room.slow_mode?;
+        if room.role(id) > Role::Member {
+            return None;
+        }
+        let elapsed = room.last_post.get(&id)?.elapsed();
+        interval.checked_sub(elapsed).filter(|wait| !wait.is_zero())
+    }
+
+    /// Разрешить сообщение сессии `id`. Лишние сообщения отбрасываются, а
+    /// сессия получает не больше одного предупреждения за `window`. После
+    /// `max_warnings` предупреждений сессия отключается
+    fn check_flood(&mut self, id: usize) -> bool {
+        let limit = self.flood_limit;
+        let now = Instant::now();
+        let flood = self.floods.entry(id).or_insert_with(|| Flood {
+            bucket: TokenBucket::new(limit.burst, limit.window / limit.burst.max(1), now),
+            warned: None,
+            warnings: 0,
+        });
+        if flood.bucket.check(now) {
+            return true;
+        }
+
+        let warn = flood
+            .warned
+            .is_none_or(|at| now.duration_since(at) >= limit.window);
+        if warn {
+            flood.warned = Some(now);
+            flood.warnings += 1;
+        }
+        let flooded = limit.max_warnings > 0 && flood.warnings >= limit.max_warnings;
+        // округление вверх, чтобы не советовать повторить через 0s
+        let retry = flood.bucket.retry_after(now).as_millis().div_ceil(1000);
+
+        if warn && flooded {
+            warn!("session {} is flooding, disconnecting", id);
+            self.send_control(id, Control::Flooded);
+        } else if warn {
+            let message = format!("rate limited, retry in {}s", retry);
+            self.send_error(id, ErrorCode::RateLimited, &message);
+        }
+        false
+    }
+
+    /// Сессии, упомянутые в тексте как `@name`, без автора и повторов.
+    /// Неизвестные имена пропускаются. Упомянуть можно и сессию из другой
+    /// комнаты, кроме сообщений комнат с паролем
+    fn mentioned(&self, from: usize, room: &str, text: &str) -> Vec<usize> {
+        let room = match self.rooms.get(room) {
+            Some(room) => room,
+            None => return Vec::new(),
+        };
+        let visible = |id: &usize| room.password.is_none() || room.sessions.contains(id);
+
+        let mut ids = Vec::new();
+        for word in text.split_whitespace() {
+            let mention = match word.strip_prefix('@') {
+                Some(mention) => mention.trim_end_matches(|c: char| !c.is_alphanumeric()),
+                None => continue,
+            };
+            match self.resolve_name(mention) {
+                Some(id) if id != from && visible(&id) && !ids.contains(&id) => ids.push(id),
+                _ => (),
+            }
+        }
+        ids
+    }
+
+    /// Отправить упомянутым сессиям отдельную копию сообщения `msg_id`
+    fn notify_mentions(&self, from: usize, room: &str, text: &str, msg_id: u64) {
+        let author: Option<Arc<str>> = self
+            .sessions
+            .get(&from)
+            .and_then(|s| s.name.as_deref())
+            .map(Arc::from);
+        let mentioned = self.mentioned(from, room, text);
+        let text: Arc<str> = Arc::from(text);
+        let room: Arc<str> = Arc::from(room);
+        for id in mentioned {
+            if let Some(addr) = self.recipient(id) {
+                let _ = addr.do_send(Message {
+                    text: text.clone(),
+                    msg_id: Some(msg_id),
+                    prev_id: None,
+                    ts: SystemTime::now(),
+                    from: author.clone(),
+                    replay: false,
+                    mention: Some(room.clone()),
+                    via: None,
+                });
+            }
+        }
+    }
+
+    /// Ответить отправителю за отошедших пользователей, упомянутых как `@name`,
+    /// в любой комнате и части сервера
+    fn reply_away(&mut self, from: usize, room: &str, text: &str) {
+        let now = Instant::now();
+
+        let mentioned = self.mentioned(from, room, text);
+        let away: Vec<(usize, String, Away)> = {
+            let registry = self.directory.registry();
+            mentioned
+                .into_iter()
+                .filter_map(|id| match registry.homes.get(&id)? {
+                    Home {
+                        name: Some(name),
+                        away: Some(away),
+                        ..
+                    } => Some((id, name.clone(), away.clone())),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let mut replies = Vec::new();
+        for (id, name, away) in away {
+            let recent = self
+                .away_replies
+                .get(&(from, id))
+                .is_some_and(|at| now.duration_since(*at) < AWAY_REPLY_INTERVAL);
+            if recent {
+                continue;
+            }
+            self.away_replies.insert((from, id), now);
+            replies.push(match &away.reason {
+                Some(reason) => format!("{} is away: {}", name, reason),
+                None => format!("{} is away", name),
+            });
+        }
+
+        for reply in replies {
+            self.send_private(from, &reply);
+        }
+    }
+}
+
+/// Handler for `ListRooms` message.
+impl Handler<ListRooms> for ChatServer {
+    type Result = MessageResult<ListRooms>;
+
+    fn handle(&mut self, msg: ListRooms, _: &mut Context<Self>) -> Self::Result {
+        let filter = msg.filter.map(|filter| filter.to_lowercase());
+        let mut rooms: Vec<RoomInfo> = self
+            .rooms
+            .iter()
+            .filter(|(name, _)| {
+                filter
+                    .as_ref()
+                    .is_none_or(|filter| name.to_lowercase().contains(filter.as_str()))
+            })
+            .map(|(name, room)| RoomInfo {
+                name: name.to_owned(),
+                members: room.sessions.len(),
+                protected: room.password.is_some(),
+                capacity: self.capacities.get(name).copied(),
+            })
+            .collect();
+
+        sort_rooms(&mut rooms, msg.sort);
+        let total = rooms.len();
+        let rooms = rooms.into_iter().skip(msg.offset).take(msg.limit).collect();
+
+        MessageResult(RoomPage { rooms, total })
+    }
+}
+
+/// Упорядочить комнаты для `ListRooms`. Названия уникальны, поэтому порядок
+/// не зависит ни от порядка HashMap, ни от того, из каких частей сервера комнаты
+fn sort_rooms(rooms: &mut [RoomInfo], sort: RoomSort) {
+    match sort {
+        RoomSort::Name => rooms.sort_by(|a, b| a.name.cmp(&b.name)),
+        RoomSort::Members => {
+            rooms.sort_by(|a, b| b.members.cmp(&a.members).then_with(|| a.name.cmp(&b.name)))
+        }
+    }
+}
+
+/// Присоединиться к комнате, отправить сообщение о разъединении в старую комнату
+/// отправить сообщение о присоединении в новую комнату
+impl Handler<Join> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<(), JoinError>>;
+
+    fn handle(&mut self, msg: Join, _: &mut Context<Self>) -> Self::Result {
+        self.chaos_latency();
+        let join = match normalize_room_name(&msg.name) {
+            Some(name) => Join {
+                name: name.to_owned(),
+                ..msg
+            },
+            None => return Box::pin(fut::ready(Err(JoinError::InvalidName))),
+        };
+
+        if self.owns(&join.name) {
+            let session_name = self.sessions.get(&join.id).and_then(|s| s.name.clone());
+            let joined = self
+                .check_join(&join, session_name.as_deref())
+                .map(|()| self.enter_joined(join));
+            return Box::pin(fut::ready(joined));
+        }
+
+        // комнатой владеет другая часть сервера: она проверяет вход, и если
+        // пустит, сессия переезжает туда и уходит из комнат этой части.
+        // Отсюда сессия уходит до отправки, чтобы в любой момент ею владела
+        // ровно одна часть, а если вход не разрешат, возвращается обратно
+        let id = join.id;
+        let notice = format!("{} left the room", self.display_name(id));
+        let (session, flood) = match self.release(id) {
+            Some(released) => released,
+            None => return Box::pin(fut::ready(Err(JoinError::Unavailable))),
+        };
+        let transfer = Transfer {
+            session: session.clone(),
+            flood: flood.clone(),
+            join,
+        };
+        let moved = self
+            .directory
+            .shard(&transfer.join.name)
+            .send(transfer)
+            .into_actor(self)
+            .map(move |res, act, _| {
+                let moved = res.unwrap_or(Err(JoinError::Unavailable));
+                match moved {
+                    Ok(()) => act.hand_off(id, &notice),
+                    Err(_) => act.restore(id, session, flood),
+                }
+                moved
+            });
+        Box::pin(moved)
+    }
+}
+
+impl ChatServer {
+    /// Пустят ли сессию `join.id` с именем `session_name` в комнату `join.name`.
+    /// Проверка ничего не меняет, предел создания комнат расходует `enter_joined`
+    fn check_join(&self, join: &Join, session_name: Option<&str>) -> Result<(), JoinError> {
+        let Join {
+            id,
+            name,
+            password,
+            capacity,
+        } = join;
+
+        // забаненный пользователь остается в текущей комнате
+        if let (Some(banned), Some(session_name)) = (self.banned.get(name), session_name) {
+            if banned.contains(&name_key(session_name)) {
+                return Err(JoinError::Banned);
+            }
+        }
+
+        // приглашение заменяет пароль и расходуется только при успешном входе
+        let invited = self
+            .invites
+            .get(id)
+            .and_then(|invites| invites.get(name))
+            .is_some_and(|&expires| expires > Instant::now());
+
+        match self.rooms.get(name).map(|room| room.password.as_ref()) {
+            Some(Some(_)) if invited => (),
+            Some(Some(hash)) => match password {
+                None => return Err(JoinError::PasswordRequired),
+                Some(password) if hash_password(password) != *hash => {
+                    return Err(JoinError::WrongPassword)
+                }
+                Some(_) => (),
+            },
+            Some(None) => (),
+            None => {
+                if !self.directory.registry().room_creation.allows(id) {
+                    return Err(JoinError::TooManyRooms);
+                }
+            }
+        }
+
+        let members = self
+            .rooms
+            .get(name)
+            .filter(|room| !room.sessions.contains(id))
+            .map_or(0, |room| room.sessions.len());
+        if self
+            .room_capacity(name, *capacity)
+            .is_some_and(|cap| members >= cap)
+        {
+            return Err(JoinError::Full);
+        }
+        Ok(())
+    }
+
+    /// Наибольшее число участников комнаты `name`: заданное при создании
+    /// или, для ещё не созданной комнаты, `capacity` нового входа. Комната
+    /// по умолчанию создается вместе с сервером, поэтому общий предел её не касается
+    fn room_capacity(&self, name: &str, capacity: Option<usize>) -> Option<usize> {
+        if self.rooms.contains_key(name) {
+            self.capacities.get(name).copied()
+        } else {
+            capacity.or(self.default_capacity)
+        }
+    }
+
+    /// Перевести сессию в комнату `join.name` после `check_join`
+    fn enter_joined(&mut self, join: Join) {
+        let Join {
+            id,
+            name,
+            password,
+            capacity,
+        } = join;
+        let mut rooms = Vec::new();
+
+        // remove session from all rooms, повторный вход в текущую комнату
+        // не считается уходом
+        for (n, room) in &mut self.rooms {
+            if *n != name && room.leave(id) {
+                rooms.push(n.to_owned());
+            }
+        }
+        // send message to other users
+        let notice = format!("{} left the room", self.display_name(id));
+        for room in rooms {
+            self.announce(&room, &notice, 0);
+        }
+
+        // комната создается только здесь, после всех проверок
+        if !self.rooms.contains_key(&name) {
+            self.directory.registry().room_creation.check(&id);
+            if let Some(capacity) = self.room_capacity(&name, capacity) {
+                self.capacities.insert(name.clone(), capacity);
+            }
+        }
+
+        // создатель комнаты становится её владельцем
+        let announcements = self.default_announcements;
+        let joined = self
+            .rooms
+            .entry(name.clone())
+            .or_insert_with(|| Room {
+                owner: Some(id),
+                announcements,
+                password: password.as_deref().map(hash_password),
+                ..Room::default()
+            })
+            .enter(id);
+        if let Some(invites) = self.invites.get_mut(&id) {
+            invites.remove(&name);
+        }
+        if !joined {
+            return;
+        }
+
+        // новый участник видит тему и последние сообщения комнаты
+        self.send_topic(id, &name);
+        self.replay_history(id, &name);
+
+        // число участников считается уже с новым участником
+        let notice = format!("{} joined", self.display_name(id));
+        self.announce(&name, &notice, id);
+    }
+
+    /// Сессия перешла в комнату другой части сервера: уйти из комнат этой
+    /// части с уведомлением `notice`. Сама сессия забыта еще до переезда
+    fn hand_off(&mut self, id: usize, notice: &str) {
+        let rooms: Vec<String> = self
+            .rooms
+            .iter_mut()
+            .filter_map(|(name, room)| room.leave(id).then(|| name.clone()))
+            .collect();
+        for room in rooms {
+            self.announce(&room, notice, 0);
+        }
+    }
+}
+
+/// Обработчик сообщения `SetName`.
+///
+/// Имена сравниваются без учета регистра, но отображаются как заданы
+impl Handler<SetName> for ChatServer {
+    type Result = MessageResult<SetName>;
+
+    fn handle(&mut self, mut msg: SetName, _: &mut Context<Self>) -> Self::Result {
+        msg.name = match normalize_user_name(&msg.name) {
+            Some(name) => name.to_owned(),
+            None => return MessageResult(SetNameResult::Invalid),
+        };
+        // имя проверяется и занимается под одной блокировкой: его может
+        // занимать сессия другой части сервера
+        let mut registry = self.directory.registry();
+        if let Some(&owner) = registry.names.get(&name_key(&msg.name)) {
+            if owner != msg.id {
+                return MessageResult(SetNameResult::Taken);
+            }
+        }
+
+        // имя не занимается за сессией, которой здесь нет
+        let old = match self.sessions.get_mut(&msg.id) {
+            Some(session) => session.name.replace(msg.name.clone()),
+            None => return MessageResult(SetNameResult::Unavailable),
+        };
+        // освободить предыдущее имя
+        if let Some(old) = &old {
+            registry.names.remove(&name_key(old));
+        }
+        registry.names.insert(name_key(&msg.name), msg.id);
+        if let Some(home) = registry.homes.get_mut(&msg.id) {
+            home.name = Some(msg.name.clone());
+        }
+        drop(registry);
+
+        // комнаты узнают о смене имени, первое имя не объявляется
+        if let Some(old) = old.filter(|old| *old != msg.name) {
+            let notice = format!("{} is now known as {}", old, msg.name);
+            let rooms: Vec<String> = self
+                .rooms
+                .iter()
+                .filter(|(_, room)| room.sessions.contains(&msg.id))
+                .map(|(name, _)| name.clone())
+                .collect();
+            for room in rooms {
+                self.send_message(&room, &notice, 0);
+            }
+        }
+
+        MessageResult(SetNameResult::Ok)
+    }
+}
+
+/// Выгнать пользователя из комнаты: его сессия закрывается, а уход
+/// обрабатывается обычным `Disconnect`
+impl Handler<Kick> for ChatServer {
+    type Result = Result<(), ModerationError>;
+
+    fn handle(&mut self, msg: Kick, _: &mut Context<Self>) -> Self::Result {
+        let Kick {
+            by,
+            room,
+            target_name,
+        } = msg;
+
+        if !self.can_moderate(&room, by) {
+            return Err(ModerationError::NotModerator);
+        }
+        let target = self.find_member(&room, &target_name)?;
+        if target == by {
+            return Err(ModerationError::SelfTarget);
+        }
+        self.check_outranks(&room, by, target)?;
+
+        // выгнанная сессия не вернется в комнату по токену возобновления
+        if let Some(r) = self.rooms.get_mut(&room) {
+            r.leave(target);
+        }
+        self.send_control(target, Control::Removed { room: room.clone() });
+        self.send_message(&room, &format!("{} was kicked", target_name), target);
+
+        Ok(())
+    }
+}
+
+/// Забанить имя в комнате. Если пользователь сейчас в комнате, он перемещается
+/// в комнату по умолчанию
+impl Handler<Ban> for ChatServer {
+    type Result = Result<(), ModerationError>;
+
+    fn handle(&mut self, msg: Ban, _: &mut Context<Self>) -> Self::Result {
+        let Ban {
+            by,
+            room,
+            target_name,
+        } = msg;
+
+        if !self.can_moderate(&room, by) {
+            return Err(ModerationError::NotModerator);
+        }
+
+        let target = self.resolve_name(&target_name);
+        if target == Some(by) {
+            return Err(ModerationError::SelfTarget);
+        }
+        if let Some(target) = target {
+            self.check_outranks(&room, by, target)?;
+        }
+
+        self.banned
+            .entry(room.clone())
+            .or_default()
+            .insert(name_key(&target_name));
+
+        if let Some(target) = target.filter(|id| self.rooms[&room].sessions.contains(id)) {
+            let kicked = Control::Kicked {
+                to_room: self.default_room.clone(),
+            };
+            self.move_to_default_room(target, &room, kicked);
+        }
+        self.send_message(&room, &format!("{} was banned", target_name), 0);
+
+        Ok(())
+    }
+}
+
+/// Снять бан с имени в комнате
+impl Handler<Unban> for ChatServer {
+    type Result = Result<(), ModerationError>;
+
+    fn handle(&mut self, msg: Unban, _: &mut Context<Self>) -> Self::Result {
+        if !self.can_moderate(&msg.room, msg.by) {
+            return Err(ModerationError::NotModerator);
+        }
+
+        let removed = self
+            .banned
+            .get_mut(&msg.room)
+            .is_some_and(|names| names.remove(&name_key(&msg.target_name)));
+        if !removed {
+            return Err(ModerationError::NotBanned);
+        }
+
+        self.send_message(&msg.room, &format!("{} was unbanned", msg.target_name), 0);
+
+        Ok(())
+    }
+}
+
+/// Обработчик сообщения `SetSlowMode`.
+impl Handler<SetSlowMode> for ChatServer {
+    type Result = Result<(), ModerationError>;
+
+    fn handle(&mut self, msg: SetSlowMode, _: &mut Context<Self>) -> Self::Result {
+        if !self.can_moderate(&msg.room, msg.by) {
+            return Err(ModerationError::NotModerator);
+        }
+        if let Some(room) = self.rooms.get_mut(&msg.room) {
+            room.slow_mode = msg.interval;
+            room.last_post.clear();
+        }
+
+        let notice = match msg.interval {
+            Some(interval) => format!("slow mode is on: one message every {}s", interval.as_secs()),
+            None => "slow mode is off".to_owned(),
+        };
+        self.send_message(&msg.room, &notice, 0);
+
+        Ok(())
+    }
+}
+
+/// Обработчик сообщения `SetTopic`.
+impl Handler<SetTopic> for ChatServer {
+    type Result = Result<(), ModerationError>;
+
+    fn handle(&mut self, msg: SetTopic, _: &mut Context<Self>) -> Self::Result {
+        if !self.can_moderate(&msg.room, msg.by) {
+            return Err(ModerationError::NotModerator);
+        }
+        let notice = format!("topic changed: {}", msg.topic);
+        if let Some(room) = self.rooms.get_mut(&msg.room) {
+            room.topic = Some(msg.topic);
+        }
+        self.send_message(&msg.room, &notice, 0);
+
+        Ok(())
+    }
+}
+
+/// Обработчик сообщения `GetTopic`.
+impl Handler<GetTopic> for ChatServer {
+    type Result = Option<String>;
+
+    fn handle(&mut self, msg: GetTopic, _: &mut Context<Self>) -> Self::Result {
+        self.rooms.get(&msg.room)?.topic.clone()
+    }
+}
+
+/// Обработчик сообщения `ClearHistory`.
+///
+/// Сообщения до очистки больше не повторяются, `Resync` сообщает о пропуске
+impl Handler<ClearHistory> for ChatServer {
+    type Result = Result<(), ModerationError>;
+
+    fn handle(&mut self, msg: ClearHistory, _: &mut Context<Self>) -> Self::Result {
+        if !self.is_owner(&msg.room, msg.requester_id) {
+            return Err(ModerationError::NotOwner);
+        }
+        if let Some(room) = self.rooms.get_mut(&msg.room) {
+            room.history.clear();
+            room.evicted_id = room.seq;
+        }
+        if let Some(store) = &self.store {
+            store.do_send(ClearRoom {
+                room: msg.room.clone(),
+            });
+        }
+
+        let notice = format!("history cleared by {}", self.display_name(msg.requester_id));
+        self.send_message(&msg.room, &notice, 0);
+
+        Ok(())
+    }
+}
+
+/// Обработчик сообщения `Promote`.
+impl Handler<Promote> for ChatServer {
+    type Result = Result<(), ModerationError>;
+
+    fn handle(&mut self, msg: Promote, _: &mut Context<Self>) -> Self::Result {
+        if !self.is_owner(&msg.room, msg.by) {
+            return Err(ModerationError::NotOwner);
+        }
+        let target = self.find_member(&msg.room, &msg.target_name)?;
+        if target == msg.by {
+            return Err(ModerationError::SelfTarget);
+        }
+
+        let promoted = self
+            .rooms
+            .get_mut(&msg.room)
+            .is_some_and(|r| r.moderators.insert(target));
+        if !promoted {
+            return Err(ModerationError::AlreadyModerator);
+        }
+
+        let notice = format!("{} is now a moderator", msg.target_name);
+        self.send_message(&msg.room, &notice, 0);
+
+        Ok(())
+    }
+}
+
+/// Обработчик сообщения `Demote`.
+impl Handler<Demote> for ChatServer {
+    type Result = Result<(), ModerationError>;
+
+    fn handle(&mut self, msg: Demote, _: &mut Context<Self>) -> Self::Result {
+        if !self.is_owner(&msg.room, msg.by) {
+            return Err(ModerationError::NotOwner);
+        }
+        let target = self.find_member(&msg.room, &msg.target_name)?;
+
+        let demoted = self
+            .rooms
+            .get_mut(&msg.room)
+            .is_some_and(|r| r.moderators.remove(&target));
+        if !demoted {
+            return Err(ModerationError::NotPromoted);
+        }
+
+        let notice = format!("{} is no longer a moderator", msg.target_name);
+        self.send_message(&msg.room, &notice, 0);
+
+        Ok(())
+    }
+}
+
+/// Обработчик сообщения `SetRoomOption`.
+impl Handler<SetRoomOption> for ChatServer {
+    type Result = Result<(), NotOwner>;
+
+    fn handle(&mut self, msg: SetRoomOption, _: &mut Context<Self>) -> Self::Result {
+        let room = match self.rooms.get_mut(&msg.room) {
+            Some(r) if r.owner == Some(msg.by) => r,
+            _ => return Err(NotOwner),
+        };
+
+        match msg.option {
+            RoomOption::Announcements(mode) => room.announcements = mode,
+            RoomOption::Statuses(show) => room.hide_statuses = !show,
+        }
+
+        Ok(())
+    }
+}
+
+/// Обработчик сообщения `ListSessions`.
+impl Handler<ListSessions> for ChatServer {
+    type Result = MessageResult<ListSessions>;
+
+    fn handle(&mut self, _: ListSessions, _: &mut Context<Self>) -> Self::Result {
+        let sessions = self
+            .sessions
+            .iter()
+            .map(|(&id, session)| SessionInfo {
+                id,
+                name: session.name.clone(),
+                peer: session.peer,
+                user_agent: session.user_agent.clone(),
+                connected: session
+                    .connected
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                rooms: self
+                    .rooms
+                    .iter()
+                    .filter(|(_, room)| room.sessions.contains(&id))
+                    .map(|(name, _)| name.clone())
+                    .collect(),
+            })
+            .collect();
+        MessageResult(sessions)
+    }
+}
+
+/// Обработчик сообщения `Who`.
+impl Handler<Who> for ChatServer {
+    type Result = MessageResult<Who>;
+
+    fn handle(&mut self, msg: Who, _: &mut Context<Self>) -> Self::Result {
+        let room = match self.rooms.get(&msg.room) {
+            Some(room) => room,
+            None => return MessageResult(None),
+        };
+
+        let mut members = Vec::new();
+        let mut guests = 0;
+        for id in &room.sessions {
+            match self.sessions.get(id) {
+                Some(Session {
+                    name: Some(name),
+                    status,
+                    away,
+                    ..
+                }) => members.push(MemberInfo {
+                    name: name.clone(),
+                    status: status.clone().filter(|_| !room.hide_statuses),
+                    away: away.clone(),
+                    role: room.role(*id),
+                }),
+                _ => guests += 1,
+            }
+        }
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+
+        MessageResult(Some(WhoSnapshot {
+            seq: room.seq,
+            members,
+            guests,
+        }))
+    }
+}
+
+/// Обработчик сообщения `Touch`.
+impl Handler<Touch> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Touch, _: &mut Context<Self>) {
+        if let Some(session) = self.sessions.get_mut(&msg.id) {
+            session.last_seen = Instant::now();
+        }
+    }
+}
+
+/// Обработчик сообщения `Typing`.
+///
+/// Комната получает не больше одного уведомления за `TYPING_DEBOUNCE`, если
+/// за `TYPING_TIMEOUT` не пришло ни сообщения, ни нового `Typing`, комнате
+/// сообщается об окончании набора
+impl Handler<Typing> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Typing, ctx: &mut Context<Self>) {
+        let in_room = self
+            .rooms
+            .get(&msg.room)
+            .is_some_and(|room| room.sessions.contains(&msg.id));
+        if !in_room {
+            return;
+        }
+
+        let now = Instant::now();
+        let notify = match self.typing.get_mut(&msg.id) {
+            Some(state) if state.room == msg.room => {
+                state.last_event = now;
+                let notify = now.duration_since(state.notified) >= TYPING_DEBOUNCE;
+                if notify {
+                    state.notified = now;
+                }
+                notify
+            }
+            _ => {
+                self.typing.insert(
+                    msg.id,
+                    TypingState {
+                        room: msg.room.clone(),
+                        notified: now,
+                        last_event: now,
+                    },
+                );
+                true
+            }
+        };
+
+        if notify {
+            self.send_typing(msg.id, &msg.room, true);
+        }
+
+        let id = msg.id;
+        ctx.run_later(TYPING_TIMEOUT, move |act, _| {
+            let expired = act
+                .typing
+                .get(&id)
+                .is_some_and(|state| state.last_event == now);
+            if expired {
+                if let Some(state) = act.typing.remove(&id) {
+                    act.send_typing(id, &state.room, false);
+                }
+            }
+        });
+    }
+}
+
+impl ChatServer {
+    /// Уведомить остальных участников комнаты о наборе сообщения сессией `id`
+    fn send_typing(&self, id: usize, room: &str, typing: bool) {
+        let members = match self.rooms.get(room) {
+            Some(room) => &room.sessions,
+            None => return,
+        };
+        let name = self
+            .sessions
+            .get(&id)
+            .and_then(|s| s.name.clone())
+            .unwrap_or_else(|| "Someone".to_owned());
+
+        for member in members.iter().filter(|&&member| member != id) {
+            if let Some(session) = self.sessions.get(member) {
+                let _ = session.control.do_send(Control::Typing {
+                    room: room.to_owned(),
+                    name: name.clone(),
+                    typing,
+                });
+            }
+        }
+    }
+}
+
+/// Обработчик сообщения `GetStats`
+impl Handler<GetStats> for ChatServer {
+    type Result = MessageResult<GetStats>;
+
+    fn handle(&mut self, _: GetStats, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(ServerStats {
+            sessions: self.sessions.len(),
+            rooms: self.rooms.len(),
+            messages: self.messages_total,
+        })
+    }
+}
+
+/// Обработчик сообщения `RoomStats`
+impl Handler<RoomStats> for ChatServer {
+    type Result = MessageResult<RoomStats>;
+
+    fn handle(&mut self, msg: RoomStats, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.rooms.get(&msg.room).map(|room| RoomStatsSnapshot {
+            members: room.sessions.len(),
+            messages: room.messages,
+            created: room.created,
+            last_activity: room.last_activity,
+        }))
+    }
+}
+
+/// Лишить участника комнаты права голоса
+impl Handler<Mute> for ChatServer {
+    type Result = Result<(), ModerationError>;
+
+    fn handle(&mut self, msg: Mute, _: &mut Context<Self>) -> Self::Result {
+        if !self.can_moderate(&msg.room, msg.by) {
+            return Err(ModerationError::NotModerator);
+        }
+
+        let target = self.find_member(&msg.room, &msg.target_name)?;
+        if target == msg.by {
+            return Err(ModerationError::SelfTarget);
+        }
+        self.check_outranks(&msg.room, msg.by, target)?;
+
+        if let Some(room) = self.rooms.get_mut(&msg.room) {
+            if !room.muted.insert(target) {
+                return Err(ModerationError::AlreadyMuted);
+            }
+        }
+        self.send_message(&msg.room, &format!("{} was muted", msg.target_name), 0);
+
+        Ok(())
+    }
+}
+
+/// Вернуть участнику комнаты право голоса
+impl Handler<Unmute> for ChatServer {
+    type Result = Result<(), ModerationError>;
+
+    fn handle(&mut self, msg: Unmute, _: &mut Context<Self>) -> Self::Result {
+        if !self.can_moderate(&msg.room, msg.by) {
+            return Err(ModerationError::NotModerator);
+        }
+
+        let target = self.find_member(&msg.room, &msg.target_name)?;
+        if let Some(room) = self.rooms.get_mut(&msg.room) {
+            if !room.muted.remove(&target) {
+                return Err(ModerationError::NotMuted);
+            }
+        }
+        self.send_message(&msg.room, &format!("{} was unmuted", msg.target_name), 0);
+
+        Ok(())
+    }
+}
+
+/// Обработчик сообщения `EditMessage`.
+///
+/// Исправить можно только свое сообщение, пока оно в истории комнаты.
+/// Удаленное сообщение остается в истории пометкой `DELETED_TEXT`
+impl Handler<EditMessage> for ChatServer {
+    type Result = Result<(), EditError>;
+
+    fn handle(&mut self, msg: EditMessage, _: &mut Context<Self>) -> Self::Result {
+        self.chaos_latency();
+        let entry = self
+            .rooms
+            .get(&msg.room)
+            .and_then(|room| room.history.iter().find(|e| e.msg_id == msg.msg_id))
+            .ok_or(EditError::NotFound)?;
+        if entry.deleted {
+            return Err(EditError::Deleted);
+        }
+        if entry.author != Some(msg.id) {
+            return Err(EditError::NotAuthor);
+        }
+
+        let text: Arc<str> = match &msg.text {
+            Some(body) => {
+                if let Some(max) = self.max_message_len.filter(|&max| body.len() > max) {
+                    return Err(EditError::TooLong(max));
+                }
+                let filtered = self
+                    .filter_text(&msg.room, msg.id, body)
+                    .map_err(EditError::Rejected)?;
+                // префикс "имя: " или "* имя " остается прежним
+                let prefix_len =
+                    entry.text.len() - message_body(entry.from.as_deref(), &entry.text).len();
+                let body = filtered.as_deref().unwrap_or(body);
+                Arc::from(format!("{}{}", &entry.text[..prefix_len], body))
+            }
+            None => Arc::from(DELETED_TEXT),
+        };
+        let from = entry.from.clone();
+
+        let room = self.rooms.get_mut(&msg.room).ok_or(EditError::NotFound)?;
+        if let Some(entry) = room.history.iter_mut().find(|e| e.msg_id == msg.msg_id) {
+            entry.text = text.clone();
+            entry.deleted = msg.text.is_none();
+        }
+        let control = |room: String| match msg.text {
+            Some(_) => Control::Edited {
+                room,
+                msg_id: msg.msg_id,
+                from: from.clone(),
+                text: text.clone(),
+            },
+            None => Control::Deleted {
+                room,
+                msg_id: msg.msg_id,
+            },
+        };
+        for id in &room.sessions {
+            if let Some(session) = self.sessions.get(id) {
+                let _ = session.control.do_send(control(msg.room.clone()));
+            }
+        }
+
+        if let Some(store) = &self.store {
+            store.do_send(UpdateMessage {
+                room: msg.room.clone(),
+                msg_id: msg.msg_id,
+                text: text.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Обработчик сообщения `GetHistory`.
+impl Handler<GetHistory> for ChatServer {
+    type Result = MessageResult<GetHistory>;
+
+    fn handle(&mut self, msg: GetHistory, _: &mut Context<Self>) -> Self::Result {
+        let history = match self.rooms.get(&msg.room) {
+            Some(room) => {
+                let skip = room.history.len().saturating_sub(msg.limit);
+                room.history.iter().skip(skip).cloned().collect()
+            }
+            None => Vec::new(),
+        };
+
+        MessageResult(history)
+    }
+}
+
+/// Обработчик сообщения `Resync`.
+///
+/// Пропуск восстановим целиком, пока ни одно сообщение после `since_id` не
+/// вытеснено из истории. Без истории (`history_len` 0) восстановить нечего
+impl Handler<Resync> for ChatServer {
+    type Result = MessageResult<Resync>;
+
+    fn handle(&mut self, msg: Resync, _: &mut Context<Self>) -> Self::Result {
+        let replay = match self.rooms.get(&msg.room) {
+            Some(room) => ResyncReplay {
+                truncated: msg.since_id < room.evicted_id
+                    || (self.history_len == 0 && msg.since_id < room.seq),
+                entries: room
+                    .history
+                    .iter()
+                    .filter(|entry| entry.msg_id > msg.since_id)
+                    .cloned()
+                    .collect(),
+            },
+            None => ResyncReplay {
+                truncated: false,
+                entries: Vec::new(),
+            },
+        };
+
+        MessageResult(replay)
+    }
+}
+
+/// Обработчик сообщения `SetStatus`.
+///
+/// Объявление в комнате откладывается на `STATUS_DEBOUNCE`, частые смены статуса
+/// объявляются один раз с последним значением
+impl Handler<SetStatus> for ChatServer {
+    type Result = Result<(), StatusError>;
+
+    fn handle(&mut self, msg: SetStatus, ctx: &mut Context<Self>) -> Self::Result {
+        let status = msg.status.as_deref().map(sanitize_status).transpose()?;
+
+        let session = self
+            .sessions
+            .get_mut(&msg.id)
+            .ok_or(StatusError::Unavailable)?;
+        session.status = status;
+
+        if self.pending_status.insert(msg.id) {
+            let id = msg.id;
+            ctx.run_later(STATUS_DEBOUNCE, move |act, _| act.announce_status(id));
+        }
+
+        Ok(())
+    }
+}
+
+impl ChatServer {
+    /// Объявить текущий статус сессии в её комнатах
+    fn announce_status(&mut self, id: usize) {
+        self.pending_status.remove(&id);
+
+        let (name, status) = match self.sessions.get(&id) {
+            Some(Session {
+                name: Some(name),
+                status,
+                ..
+            }) => (name.clone(), status.clone()),
+            _ => return,
+        };
+        let notice = match status {
+            Some(status) => format!("{} is now: {}", name, status),
+            None => format!("{} cleared their status", name),
+        };
+
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.sessions.contains(&id) && !room.hide_statuses)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for room in rooms {
+            self.send_message(&room, &notice, 0);
+        }
+    }
+}
+
+/// Обработчик сообщения `SetAway`.
+///
+/// Об уходе и возвращении пользователя объявляется в его комнатах
+impl Handler<SetAway> for ChatServer {
+    type Result = Result<(), StatusError>;
+
+    fn handle(&mut self, msg: SetAway, _: &mut Context<Self>) -> Self::Result {
+        let SetAway { id, away } = msg;
+        let away = match away {
+            Some(Away {
+                reason: Some(reason),
+            }) => Some(Away {
+                reason: Some(sanitize_status(&reason)?),
+            }),
+            away => away,
+        };
+        if !self.sessions.contains_key(&id) {
+            return Err(StatusError::Unavailable);
+        }
+
+        self.set_away(id, away);
+        Ok(())
+    }
+}
+
+impl ChatServer {
+    /// Сменить отсутствие сессии и объявить об этом в ее комнатах
+    fn set_away(&mut self, id: usize, away: Option<Away>) {
+        let session = match self.sessions.get_mut(&id) {
+            Some(session) => session,
+            None => return,
+        };
+        let was_away = session.away.is_some();
+        // отсутствие видно упоминаниям из других частей сервера
+        if let Some(home) = self.directory.registry().homes.get_mut(&id) {
+            home.away = away.clone();
+        }
+        session.away = away;
+
+        let name = match &session.name {
+            Some(name) => name,
+            None => return,
+        };
+        let notice = match (&session.away, was_away) {
+            (
+                Some(Away {
+                    reason: Some(reason),
+                }),
+                _,
+            ) => format!("{} is away ({})", name, reason),
+            (Some(Away { reason: None }), _) => format!("{} is away", name),
+            (None, true) => format!("{} is back", name),
+            (None, false) => return,
+        };
+        let rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.sessions.contains(&id))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for room in rooms {
+            self.send_message(&room, &notice, 0);
+        }
+    }
+}
+
+/// Обработчик сообщения `Invite`.
+///
+/// Приглашенный получает личное сообщение, повторное приглашение продлевает срок
+impl Handler<Invite> for ChatServer {
+    type Result = Result<(), InviteError>;
+
+    fn handle(&mut self, msg: Invite, _: &mut Context<Self>) -> Self::Result {
+        let members = &self
+            .rooms
+            .get(&msg.room)
+            .ok_or(InviteError::NotInRoom)?
+            .sessions;
+        if !members.contains(&msg.by) {
+            return Err(InviteError::NotInRoom);
+        }
+
+        let target = self
+            .resolve_name(&msg.target_name)
+            .ok_or(InviteError::NoSuchUser)?;
+        if members.contains(&target) {
+            return Err(InviteError::AlreadyInRoom);
+        }
+
+        self.invites
+            .entry(target)
+            .or_default()
+            .insert(msg.room.clone(), Instant::now() + self.invite_ttl);
+
+        let by = self
+            .sessions
+            .get(&msg.by)
+            .and_then(|s| s.name.as_deref())
+            .unwrap_or("Someone");
+        let notice = format!(
+            "{} invited you to {}, use /join {} to enter",
+            by, msg.room, msg.room
+        );
+        self.send_private(target, &notice);
+
+        Ok(())
+    }
+}
+
+impl ChatServer {
+    /// Удалить просроченные приглашения
+    fn expire_invites(&mut self) {
+        let now = Instant::now();
+        for invites in self.invites.values_mut() {
+            invites.retain(|_, expires| *expires > now);
+        }
+        self.invites.retain(|_, invites| !invites.is_empty());
+    }
+
+    /// Удалить комнаты, пустые дольше `ROOM_GRACE`, и комнаты без активности
+    /// дольше `room_ttl` вместе с историей в памяти и в базе и модерацией. Короткий перерыв
+    /// комнату не удаляет. Комната по умолчанию не удаляется никогда
+    fn collect_rooms(&mut self) {
+        let now = Instant::now();
+        let ttl = self.room_ttl;
+        let expired: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(name, room)| {
+                let empty = room.sessions.is_empty()
+                    && room
+                        .emptied
+                        .is_some_and(|since| now.duration_since(since) >= ROOM_GRACE);
+                let inactive =
+                    ttl.is_some_and(|ttl| room.last_activity.elapsed().unwrap_or_default() >= ttl);
+                **name != self.default_room && (empty || inactive)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in expired {
+            let members: Vec<usize> = self.rooms[&name].sessions.iter().copied().collect();
+            if members.is_empty() {
+                info!("room {} is empty, removing it", name);
+            } else {
+                info!(
+                    "room {} is inactive for over {:?}, removing it",
+                    name,
+                    ttl.unwrap_or_default()
+                );
+            }
+            for id in members {
+                let closed = Control::RoomClosed {
+                    room: name.clone(),
+                    to_room: self.default_room.clone(),
+                };
+                self.move_to_default_room(id, &name, closed);
+            }
+            self.rooms.remove(&name);
+            self.banned.remove(&name);
+            self.capacities.remove(&name);
+            // иначе новая комната с тем же названием начнет номера с 1 поверх
+            // старых строк, а после перезапуска старая история вернется
+            if let Some(store) = &self.store {
+                store.do_send(ClearRoom { room: name });
+            }
+        }
+    }
+
+    /// Удалить сессии, чей актор остановился, не отправив `Disconnect`.
+    /// Иначе такая сессия числилась бы в комнатах, пока ей не придет рассылка
+    fn sweep_sessions(&mut self) {
+        let dead: Vec<usize> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| !session.addr.connected() || !session.control.connected())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in dead {
+            warn!("session {} is gone, removing it", id);
+            self.remove_session(id);
+        }
+    }
+
+    /// Удалить сессии, молчащие дольше `timeout`. Если сессия отключится
+    /// сама, её `Disconnect` ничего не найдет и ничего не сделает
+    fn reap_idle(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        let idle: Vec<usize> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| now.duration_since(session.last_seen) > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in idle {
+            warn!("session {} is idle for over {:?}, removing it", id, timeout);
+            self.send_private(id, "disconnected: idle for too long");
+            self.send_control(id, Control::Idle);
+            self.remove_session(id);
+        }
+    }
+
+    /// Забыть отключенные сессии, которые так и не вернулись
+    fn expire_suspended(&mut self) {
+        let now = Instant::now();
+        self.directory
+            .registry()
+            .suspended
+            .retain(|_, suspended| suspended.expires > now);
+    }
+}
+
+/// Обработчик сообщения `Broadcast`.
+///
+/// Каждая сессия получает одну копию, даже если она в нескольких комнатах
+impl Handler<Broadcast> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Broadcast, _: &mut Context<Self>) {
+        info!("Announcement to {} sessions", self.sessions.len());
+        self.announce_all(&msg.text);
+    }
+}
+
+/// Уведомить все сессии об остановке сервера
+impl Handler<Shutdown> for ChatServer {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: Shutdown, _: &mut Context<Self>) -> Self::Result {
+        info!("Shutting down, notifying {} sessions", self.sessions.len());
+
+        self.announce_all("Server shutting down");
+        for session in self.sessions.values() {
+            let _ = session.control.do_send(Control::Shutdown);
+        }
+
+        let (drained, rx) = oneshot::channel();
+        if self.sessions.is_empty() {
+            let _ = drained.send(());
+        } else {
+            self.drained = Some(drained);
+        }
+        let wait = async move {
+            let timeout = actix::clock::delay_for(msg.timeout);
+            if let Either::Right(_) = future::select(rx, timeout).await {
+                warn!("sessions did not close within {:?}", msg.timeout);
+            }
+        };
+        Box::pin(wait.into_actor(self))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+
+    /// Сессия-заглушка, запоминает тексты полученных сообщений. Перед текстом
+    /// сообщения не из вебсокета стоит пометка `[via ...]`
+    pub(crate) struct Probe {
+        messages: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Actor for Probe {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Probe {
+        type Result = ();
+
+        fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
+            let text = match msg.via {
+                Some(via) => format!("[via {}] {}", via, msg.text),
+                None => msg.text.to_string(),
+            };
+            self.messages.lock().unwrap().push(text);
+        }
+    }
+
+    /// Ошибки записываются как строки `!!! ...` текстового клиента, объявления
+    /// с пометкой `[announcement]`, требование закрыться из-за флуда как
+    /// `[flooded]`. Сбой `chaos` останавливает заглушку без `Disconnect`,
+    /// как обрыв соединения
+    impl Handler<Control> for Probe {
+        type Result = ();
+
+        #[cfg_attr(not(feature = "chaos"), allow(unused_variables))]
+        fn handle(&mut self, msg: Control, ctx: &mut Context<Self>) {
+            let text = match msg {
+                Control::Error { message, .. } => format!("!!! {}", message),
+                Control::Announcement { text } => format!("[announcement] {}", text),
+                Control::Flooded => "[flooded]".to_owned(),
+                #[cfg(feature = "chaos")]
+                Control::Dropped => return ctx.stop(),
+                _ => return,
+            };
+            self.messages.lock().unwrap().push(text);
+        }
+    }
+
+    /// Остановить заглушку, не отправляя `Disconnect`, как упавший актор
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    pub(crate) struct Crash;
+
+    impl Handler<Crash> for Probe {
+        type Result = ();
+
+        fn handle(&mut self, _: Crash, ctx: &mut Context<Self>) {
+            ctx.stop();
+        }
+    }
+
+    /// Подключенная к серверу заглушка
+    pub(crate) struct Client {
+        pub id: usize,
+        /// Токен возобновления
+        pub token: String,
+        pub probe: Addr<Probe>,
+        pub messages: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Client {
+        pub fn received(&self, text: &str) -> bool {
+            self.messages
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|m| m.contains(text))
+        }
+    }
+
+    /// Выполнить `f` над состоянием сервера внутри актора
+    pub(crate) struct Inspect<R>(pub Box<dyn FnOnce(&mut ChatServer) -> R + Send>);
+
+    impl<R: 'static> actix::Message for Inspect<R> {
+        type Result = R;
+    }
+
+    impl<R: 'static> Handler<Inspect<R>> for ChatServer {
+        type Result = MessageResult<Inspect<R>>;
+
+        fn handle(&mut self, msg: Inspect<R>, _: &mut Context<Self>) -> Self::Result {
+            MessageResult((msg.0)(self))
+        }
+    }
+
+    pub(crate) async fn inspect<R: Send + 'static>(
+        server: &Addr<ChatServer>,
+        f: impl FnOnce(&mut ChatServer) -> R + Send + 'static,
+    ) -> R {
+        server.send(Inspect(Box::new(f))).await.unwrap()
+    }
+
+    pub(crate) fn test_server() -> ChatServer {
+        ChatServer::new(
+            Arc::default(),
+            Announcements::Full,
+            50,
+            20,
+            Duration::from_secs(60),
+            10_000,
+            None,
+        )
+    }
+
+    pub(crate) async fn connect(server: &Addr<ChatServer>) -> Client {
+        connect_via(|msg| server.send(msg), None).await
+    }
+
+    /// Подключить заглушку через `send`, например через `Router`, с токеном `resume`
+    pub(crate) async fn connect_via<F>(
+        send: impl FnOnce(Connect) -> F,
+        resume: Option<String>,
+    ) -> Client
+    where
+        F: std::future::Future<Output = Result<Result<Connected, ServerFull>, MailboxError>>,
+    {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let probe = Probe {
+            messages: messages.clone(),
+        }
+        .start();
+        let connected = send(Connect {
+            addr: probe.clone().recipient(),
+            control: probe.clone().recipient(),
+            resume,
+            peer: None,
+            user_agent: None,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        Client {
+            id: connected.id,
+            token: connected.token,
+            probe,
+            messages,
+        }
+    }
+
+    /// Дать акторам обработать очереди
+    pub(crate) async fn settle() {
+        actix::clock::delay_for(Duration::from_millis(20)).await;
+    }
+
+    /// Считает байты, выделенные текущим потоком
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|n| n.set(n.get() + layout.size()));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    /// Байты и время, потраченные `f` в текущем потоке
+    fn measure(f: impl FnOnce()) -> (usize, Duration) {
+        let before = ALLOCATED.with(|n| n.get());
+        let started = Instant::now();
+        f();
+        (ALLOCATED.with(|n| n.get()) - before, started.elapsed())
+    }
+
+    /// Рассылка одного текста в комнату из `members` сессий: общим `Arc<str>`,
+    /// как `broadcast`, и отдельной копией на получателя, как было раньше.
+    /// `cargo test --release -- --ignored --nocapture bench_`
+    #[test]
+    #[ignore]
+    fn bench_broadcast_fan_out() {
+        System::new("test").block_on(async {
+            let text = "x".repeat(1024);
+            for &members in &[1, 1000] {
+                let server = test_server().start();
+                for _ in 0..members {
+                    connect(&server).await;
+                }
+                let payload = text.clone();
+                let ((shared, shared_time), (copied, copied_time)) =
+                    inspect(&server, move |s| {
+                        // среднее по нескольким рассылкам, одна короткая рассылка
+                        // тонет в погрешности часов
+                        let rounds = 10_000 / members;
+                        let per_round = |(bytes, time): (usize, Duration)| {
+                            (bytes / rounds, time / rounds as u32)
+                        };
+                        let shared = measure(|| {
+                            for _ in 0..rounds {
+                                s.broadcast(DEFAULT_ROOM, Arc::from(payload.as_str()), None, 0, None);
+                            }
+                        });
+                        let copied = measure(|| {
+                            for _ in 0..rounds {
+                                for id in &s.rooms[DEFAULT_ROOM].sessions {
+                                    let _ = s.sessions[id].addr.do_send(Message {
+                                        text: Arc::from(payload.as_str()),
+                                        msg_id: Some(0),
+                                        prev_id: Some(0),
+                                        ts: SystemTime::now(),
+                                        from: None,
+                                        replay: false,
+                                        mention: None,
+                                        via: None,
+                                    });
+                                }
+                            }
+                        });
+                        (per_round(shared), per_round(copied))
+                    })
+                    .await;
+                println!(
+                    "{} members: shared Arc<str> {} bytes in {:?}, copy per recipient {} bytes in {:?}",
+                    members, shared, shared_time, copied, copied_time
+                );
+                if members > 1 {
+                    // каждая копия стоит не меньше самого текста
+                    assert!(copied - shared >= (members - 1) * text.len());
+                }
+            }
+        });
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn server_is_consistent_after_a_chaos_run() {
+        use crate::chaos::Fault;
+
+        System::new("test").block_on(async {
+            let chaos = Arc::new(Chaos::default());
+            let server = test_server().with_chaos(chaos.clone()).start();
+            let mut clients = Vec::new();
+            for _ in 0..4 {
+                clients.push(connect(&server).await);
+            }
+
+            // проверка отключений идет раз в секунду: сбой успеет сработать
+            // ровно один раз и истечет до второй проверки
+            chaos
+                .arm(
+                    Fault::Disconnect { per_min: 60 },
+                    Duration::from_millis(1500),
+                )
+                .unwrap();
+            chaos
+                .arm(
+                    Fault::Latency(Duration::from_millis(5)),
+                    Duration::from_millis(1500),
+                )
+                .unwrap();
+            actix::clock::delay_for(Duration::from_millis(3200)).await;
+            assert_eq!(chaos.latency(), None);
+
+            let alive: Vec<&Client> = clients.iter().filter(|c| c.probe.connected()).collect();
+            assert_eq!(alive.len(), 3, "the fault must fire once and then expire");
+
+            // следующая рассылка убирает отключенную сессию
+            server.do_send(ClientMessage {
+                id: alive[0].id,
+                msg: "after chaos".to_owned(),
+                room: DEFAULT_ROOM.to_owned(),
+                correlation_id: None,
+            });
+            let (sessions, violations) =
+                inspect(&server, |s| (s.sessions.len(), s.fsck(false))).await;
+            assert_eq!(sessions, 3);
+            assert!(violations.is_empty(), "{:?}", violations);
+        });
+    }
+
+    #[test]
+    fn racing_sessions_cannot_claim_the_same_name() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let first = connect(&server).await;
+            let second = connect(&server).await;
+
+            // одно и то же имя в разном регистре и в форме совместимости
+            let claims = future::join(
+                server.send(SetName {
+                    id: first.id,
+                    name: "Alice".to_owned(),
+                }),
+                server.send(SetName {
+                    id: second.id,
+                    name: "ＡＬＩＣＥ".to_owned(),
+                }),
+            )
+            .await;
+            let won = [claims.0.unwrap(), claims.1.unwrap()]
+                .iter()
+                .filter(|result| matches!(result, SetNameResult::Ok))
+                .count();
+            assert_eq!(won, 1);
+
+            let violations = inspect(&server, |s| s.fsck(false)).await;
+            assert!(violations.is_empty(), "{:?}", violations);
+        });
+    }
+
+    #[test]
+    fn fsck_accepts_names_keyed_by_name_key() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let client = connect(&server).await;
+            let name = "Ｚoë".to_owned();
+            server
+                .send(SetName {
+                    id: client.id,
+                    name: name.clone(),
+                })
+                .await
+                .unwrap();
+
+            let (violations, repaired, key) = inspect(&server, move |s| {
+                let violations = s.fsck(false);
+                let repaired = s.fsck(true);
+                (violations, repaired, s.resolve_name(&name))
+            })
+            .await;
+            assert!(violations.is_empty(), "{:?}", violations);
+            assert!(repaired.is_empty(), "{:?}", repaired);
+            assert_eq!(key, Some(client.id));
+        });
+    }
+
+    #[test]
+    fn renames_free_the_old_name_and_disconnects_free_the_new_one() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = named(&server, "alice").await;
+            let bob = connect(&server).await;
+            let set_name = |id, name: &str| {
+                server.send(SetName {
+                    id,
+                    name: name.to_owned(),
+                })
+            };
+            let resolve = |names: &'static [&'static str]| {
+                inspect(&server, move |s| {
+                    names
+                        .iter()
+                        .map(|name| s.resolve_name(name))
+                        .collect::<Vec<_>>()
+                })
+            };
+
+            let renamed = set_name(alice.id, "Alicia").await.unwrap();
+            assert!(matches!(renamed, SetNameResult::Ok));
+            assert_eq!(
+                resolve(&["alice", "alicia", "ALICIA"]).await,
+                [None, Some(alice.id), Some(alice.id)]
+            );
+            assert!(bob.received("alice is now known as Alicia"));
+
+            // старое имя свободно, новое занято в любом регистре
+            let taken = set_name(bob.id, "aLiCiA").await.unwrap();
+            assert!(matches!(taken, SetNameResult::Taken));
+            let reused = set_name(bob.id, "Alice").await.unwrap();
+            assert!(matches!(reused, SetNameResult::Ok));
+            // смена только регистра остается за той же сессией
+            let recased = set_name(alice.id, "ALICIA").await.unwrap();
+            assert!(matches!(recased, SetNameResult::Ok));
+            assert_eq!(
+                resolve(&["alice", "alicia"]).await,
+                [Some(bob.id), Some(alice.id)]
+            );
+
+            server.do_send(Disconnect { id: alice.id });
+            assert_eq!(resolve(&["alicia"]).await, [None]);
+            let carol = named(&server, "alicia").await;
+            assert_eq!(resolve(&["Alicia"]).await, [Some(carol.id)]);
+            let violations = inspect(&server, |s| s.fsck(false)).await;
+            assert!(violations.is_empty(), "{:?}", violations);
+        });
+    }
+
+    #[test]
+    fn new_sessions_join_a_custom_default_room() {
+        System::new("test").block_on(async {
+            let server = test_server().with_default_room("Lobby".to_owned()).start();
+            let client = connect(&server).await;
+
+            let id = client.id;
+            let (in_lobby, has_main, violations) = inspect(&server, move |s| {
+                (
+                    s.rooms["Lobby"].sessions.contains(&id),
+                    s.rooms.contains_key(DEFAULT_ROOM),
+                    s.fsck(true),
+                )
+            })
+            .await;
+            assert!(in_lobby);
+            // repair не создает прежнюю комнату по умолчанию
+            assert!(!has_main);
+            assert!(violations.is_empty(), "{:?}", violations);
+            settle().await;
+            assert!(client.received("in Lobby"));
+
+            // сессия без комнаты возвращается в настроенную комнату
+            let (violations, in_lobby, has_main) = inspect(&server, move |s| {
+                s.rooms.get_mut("Lobby").unwrap().leave(id);
+                s.fsck(true);
+                s.collect_rooms();
+                (
+                    s.fsck(false),
+                    s.rooms["Lobby"].sessions.contains(&id),
+                    s.rooms.contains_key(DEFAULT_ROOM),
+                )
+            })
+            .await;
+            assert!(violations.is_empty(), "{:?}", violations);
+            assert!(in_lobby);
+            assert!(!has_main);
+        });
+    }
+
+    #[test]
+    fn fsck_orders_history_by_msg_id_not_by_time() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let violations = inspect(&server, |s| {
+                let entry = |msg_id, ts| HistoryEntry {
+                    msg_id,
+                    from: None,
+                    text: Arc::from("text"),
+                    ts,
+                    author: None,
+                    deleted: false,
+                };
+                let now = SystemTime::now();
+                let earlier = now - Duration::from_secs(5);
+                let room = s.rooms.get_mut(DEFAULT_ROOM).unwrap();
+                // часы могут повториться или отступить назад
+                room.history = vec![entry(1, now), entry(2, now), entry(3, earlier)].into();
+                let clean = s.fsck(false);
+
+                let room = s.rooms.get_mut(DEFAULT_ROOM).unwrap();
+                room.history = vec![entry(2, earlier), entry(2, now)].into();
+                (clean, s.fsck(false))
+            })
+            .await;
+            assert!(violations.0.is_empty(), "{:?}", violations.0);
+            assert_eq!(violations.1, vec!["room Main history is out of order"]);
+        });
+    }
+
+    #[test]
+    fn fsck_repair_enters_the_default_room_properly() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let client = connect(&server).await;
+            let id = client.id;
+            let (joined, emptied, violations) = inspect(&server, move |s| {
+                s.rooms.get_mut(DEFAULT_ROOM).unwrap().leave(id);
+                s.fsck(true);
+                let room = &s.rooms[DEFAULT_ROOM];
+                (room.joined.contains_key(&id), room.emptied, s.fsck(false))
+            })
+            .await;
+            assert!(joined);
+            assert_eq!(emptied, None);
+            assert!(violations.is_empty(), "{:?}", violations);
+        });
+    }
+
+    #[test]
+    fn removed_rooms_are_not_restored_from_the_store() {
+        let path = crate::store::tests::temp_db("gc");
+        let db = path.to_str().unwrap().to_owned();
+        System::new("test").block_on(async move {
+            let opened = db.clone();
+            let store = SyncArbiter::start(1, move || Store::open(&opened).unwrap());
+            let server = test_server().with_store(store.clone(), Vec::new()).start();
+            let client = connect(&server).await;
+            let join = |name: &str| Join {
+                id: client.id,
+                name: name.to_owned(),
+                password: None,
+                capacity: None,
+            };
+
+            server.send(join("r")).await.unwrap().unwrap();
+            server.do_send(ClientMessage {
+                id: client.id,
+                msg: "first life".to_owned(),
+                room: "r".to_owned(),
+                correlation_id: None,
+            });
+            server.send(join(DEFAULT_ROOM)).await.unwrap().unwrap();
+            let removed = inspect(&server, |s| {
+                s.rooms.get_mut("r").unwrap().emptied = Some(Instant::now() - ROOM_GRACE);
+                s.collect_rooms();
+                !s.rooms.contains_key("r")
+            })
+            .await;
+            assert!(removed);
+
+            // комната с тем же названием начинает номера заново
+            server.send(join("r")).await.unwrap().unwrap();
+            server.do_send(ClientMessage {
+                id: client.id,
+                msg: "second life".to_owned(),
+                room: "r".to_owned(),
+                correlation_id: None,
+            });
+            server.send(join(DEFAULT_ROOM)).await.unwrap().unwrap();
+            // очередь базы обрабатывается по порядку
+            store
+                .send(ClearRoom {
+                    room: "unused".to_owned(),
+                })
+                .await
+                .unwrap();
+
+            let rooms = Store::open(&db).unwrap().load(10).unwrap();
+            let texts: Vec<String> = rooms
+                .iter()
+                .filter(|room| room.name == "r")
+                .flat_map(|room| room.history.iter().map(|e| e.text.to_string()))
+                .collect();
+            assert_eq!(texts.len(), 1, "{:?}", texts);
+            assert!(texts[0].contains("second life"));
+        });
+    }
+
+    #[test]
+    fn rejected_joins_keep_the_invite() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let owner = connect(&server).await;
+            let guest = connect(&server).await;
+            for (client, name) in [(&owner, "owner"), (&guest, "guest")] {
+                server
+                    .send(SetName {
+                        id: client.id,
+                        name: name.to_owned(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            let join = |id, password: Option<&str>, capacity| Join {
+                id,
+                name: "private".to_owned(),
+                password: password.map(str::to_owned),
+                capacity,
+            };
+
+            server
+                .send(join(owner.id, Some("secret"), Some(1)))
+                .await
+                .unwrap()
+                .unwrap();
+            server
+                .send(Invite {
+                    by: owner.id,
+                    room: "private".to_owned(),
+                    target_name: "guest".to_owned(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+
+            let full = server.send(join(guest.id, None, None)).await.unwrap();
+            assert!(matches!(full, Err(JoinError::Full)));
+
+            // после ухода владельца место освобождается, приглашение все ещё действует
+            server
+                .send(Join {
+                    id: owner.id,
+                    name: DEFAULT_ROOM.to_owned(),
+                    password: None,
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            let joined = server.send(join(guest.id, None, None)).await.unwrap();
+            assert!(joined.is_ok(), "{:?}", joined);
+            let spent = inspect(&server, move |s| {
+                s.invites
+                    .get(&guest.id)
+                    .is_none_or(|invites| !invites.contains_key("private"))
+            })
+            .await;
+            assert!(spent);
+        });
+    }
+
+    #[test]
+    fn rest_messages_are_sent_as_a_member_session() {
+        System::new("test").block_on(async {
+            let server = test_server()
+                .with_flood_limit(FloodLimit {
+                    burst: 2,
+                    window: Duration::from_secs(60),
+                    max_warnings: 0,
+                })
+                .start();
+            let alice = connect(&server).await;
+            let bob = connect(&server).await;
+            let carol = connect(&server).await;
+            server
+                .send(SetName {
+                    id: alice.id,
+                    name: "alice".to_owned(),
+                })
+                .await
+                .unwrap();
+            server
+                .send(Join {
+                    id: carol.id,
+                    name: "other".to_owned(),
+                    password: None,
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            let rest = |token: &str, room: &str| RestMessage {
+                token: token.to_owned(),
+                room: room.to_owned(),
+                text: "from the bus".to_owned(),
+                require_name: false,
+            };
+
+            let unknown = server.send(rest("0", DEFAULT_ROOM)).await.unwrap();
+            assert!(matches!(unknown, Err(RestError::Unauthorized)));
+            let outsider = server.send(rest(&alice.token, "other")).await.unwrap();
+            assert!(matches!(outsider, Err(RestError::NotMember)));
+
+            let msg_id = server
+                .send(rest(&alice.token, DEFAULT_ROOM))
+                .await
+                .unwrap()
+                .unwrap();
+            settle().await;
+            // вебсокет автора получает сообщение как остальные участники
+            assert!(alice.received("[via rest] alice: from the bus"));
+            assert!(bob.received("[via rest] alice: from the bus"));
+            assert!(!carol.received("from the bus"));
+            let history = server
+                .send(GetHistory {
+                    room: DEFAULT_ROOM.to_owned(),
+                    limit: 1,
+                })
+                .await
+                .unwrap();
+            assert_eq!(history[0].msg_id, msg_id);
+            assert_eq!(history[0].author, Some(alice.id));
+            assert_eq!(history[0].from.as_deref(), Some("alice"));
+
+            // вебсокет и HTTP расходуют один предел частоты
+            server
+                .send(ClientMessage {
+                    id: alice.id,
+                    msg: "alice: from the socket".to_owned(),
+                    room: DEFAULT_ROOM.to_owned(),
+                    correlation_id: None,
+                })
+                .await
+                .unwrap();
+            let limited = server.send(rest(&alice.token, DEFAULT_ROOM)).await.unwrap();
+            assert!(matches!(
+                limited,
+                Err(RestError::Refused(Refusal::RateLimited))
+            ));
+        });
+    }
+
+    #[test]
+    fn resumed_sessions_keep_status_and_away() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = connect(&server).await;
+            server
+                .send(SetName {
+                    id: alice.id,
+                    name: "alice".to_owned(),
+                })
+                .await
+                .unwrap();
+            server
+                .send(SetStatus {
+                    id: alice.id,
+                    status: Some("on call".to_owned()),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            server
+                .send(SetAway {
+                    id: alice.id,
+                    away: Some(Away {
+                        reason: Some("lunch".to_owned()),
+                    }),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            server.send(Disconnect { id: alice.id }).await.unwrap();
+
+            let probe = Probe {
+                messages: Arc::default(),
+            }
+            .start();
+            let resumed = server
+                .send(Connect {
+                    addr: probe.clone().recipient(),
+                    control: probe.recipient(),
+                    resume: Some(alice.token.clone()),
+                    peer: None,
+                    user_agent: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(resumed.id, alice.id);
+            assert_eq!(
+                resumed.away.and_then(|away| away.reason).as_deref(),
+                Some("lunch")
+            );
+            let (status, away) = inspect(&server, move |s| {
+                let session = &s.sessions[&alice.id];
+                (session.status.clone(), session.away.is_some())
+            })
+            .await;
+            assert_eq!(status.as_deref(), Some("on call"));
+            assert!(away);
+        });
+    }
+
+    #[test]
+    fn user_names_are_validated() {
+        assert_eq!(normalize_user_name("  alice "), Some("alice"));
+        assert_eq!(normalize_user_name("Ｚoë"), Some("Ｚoë"));
+        assert!(normalize_user_name(&"é".repeat(MAX_USER_NAME_LEN)).is_some());
+        for invalid in [
+            "",
+            "   ",
+            "al\nice",
+            "bell\u{7}",
+            &"a".repeat(MAX_USER_NAME_LEN + 1),
+        ] {
+            assert_eq!(normalize_user_name(invalid), None, "{:?}", invalid);
+        }
+
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let client = connect(&server).await;
+            let set_name = |name: &str| SetName {
+                id: client.id,
+                name: name.to_owned(),
+            };
+            let rejected = server.send(set_name("evil\r\nadmin")).await.unwrap();
+            assert!(matches!(rejected, SetNameResult::Invalid));
+            let accepted = server.send(set_name(" alice ")).await.unwrap();
+            assert!(matches!(accepted, SetNameResult::Ok));
+            let name = inspect(&server, move |s| s.sessions[&client.id].name.clone()).await;
+            assert_eq!(name.as_deref(), Some("alice"));
+        });
+    }
+
+    #[test]
+    fn secrets_match_only_identical_strings() {
+        assert!(secrets_match("secret", "secret"));
+        assert!(!secrets_match("secret", "secreT"));
+        assert!(!secrets_match("secret", "secret "));
+        assert!(!secrets_match("secret", ""));
+        assert!(secrets_match("", ""));
+    }
+
+    #[test]
+    fn broadcast_purges_sessions_with_a_dropped_recipient() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alive = connect(&server).await;
+            let dead = connect(&server).await;
+
+            // актор умирает, не успев отправить `Disconnect`
+            dead.probe.send(Crash).await.unwrap();
+            drop(dead.probe);
+            settle().await;
+
+            server.do_send(ClientMessage {
+                id: alive.id,
+                msg: "hello".to_owned(),
+                room: DEFAULT_ROOM.to_owned(),
+                correlation_id: None,
+            });
+            let dead_id = dead.id;
+            let (in_sessions, in_room, active) = inspect(&server, move |s| {
+                (
+                    s.sessions.contains_key(&dead_id),
+                    s.rooms[DEFAULT_ROOM].sessions.contains(&dead_id),
+                    s.counters.active_sessions.load(Ordering::SeqCst),
+                )
+            })
+            .await;
+            assert!(!in_sessions);
+            assert!(!in_room);
+            assert_eq!(active, 1);
+
+            settle().await;
+            assert!(alive.received(&format!("guest-{:04} disconnected", dead_id % 10_000)));
+        });
+    }
+
+    #[test]
+    fn member_counts_follow_the_announcement_mode() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let owner = connect(&server).await;
+            let guest = connect(&server).await;
+            let join = |id, room: &str| Join {
+                id,
+                name: room.to_owned(),
+                password: None,
+                capacity: None,
+            };
+            server.send(join(owner.id, "dev")).await.unwrap().unwrap();
+            server.send(join(guest.id, "dev")).await.unwrap().unwrap();
+            settle().await;
+            assert!(owner.received("joined (2 users online)"));
+            assert!(owner.received("room dev now has 2 members"));
+
+            let set_mode = |mode| SetRoomOption {
+                by: owner.id,
+                room: "dev".to_owned(),
+                option: RoomOption::Announcements(mode),
+            };
+            server
+                .send(set_mode(Announcements::CountsOnly))
+                .await
+                .unwrap()
+                .unwrap();
+            owner.messages.lock().unwrap().clear();
+            server
+                .send(join(guest.id, DEFAULT_ROOM))
+                .await
+                .unwrap()
+                .unwrap();
+            settle().await;
+            assert!(!owner.received("left the room"));
+            assert_eq!(
+                *owner.messages.lock().unwrap(),
+                ["room dev now has 1 member"]
+            );
+
+            // молчащая комната не получает и количества участников
+            server
+                .send(set_mode(Announcements::Silent))
+                .await
+                .unwrap()
+                .unwrap();
+            owner.messages.lock().unwrap().clear();
+            server.send(join(guest.id, "dev")).await.unwrap().unwrap();
+            settle().await;
+            assert!(owner.messages.lock().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn history_keeps_the_newest_messages_up_to_its_capacity() {
+        System::new("test").block_on(async {
+            let server = ChatServer::new(
+                Arc::default(),
+                Announcements::Full,
+                3,
+                3,
+                Duration::from_secs(60),
+                10_000,
+                None,
+            )
+            .start();
+            let alice = connect(&server).await;
+            let mut ids = Vec::new();
+            for n in 0..6 {
+                server.do_send(ClientMessage {
+                    id: alice.id,
+                    msg: format!("m{}", n),
+                    room: DEFAULT_ROOM.to_owned(),
+                    correlation_id: None,
+                });
+                let (len, last) = inspect(&server, |s| {
+                    let history = &s.rooms[DEFAULT_ROOM].history;
+                    (history.len(), history.back().map(|e| e.msg_id))
+                })
+                .await;
+                assert!(len <= 3);
+                ids.push(last.unwrap());
+            }
+
+            let (history, evicted, allocated) = inspect(&server, |s| {
+                let room = &s.rooms[DEFAULT_ROOM];
+                let history: Vec<(u64, String, Option<usize>)> = room
+                    .history
+                    .iter()
+                    .map(|e| (e.msg_id, e.text.to_string(), e.author))
+                    .collect();
+                (history, room.evicted_id, room.history.capacity())
+            })
+            .await;
+            // вытесняются самые старые, порядок сохраняется
+            let expected: Vec<(u64, String, Option<usize>)> = (3..6)
+                .map(|n| (ids[n], format!("m{}", n), Some(alice.id)))
+                .collect();
+            assert_eq!(history, expected);
+            assert_eq!(evicted, ids[2]);
+            // буфер не растет сверх емкости с запасом на одну вставку
+            assert!(allocated < 8, "{}", allocated);
+        });
+    }
+
+    #[test]
+    fn full_rooms_refuse_the_next_join_and_keep_the_session_in_place() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let owner = connect(&server).await;
+            let member = connect(&server).await;
+            let late = connect(&server).await;
+            let join = |id, room: &str, capacity| Join {
+                id,
+                name: room.to_owned(),
+                password: None,
+                capacity,
+            };
+            server
+                .send(join(owner.id, "small", Some(2)))
+                .await
+                .unwrap()
+                .unwrap();
+            server
+                .send(join(member.id, "small", None))
+                .await
+                .unwrap()
+                .unwrap();
+
+            let full = server.send(join(late.id, "small", None)).await.unwrap();
+            assert!(matches!(full, Err(JoinError::Full)));
+            let (small, in_main) = inspect(&server, move |s| {
+                let mut small: Vec<usize> = s.rooms["small"].sessions.iter().copied().collect();
+                small.sort_unstable();
+                (small, s.rooms[DEFAULT_ROOM].sessions.contains(&late.id))
+            })
+            .await;
+            let mut expected = vec![owner.id, member.id];
+            expected.sort_unstable();
+            assert_eq!(small, expected);
+            assert!(in_main);
+        });
+    }
+
+    #[test]
+    fn refused_room_creation_leaves_no_trace() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = connect(&server).await;
+            let join = |room: String, capacity| Join {
+                id: alice.id,
+                name: room,
+                password: None,
+                capacity,
+            };
+            // отказы не расходуют предел создания комнат
+            for n in 0..ROOM_CREATION_LIMIT + 1 {
+                let refused = server
+                    .send(join(format!("empty-{}", n), Some(0)))
+                    .await
+                    .unwrap();
+                assert!(matches!(refused, Err(JoinError::Full)));
+            }
+            let leaked = inspect(&server, |s| s.capacities.len() + s.rooms.len()).await;
+            assert_eq!(leaked, 1);
+
+            for n in 0..ROOM_CREATION_LIMIT {
+                server
+                    .send(join(format!("room-{}", n), None))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            }
+            let limited = server
+                .send(join("one-too-many".to_owned(), None))
+                .await
+                .unwrap();
+            assert!(matches!(limited, Err(JoinError::TooManyRooms)));
+        });
+    }
+
+    /// Названия комнат из `ListRooms`
+    async fn room_names(server: &Addr<ChatServer>) -> Vec<String> {
+        let page = server
+            .send(ListRooms {
+                filter: None,
+                offset: 0,
+                limit: 100,
+                sort: RoomSort::Name,
+            })
+            .await
+            .unwrap();
+        page.rooms.into_iter().map(|room| room.name).collect()
+    }
+
+    /// Сдвинуть начало пустоты комнаты `room` на `ROOM_GRACE` назад и собрать
+    /// пустые комнаты, как будто пауза прошла
+    async fn collect_after_grace(server: &Addr<ChatServer>, room: &'static str) {
+        inspect(server, move |s| {
+            if let Some(since) = s.rooms.get_mut(room).and_then(|r| r.emptied.as_mut()) {
+                *since -= ROOM_GRACE;
+            }
+            s.collect_rooms();
+        })
+        .await
+    }
+
+    #[test]
+    fn empty_rooms_survive_the_grace_period_and_are_removed_after_it() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = connect(&server).await;
+            let join = |name: &str| Join {
+                id: alice.id,
+                name: name.to_owned(),
+                password: None,
+                capacity: None,
+            };
+            server.send(join("dev")).await.unwrap().unwrap();
+            server
+                .send(SetTopic {
+                    by: alice.id,
+                    room: "dev".to_owned(),
+                    topic: "release day".to_owned(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            server.send(join(DEFAULT_ROOM)).await.unwrap().unwrap();
+
+            // в пределах паузы комната остается вместе с темой
+            inspect(&server, |s| s.collect_rooms()).await;
+            assert_eq!(room_names(&server).await, [DEFAULT_ROOM, "dev"]);
+            alice.messages.lock().unwrap().clear();
+            server.send(join("dev")).await.unwrap().unwrap();
+            settle().await;
+            assert!(alice.received("topic: release day"));
+
+            // после паузы пустая комната удаляется, а пустая комната по
+            // умолчанию остается
+            server.send(join(DEFAULT_ROOM)).await.unwrap().unwrap();
+            collect_after_grace(&server, "dev").await;
+            assert_eq!(room_names(&server).await, [DEFAULT_ROOM]);
+            server.send(join("dev")).await.unwrap().unwrap();
+            collect_after_grace(&server, DEFAULT_ROOM).await;
+            assert_eq!(room_names(&server).await, [DEFAULT_ROOM, "dev"]);
+            let topic = inspect(&server, |s| s.rooms["dev"].topic.clone()).await;
+            assert_eq!(topic, None);
+        });
+    }
+
+    #[test]
+    fn restored_rooms_nobody_joins_are_collected() {
+        let path = crate::store::tests::temp_db("restored-gc");
+        let db = path.to_str().unwrap().to_owned();
+        System::new("test").block_on(async move {
+            let store = SyncArbiter::start(1, move || Store::open(&db).unwrap());
+            let stored = StoredRoom {
+                name: "old".to_owned(),
+                history: vec![HistoryEntry {
+                    msg_id: 1,
+                    from: None,
+                    text: Arc::from("from the last run"),
+                    ts: SystemTime::now(),
+                    author: None,
+                    deleted: false,
+                }],
+            };
+            let server = test_server().with_store(store, vec![stored]).start();
+            assert_eq!(room_names(&server).await, [DEFAULT_ROOM, "old"]);
+            collect_after_grace(&server, "old").await;
+            assert_eq!(room_names(&server).await, [DEFAULT_ROOM]);
+        });
+    }
+
+    /// Сколько полученных сообщений содержат `text`
+    fn count(client: &Client, text: &str) -> usize {
+        client
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.contains(text))
+            .count()
+    }
+
+    /// Подключить заглушку с именем `name`
+    async fn named(server: &Addr<ChatServer>, name: &str) -> Client {
+        let client = connect(server).await;
+        server
+            .send(SetName {
+                id: client.id,
+                name: name.to_owned(),
+            })
+            .await
+            .unwrap();
+        client
+    }
+
+    #[test]
+    fn mentions_reach_every_named_user_once_in_any_room() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = named(&server, "alice").await;
+            let bob = named(&server, "bob").await;
+            let carol = named(&server, "carol").await;
+            server
+                .send(Join {
+                    id: bob.id,
+                    name: "elsewhere".to_owned(),
+                    password: None,
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            server
+                .send(SetAway {
+                    id: bob.id,
+                    away: Some(Away {
+                        reason: Some("lunch".to_owned()),
+                    }),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+
+            let text = "hi @bob, @Carol and @bob again, @nobody @alice";
+            server.do_send(ClientMessage {
+                id: alice.id,
+                msg: text.to_owned(),
+                room: DEFAULT_ROOM.to_owned(),
+                correlation_id: None,
+            });
+            settle().await;
+
+            // bob в другой комнате получает только свою копию, carol ещё и рассылку
+            assert_eq!(count(&bob, text), 1);
+            assert_eq!(count(&carol, text), 2);
+            // себя не упоминают, неизвестные имена пропускаются
+            assert_eq!(count(&alice, text), 0);
+            assert_eq!(count(&alice, "bob is away: lunch"), 1);
+            assert_eq!(count(&alice, "is away"), 1);
+        });
+    }
+
+    #[test]
+    fn each_announcement_mode_sends_its_own_notices() {
+        System::new("test").block_on(async {
+            for (mode, expected) in [
+                (
+                    Announcements::Full,
+                    &["guest joined (2 users online)", "room r now has 2 members"][..],
+                ),
+                (Announcements::CountsOnly, &["room r now has 2 members"][..]),
+                (Announcements::Silent, &[][..]),
+            ] {
+                let server = test_server().start();
+                let owner = connect(&server).await;
+                let guest = named(&server, "guest").await;
+                let join = |id, room: &str| Join {
+                    id,
+                    name: room.to_owned(),
+                    password: None,
+                    capacity: None,
+                };
+                server.send(join(owner.id, "r")).await.unwrap().unwrap();
+                server
+                    .send(SetRoomOption {
+                        by: owner.id,
+                        room: "r".to_owned(),
+                        option: RoomOption::Announcements(mode),
+                    })
+                    .await
+                    .unwrap()
+                    .unwrap();
+                owner.messages.lock().unwrap().clear();
+                server.send(join(guest.id, "r")).await.unwrap().unwrap();
+                settle().await;
+                let joined: Vec<String> = owner.messages.lock().unwrap().drain(..).collect();
+                assert_eq!(joined, expected, "{:?}", mode);
+
+                server.do_send(Disconnect { id: guest.id });
+                settle().await;
+                let left: Vec<String> = owner.messages.lock().unwrap().drain(..).collect();
+                let expected: Vec<&str> = match mode {
+                    Announcements::Full => vec![
+                        "guest disconnected (1 user online)",
+                        "room r now has 1 member",
+                    ],
+                    Announcements::CountsOnly => vec!["room r now has 1 member"],
+                    Announcements::Silent => vec![],
+                };
+                assert_eq!(left, expected, "{:?}", mode);
+            }
+        });
+    }
+
+    #[test]
+    fn the_default_room_keeps_full_announcements_whatever_the_default() {
+        System::new("test").block_on(async {
+            let server = ChatServer::new(
+                Arc::default(),
+                Announcements::Silent,
+                50,
+                20,
+                Duration::from_secs(60),
+                10_000,
+                None,
+            )
+            .start();
+            let first = connect(&server).await;
+            let second = connect(&server).await;
+            settle().await;
+            // прежнее поведение комнаты по умолчанию, общий счетчик
+            // посетителей виден только в приветствии
+            assert!(first.received("joined (2 users online)"));
+            assert!(!first.received("Total visitors 2"));
+            assert!(second.received("Welcome! Total visitors 2"));
+
+            server
+                .send(Join {
+                    id: first.id,
+                    name: "new".to_owned(),
+                    password: None,
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            let modes = inspect(&server, |s| {
+                (
+                    s.rooms[DEFAULT_ROOM].announcements,
+                    s.rooms["new"].announcements,
+                )
+            })
+            .await;
+            assert_eq!(modes, (Announcements::Full, Announcements::Silent));
+        });
+    }
+
+    /// Комнаты из `ListRooms` с числом участников
+    async fn room_counts(server: &Addr<ChatServer>) -> Vec<(String, usize)> {
+        let page = server
+            .send(ListRooms {
+                filter: None,
+                offset: 0,
+                limit: 100,
+                sort: RoomSort::Name,
+            })
+            .await
+            .unwrap();
+        page.rooms
+            .into_iter()
+            .map(|room| (room.name, room.members))
+            .collect()
+    }
+
+    #[test]
+    fn room_counts_follow_joins_and_disconnects() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = connect(&server).await;
+            let bob = connect(&server).await;
+            let counts = |list: &[(&str, usize)]| -> Vec<(String, usize)> {
+                list.iter().map(|&(name, n)| (name.to_owned(), n)).collect()
+            };
+            assert_eq!(room_counts(&server).await, counts(&[(DEFAULT_ROOM, 2)]));
+
+            server
+                .send(Join {
+                    id: alice.id,
+                    name: "dev".to_owned(),
+                    password: None,
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                room_counts(&server).await,
+                counts(&[(DEFAULT_ROOM, 1), ("dev", 1)])
+            );
+
+            // пустая комната остается в списке с нулем участников
+            server.do_send(Disconnect { id: alice.id });
+            assert_eq!(
+                room_counts(&server).await,
+                counts(&[(DEFAULT_ROOM, 1), ("dev", 0)])
+            );
+            server.do_send(Disconnect { id: bob.id });
+            assert_eq!(
+                room_counts(&server).await,
+                counts(&[(DEFAULT_ROOM, 0), ("dev", 0)])
+            );
+        });
+    }
+
+    #[test]
+    fn room_counts_match_a_model_over_random_joins_kicks_and_disconnects() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        const ROOMS: [&str; 4] = [DEFAULT_ROOM, "a", "b", "c"];
+
+        System::new("test").block_on(async {
+            let mut kicks = 0;
+            for seed in 0..20 {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let server = test_server().start();
+                // модель: комната каждой подключенной сессии, после `/kick`
+                // сессия не состоит ни в одной комнате
+                let mut model: HashMap<usize, Option<&str>> = HashMap::new();
+                let mut names: HashMap<usize, String> = HashMap::new();
+                let mut next_name = 0;
+
+                for step in 0..40 {
+                    let ids: Vec<usize> = model.keys().copied().collect();
+                    let pick = |rng: &mut StdRng| ids[rng.gen_range(0..ids.len())];
+                    // без двух сессий только подключаются
+                    let action = match ids.len() {
+                        0 | 1 => 3,
+                        _ => rng.gen_range(0..4),
+                    };
+                    match action {
+                        0 => {
+                            let id = pick(&mut rng);
+                            let room = ROOMS[rng.gen_range(0..ROOMS.len())];
+                            let joined = server
+                                .send(Join {
+                                    id,
+                                    name: room.to_owned(),
+                                    password: None,
+                                    capacity: None,
+                                })
+                                .await
+                                .unwrap();
+                            if joined.is_ok() {
+                                model.insert(id, Some(room));
+                            }
+                        }
+                        1 => {
+                            // выгоняет владелец случайной комнаты кого-то из ее участников
+                            let room = ROOMS[rng.gen_range(1..ROOMS.len())];
+                            let owner = inspect(&server, move |s| s.rooms.get(room)?.owner).await;
+                            let members: Vec<usize> = model
+                                .iter()
+                                .filter(|&(id, r)| *r == Some(room) && Some(*id) != owner)
+                                .map(|(id, _)| *id)
+                                .collect();
+                            let (by, target) = match (owner, members.is_empty()) {
+                                (Some(owner), false) => {
+                                    (owner, members[rng.gen_range(0..members.len())])
+                                }
+                                _ => continue,
+                            };
+                            let kicked = server
+                                .send(Kick {
+                                    by,
+                                    room: room.to_owned(),
+                                    target_name: names[&target].clone(),
+                                })
+                                .await
+                                .unwrap();
+                            if kicked.is_ok() {
+                                model.insert(target, None);
+                                kicks += 1;
+                            }
+                        }
+                        2 => {
+                            let id = pick(&mut rng);
+                            server.do_send(Disconnect { id });
+                            model.remove(&id);
+                        }
+                        _ => {
+                            let name = format!("u{}", next_name);
+                            next_name += 1;
+                            let client = named(&server, &name).await;
+                            model.insert(client.id, Some(DEFAULT_ROOM));
+                            names.insert(client.id, name);
+                        }
+                    }
+
+                    let mut expected: Vec<(String, usize)> = ROOMS
+                        .iter()
+                        .map(|&room| {
+                            let n = model.values().filter(|r| **r == Some(room)).count();
+                            (room.to_owned(), n)
+                        })
+                        .filter(|&(ref room, n)| n > 0 || room == DEFAULT_ROOM)
+                        .collect();
+                    expected.sort();
+                    let mut actual = room_counts(&server).await;
+                    actual.retain(|(room, n)| *n > 0 || room == DEFAULT_ROOM);
+                    actual.sort();
+                    assert_eq!(actual, expected, "seed {} step {}", seed, step);
+                }
+            }
+            // владельцы комнат выгоняют хотя бы иногда
+            assert!(kicks > 0);
+        });
+    }
+
+    /// Забрать полученные клиентом сообщения
+    fn drain(client: &Client) -> Vec<String> {
+        client.messages.lock().unwrap().drain(..).collect()
+    }
+
+    #[test]
+    fn join_switch_and_disconnect_notices_reach_exactly_the_right_sessions() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = connect(&server).await;
+            let bob = connect(&server).await;
+            let carol = connect(&server).await;
+            server
+                .send(Join {
+                    id: carol.id,
+                    name: "other".to_owned(),
+                    password: None,
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            settle().await;
+            for client in [&alice, &bob, &carol] {
+                drain(client);
+            }
+
+            // подключение видят только в комнате по умолчанию, кроме самого гостя
+            let dave = connect(&server).await;
+            settle().await;
+            let guest = format!("guest-{:04}", dave.id % 10_000);
+            let joined = vec![
+                format!("{} joined (3 users online)", guest),
+                "room Main now has 3 members".to_owned(),
+            ];
+            assert_eq!(drain(&alice), joined);
+            assert_eq!(drain(&bob), joined);
+            assert!(drain(&carol).is_empty());
+            assert!(!drain(&dave).iter().any(|m| m.contains("joined")));
+
+            server
+                .send(SetName {
+                    id: dave.id,
+                    name: "dave".to_owned(),
+                })
+                .await
+                .unwrap();
+            server
+                .send(Join {
+                    id: dave.id,
+                    name: "other".to_owned(),
+                    password: None,
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            settle().await;
+            let left = vec![
+                "dave left the room (2 users online)".to_owned(),
+                "room Main now has 2 members".to_owned(),
+            ];
+            assert_eq!(drain(&alice), left);
+            assert_eq!(drain(&bob), left);
+            assert_eq!(
+                drain(&carol),
+                [
+                    "dave joined (2 users online)",
+                    "room other now has 2 members"
+                ]
+            );
+            assert!(!drain(&dave).iter().any(|m| m.contains("dave")));
+
+            server.do_send(Disconnect { id: dave.id });
+            settle().await;
+            assert!(drain(&alice).is_empty());
+            assert!(drain(&bob).is_empty());
+            assert_eq!(
+                drain(&carol),
+                [
+                    "dave disconnected (1 user online)",
+                    "room other now has 1 member"
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn roles_gate_moderation_and_ownership_passes_to_the_longest_present() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = named(&server, "alice").await;
+            let bob = named(&server, "bob").await;
+            let carol = named(&server, "carol").await;
+            for client in [&alice, &bob, &carol] {
+                server
+                    .send(Join {
+                        id: client.id,
+                        name: "club".to_owned(),
+                        password: None,
+                        capacity: None,
+                    })
+                    .await
+                    .unwrap()
+                    .unwrap();
+            }
+            let target = |by: &Client, name: &str| (by.id, "club".to_owned(), name.to_owned());
+            let kick = |(by, room, target_name)| Kick {
+                by,
+                room,
+                target_name,
+            };
+            let promote = |(by, room, target_name)| Promote {
+                by,
+                room,
+                target_name,
+            };
+            let roles = || async {
+                let who = server
+                    .send(Who {
+                        room: "club".to_owned(),
+                    })
+                    .await
+                    .unwrap()
+                    .unwrap();
+                who.members
+                    .into_iter()
+                    .map(|m| (m.name, m.role))
+                    .collect::<Vec<_>>()
+            };
+
+            // участники не модерируют и не назначают модераторов
+            let denied = server.send(kick(target(&bob, "carol"))).await.unwrap();
+            assert!(matches!(denied, Err(ModerationError::NotModerator)));
+            let denied = server.send(promote(target(&bob, "carol"))).await.unwrap();
+            assert!(matches!(denied, Err(ModerationError::NotOwner)));
+
+            server
+                .send(promote(target(&alice, "bob")))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                roles().await,
+                [
+                    ("alice".to_owned(), Role::Owner),
+                    ("bob".to_owned(), Role::Moderator),
+                    ("carol".to_owned(), Role::Member),
+                ]
+            );
+            let again = server.send(promote(target(&alice, "bob"))).await.unwrap();
+            assert!(matches!(again, Err(ModerationError::AlreadyModerator)));
+            // модератор не назначает других и не трогает владельца
+            let denied = server.send(promote(target(&bob, "carol"))).await.unwrap();
+            assert!(matches!(denied, Err(ModerationError::NotOwner)));
+            let outranked = server.send(kick(target(&bob, "alice"))).await.unwrap();
+            assert!(matches!(outranked, Err(ModerationError::Outranked)));
+            server
+                .send(Mute {
+                    by: bob.id,
+                    room: "club".to_owned(),
+                    target_name: "carol".to_owned(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+
+            server.do_send(Disconnect { id: alice.id });
+            assert_eq!(
+                roles().await,
+                [
+                    ("bob".to_owned(), Role::Owner),
+                    ("carol".to_owned(), Role::Member),
+                ]
+            );
+            server.do_send(Disconnect { id: bob.id });
+            assert_eq!(roles().await, [("carol".to_owned(), Role::Owner)]);
+            server.do_send(Disconnect { id: carol.id });
+            let owner = inspect(&server, |s| s.rooms["club"].owner).await;
+            assert_eq!(owner, None);
+        });
+    }
+
+    #[test]
+    fn bursts_past_the_flood_limit_are_dropped_with_one_warning() {
+        System::new("test").block_on(async {
+            let limit = FloodLimit {
+                burst: 3,
+                window: Duration::from_millis(300),
+                max_warnings: 0,
+            };
+            let server = test_server().with_flood_limit(limit).start();
+            let spammer = connect(&server).await;
+            let peer = connect(&server).await;
+            let say = |n| ClientMessage {
+                id: spammer.id,
+                msg: format!("spam {}", n),
+                room: DEFAULT_ROOM.to_owned(),
+                correlation_id: None,
+            };
+            for n in 0..10 {
+                server.do_send(say(n));
+            }
+            settle().await;
+            assert_eq!(count(&peer, "spam"), 3);
+            assert!(peer.received("spam 2") && !peer.received("spam 3"));
+            // предупреждение само не становится спамом
+            assert_eq!(count(&spammer, "!!! rate limited, retry in 1s"), 1);
+
+            // за окно запас пополняется целиком, но не больше
+            actix::clock::delay_for(limit.window).await;
+            for n in 10..20 {
+                server.do_send(say(n));
+            }
+            settle().await;
+            assert_eq!(count(&peer, "spam"), 6);
+            assert_eq!(count(&spammer, "!!! rate limited"), 2);
+            assert!(!spammer.received("[flooded]"));
+        });
+    }
+
+    #[test]
+    fn repeated_floods_disconnect_the_session() {
+        System::new("test").block_on(async {
+            let limit = FloodLimit {
+                burst: 2,
+                window: Duration::from_millis(100),
+                max_warnings: 2,
+            };
+            let server = test_server().with_flood_limit(limit).start();
+            let spammer = connect(&server).await;
+            for round in 0..2 {
+                for n in 0..5 {
+                    server.do_send(ClientMessage {
+                        id: spammer.id,
+                        msg: format!("spam {} {}", round, n),
+                        room: DEFAULT_ROOM.to_owned(),
+                        correlation_id: None,
+                    });
+                }
+                settle().await;
+                if round == 0 {
+                    actix::clock::delay_for(limit.window).await;
+                }
+            }
+            assert_eq!(count(&spammer, "!!! rate limited"), 1);
+            assert_eq!(count(&spammer, "[flooded]"), 1);
+        });
+    }
+
+    #[test]
+    fn slow_mode_limits_members_and_exempts_moderators() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let owner = named(&server, "owner").await;
+            let moderator = named(&server, "mod").await;
+            let member = named(&server, "member").await;
+            for client in [&owner, &moderator, &member] {
+                server
+                    .send(Join {
+                        id: client.id,
+                        name: "slow".to_owned(),
+                        password: None,
+                        capacity: None,
+                    })
+                    .await
+                    .unwrap()
+                    .unwrap();
+            }
+            server
+                .send(Promote {
+                    by: owner.id,
+                    room: "slow".to_owned(),
+                    target_name: "mod".to_owned(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            let slow_mode = |by, interval| SetSlowMode {
+                by,
+                room: "slow".to_owned(),
+                interval,
+            };
+            let denied = server
+                .send(slow_mode(member.id, Some(Duration::from_secs(5))))
+                .await
+                .unwrap();
+            assert!(matches!(denied, Err(ModerationError::NotModerator)));
+            server
+                .send(slow_mode(moderator.id, Some(Duration::from_secs(5))))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(member.received("slow mode is on: one message every 5s"));
+
+            let say = |client: &Client, text: &str| ClientMessage {
+                id: client.id,
+                msg: text.to_owned(),
+                room: "slow".to_owned(),
+                correlation_id: None,
+            };
+            server.do_send(say(&member, "first"));
+            server.do_send(say(&member, "too soon"));
+            settle().await;
+            assert!(owner.received("first"));
+            assert!(!owner.received("too soon"));
+            assert!(member.received("!!! slow mode is on, wait 5s"));
+
+            // через 3 секунды осталось ждать 2
+            let id = member.id;
+            inspect(&server, move |s| {
+                let posted = s
+                    .rooms
+                    .get_mut("slow")
+                    .unwrap()
+                    .last_post
+                    .get_mut(&id)
+                    .unwrap();
+                *posted -= Duration::from_secs(3);
+            })
+            .await;
+            server.do_send(say(&member, "still too soon"));
+            settle().await;
+            assert!(member.received("!!! slow mode is on, wait 2s"));
+            assert!(!owner.received("still too soon"));
+
+            for client in [&owner, &moderator] {
+                for n in 0..3 {
+                    server.do_send(say(client, &format!("exempt {}", n)));
+                }
+            }
+            settle().await;
+            assert_eq!(count(&member, "exempt"), 6);
+
+            server
+                .send(slow_mode(owner.id, None))
+                .await
+                .unwrap()
+                .unwrap();
+            server.do_send(say(&member, "free again"));
+            server.do_send(say(&member, "and again"));
+            settle().await;
+            assert!(owner.received("free again") && owner.received("and again"));
+        });
+    }
+
+    #[test]
+    fn the_server_rejects_bodies_past_the_byte_limit() {
+        System::new("test").block_on(async {
+            let server = test_server().with_max_message_len(8).start();
+            let alice = named(&server, "alice").await;
+            let peer = connect(&server).await;
+            let say = |text: String| ClientMessage {
+                id: alice.id,
+                msg: text,
+                room: DEFAULT_ROOM.to_owned(),
+                correlation_id: None,
+            };
+            // префикс с именем автора не считается, "é" занимает два байта
+            server.do_send(say("alice: éééé".to_owned()));
+            server.do_send(say("alice: éééé!".to_owned()));
+            server.do_send(say("* alice ééééé".to_owned()));
+            settle().await;
+            assert_eq!(count(&peer, "éééé"), 1);
+            assert!(peer.received("alice: éééé"));
+            assert_eq!(count(&alice, "!!! message too long (max 8 bytes)"), 2);
+        });
+    }
+
+    #[test]
+    fn room_pages_are_filtered_bounded_and_deterministic() {
+        System::new("test").block_on(async {
+            // одни и те же комнаты, вставленные в разном порядке
+            let mut rooms: Vec<(String, usize)> = vec![
+                ("game-a".to_owned(), 3),
+                ("game-b".to_owned(), 1),
+                ("Game-c".to_owned(), 1),
+                ("chat".to_owned(), 2),
+            ];
+            rooms.extend((0..20).map(|n| (format!("room-{:02}", n), n % 4)));
+            let mut servers = Vec::new();
+            for reversed in [false, true] {
+                let server = test_server().start();
+                let mut order = rooms.clone();
+                if reversed {
+                    order.reverse();
+                }
+                inspect(&server, move |s| {
+                    for (n, (name, members)) in order.into_iter().enumerate() {
+                        let mut room = Room::default();
+                        for member in 0..members {
+                            room.enter(1000 * (n + 1) + member);
+                        }
+                        s.rooms.insert(name, room);
+                    }
+                })
+                .await;
+                servers.push(server);
+            }
+            let list = |server: &Addr<ChatServer>, filter: Option<&str>, offset, limit, sort| {
+                server.send(ListRooms {
+                    filter: filter.map(str::to_owned),
+                    offset,
+                    limit,
+                    sort,
+                })
+            };
+            let names = |page: RoomPage| -> (Vec<String>, usize) {
+                (page.rooms.into_iter().map(|r| r.name).collect(), page.total)
+            };
+
+            let games = list(&servers[0], Some("GAME"), 0, 50, RoomSort::Members);
+            assert_eq!(
+                names(games.await.unwrap()),
+                (
+                    vec![
+                        "game-a".to_owned(),
+                        "Game-c".to_owned(),
+                        "game-b".to_owned()
+                    ],
+                    3
+                )
+            );
+            for (offset, expected) in [(0, 2), (2, 1), (3, 0), (10, 0)] {
+                let page = list(&servers[0], Some("game"), offset, 2, RoomSort::Name);
+                let (page, total) = names(page.await.unwrap());
+                assert_eq!((page.len(), total), (expected, 3), "offset {}", offset);
+            }
+
+            for sort in [RoomSort::Name, RoomSort::Members] {
+                let first = names(list(&servers[0], None, 0, 100, sort).await.unwrap());
+                let second = names(list(&servers[1], None, 0, 100, sort).await.unwrap());
+                assert_eq!(first, second, "{:?}", sort);
+                // страницы подряд складываются в полный список без пропусков и повторов
+                let mut paged = Vec::new();
+                for offset in (0..first.1).step_by(7) {
+                    paged.extend(names(list(&servers[1], None, offset, 7, sort).await.unwrap()).0);
+                }
+                assert_eq!(paged, first.0, "{:?}", sort);
+            }
+        });
+    }
+
+    #[test]
+    fn broadcasts_reach_each_session_once_whatever_its_rooms() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = connect(&server).await;
+            let bob = connect(&server).await;
+            let carol = connect(&server).await;
+            server
+                .send(Join {
+                    id: carol.id,
+                    name: "other".to_owned(),
+                    password: None,
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            // alice состоит сразу в трех комнатах
+            let id = alice.id;
+            inspect(&server, move |s| {
+                for room in ["other", "third"] {
+                    s.rooms.entry(room.to_owned()).or_default().enter(id);
+                }
+            })
+            .await;
+
+            server.do_send(Broadcast {
+                text: "restarting in 5 minutes".to_owned(),
+            });
+            settle().await;
+            for client in [&alice, &bob, &carol] {
+                assert_eq!(count(client, "[announcement] restarting in 5 minutes"), 1);
+                // объявление не становится сообщением комнаты
+                assert_eq!(count(client, "restarting"), 1);
+            }
+        });
+    }
+
+    #[test]
+    fn bans_reject_joins_across_reconnects_until_unbanned() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let owner = named(&server, "owner").await;
+            let bob = named(&server, "bob").await;
+            let join = |id| Join {
+                id,
+                name: "club".to_owned(),
+                password: None,
+                capacity: None,
+            };
+            server.send(join(owner.id)).await.unwrap().unwrap();
+            server.send(join(bob.id)).await.unwrap().unwrap();
+            let ban = Ban {
+                by: owner.id,
+                room: "club".to_owned(),
+                target_name: "Bob".to_owned(),
+            };
+            server.send(ban).await.unwrap().unwrap();
+            let room_of = |id| {
+                inspect(&server, move |s| {
+                    s.rooms
+                        .iter()
+                        .filter(|(_, room)| room.sessions.contains(&id))
+                        .map(|(name, _)| name.clone())
+                        .collect::<Vec<_>>()
+                })
+            };
+            assert_eq!(room_of(bob.id).await, [DEFAULT_ROOM]);
+            assert!(owner.received("Bob was banned"));
+
+            let refused = server.send(join(bob.id)).await.unwrap();
+            assert!(matches!(refused, Err(JoinError::Banned)));
+            assert_eq!(room_of(bob.id).await, [DEFAULT_ROOM]);
+
+            // бан привязан к имени, а не к сессии
+            server.do_send(Disconnect { id: bob.id });
+            let bob = named(&server, "bob").await;
+            let refused = server.send(join(bob.id)).await.unwrap();
+            assert!(matches!(refused, Err(JoinError::Banned)));
+            assert_eq!(room_of(bob.id).await, [DEFAULT_ROOM]);
+
+            let unban = |by| Unban {
+                by,
+                room: "club".to_owned(),
+                target_name: "bob".to_owned(),
+            };
+            let denied = server.send(unban(bob.id)).await.unwrap();
+            assert!(matches!(denied, Err(ModerationError::NotModerator)));
+            server.send(unban(owner.id)).await.unwrap().unwrap();
+            assert!(owner.received("bob was unbanned"));
+            let again = server.send(unban(owner.id)).await.unwrap();
+            assert!(matches!(again, Err(ModerationError::NotBanned)));
+
+            server.send(join(bob.id)).await.unwrap().unwrap();
+            assert_eq!(room_of(bob.id).await, ["club"]);
+        });
+    }
+    #[test]
+    fn resync_replays_the_buffer_and_marks_truncated_gaps() {
+        System::new("test").block_on(async {
+            let server = ChatServer::new(
+                Arc::default(),
+                Announcements::Full,
+                5,
+                0,
+                Duration::from_secs(60),
+                10_000,
+                None,
+            )
+            .start();
+            let alice = connect(&server).await;
+            for n in 0..8 {
+                server.do_send(ClientMessage {
+                    id: alice.id,
+                    msg: format!("m{}", n),
+                    room: DEFAULT_ROOM.to_owned(),
+                    correlation_id: None,
+                });
+            }
+            let (evicted, last) = inspect(&server, |s| {
+                let room = &s.rooms[DEFAULT_ROOM];
+                (room.evicted_id, room.seq)
+            })
+            .await;
+            assert_eq!(last - evicted, 5);
+            let resync = |since_id| {
+                server.send(Resync {
+                    room: DEFAULT_ROOM.to_owned(),
+                    since_id,
+                })
+            };
+            let ids = |replay: &ResyncReplay| -> Vec<u64> {
+                replay.entries.iter().map(|entry| entry.msg_id).collect()
+            };
+
+            let within = resync(last - 2).await.unwrap();
+            assert!(!within.truncated);
+            assert_eq!(ids(&within), [last - 1, last]);
+            assert_eq!(within.entries[1].text.as_ref(), "m7");
+
+            // пропуск ровно до начала буфера восстанавливается целиком
+            let boundary = resync(evicted).await.unwrap();
+            assert!(!boundary.truncated);
+            assert_eq!(ids(&boundary), (evicted + 1..=last).collect::<Vec<_>>());
+
+            let truncated = resync(evicted - 1).await.unwrap();
+            assert!(truncated.truncated);
+            assert_eq!(ids(&truncated), ids(&boundary));
+
+            let current = resync(last).await.unwrap();
+            assert!(!current.truncated);
+            assert!(current.entries.is_empty());
+        });
+    }
+
+    #[test]
+    fn room_names_are_trimmed_and_limited_to_plain_characters() {
+        assert_eq!(normalize_room_name("  dev "), Some("dev"));
+        assert_eq!(normalize_room_name("\tops_2-b\n"), Some("ops_2-b"));
+        let longest = "r".repeat(MAX_ROOM_NAME_LEN);
+        assert_eq!(normalize_room_name(&longest), Some(longest.as_str()));
+        for invalid in [
+            "",
+            "   ",
+            "dev ops",
+            "dév",
+            "dev\u{0}",
+            "a/b",
+            &"r".repeat(65),
+        ] {
+            assert_eq!(normalize_room_name(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn joins_trim_room_names_and_refuse_invalid_ones() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = connect(&server).await;
+            let bob = connect(&server).await;
+            let join = |id, room: &str| Join {
+                id,
+                name: room.to_owned(),
+                password: None,
+                capacity: None,
+            };
+            server.send(join(alice.id, " dev ")).await.unwrap().unwrap();
+            server.send(join(bob.id, "dev")).await.unwrap().unwrap();
+            let refused = server.send(join(bob.id, "dev\u{7}")).await.unwrap();
+            assert!(matches!(refused, Err(JoinError::InvalidName)));
+
+            let (rooms, dev) = inspect(&server, |s| {
+                let mut rooms: Vec<String> = s.rooms.keys().cloned().collect();
+                rooms.sort();
+                (rooms, s.rooms["dev"].sessions.len())
+            })
+            .await;
+            assert_eq!(rooms, [DEFAULT_ROOM, "dev"]);
+            assert_eq!(dev, 2);
+        });
+    }
+
+    #[test]
+    fn statuses_are_cleaned_and_limited_in_graphemes() {
+        assert_eq!(
+            sanitize_status("  🎧\u{7}  focusing\n\tnow ").unwrap(),
+            "🎧 focusing now"
+        );
+        assert!(matches!(
+            sanitize_status(" \u{1b}\n "),
+            Err(StatusError::Empty)
+        ));
+        // флаг из двух кодовых точек считается одной графемой
+        let flags = "🇺🇦".repeat(MAX_STATUS_LEN);
+        assert_eq!(sanitize_status(&flags).unwrap(), flags);
+        let too_long = "a".repeat(MAX_STATUS_LEN + 1);
+        assert!(matches!(
+            sanitize_status(&too_long),
+            Err(StatusError::TooLong)
+        ));
+    }
+
+    #[test]
+    fn rooms_with_statuses_off_omit_them_from_members_and_notices() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let alice = named(&server, "alice").await;
+            let bob = named(&server, "bob").await;
+            server
+                .send(Join {
+                    id: alice.id,
+                    name: "quiet".to_owned(),
+                    password: None,
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            server
+                .send(SetRoomOption {
+                    by: alice.id,
+                    room: "quiet".to_owned(),
+                    option: RoomOption::Statuses(false),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            for (client, status) in [(&alice, "🎧 focusing"), (&bob, "on call")] {
+                server
+                    .send(SetStatus {
+                        id: client.id,
+                        status: Some(status.to_owned()),
+                    })
+                    .await
+                    .unwrap()
+                    .unwrap();
+            }
+            // не ждать `STATUS_DEBOUNCE`
+            let (a, b) = (alice.id, bob.id);
+            inspect(&server, move |s| {
+                s.announce_status(a);
+                s.announce_status(b);
+            })
+            .await;
+            settle().await;
+
+            let statuses = |room: &str| {
+                server.send(Who {
+                    room: room.to_owned(),
+                })
+            };
+            let quiet = statuses("quiet").await.unwrap().unwrap();
+            assert_eq!(quiet.members[0].name, "alice");
+            assert_eq!(quiet.members[0].status, None);
+            let main = statuses(DEFAULT_ROOM).await.unwrap().unwrap();
+            assert_eq!(main.members[0].name, "bob");
+            assert_eq!(main.members[0].status.as_deref(), Some("on call"));
+
+            assert!(!alice.received("is now:"));
+            assert!(bob.received("bob is now: on call"));
+            assert!(!bob.received("alice is now"));
+        });
+    }
+
+    /// Клиент со списком участников: запоминает сообщения комнаты вместе с номерами
+    struct Observer {
+        events: Arc<Mutex<Vec<(u64, String)>>>,
+    }
+
+    impl Actor for Observer {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Observer {
+        type Result = ();
+
+        fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
+            if let Some(seq) = msg.msg_id {
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push((seq, msg.text.to_string()));
+            }
+        }
+    }
+
+    impl Handler<Control> for Observer {
+        type Result = ();
+
+        fn handle(&mut self, _: Control, _: &mut Context<Self>) {}
+    }
+
+    /// Подключить `Observer`, вернуть id сессии и её сообщения с номерами
+    async fn observe(server: &Addr<ChatServer>) -> (usize, Arc<Mutex<Vec<(u64, String)>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observer = Observer {
+            events: events.clone(),
+        }
+        .start();
+        let connected = server
+            .send(Connect {
+                addr: observer.clone().recipient(),
+                control: observer.recipient(),
+                resume: None,
+                peer: None,
+                user_agent: None,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        (connected.id, events)
+    }
+
+    /// Восстановить список участников, как клиент: снимок `/who` и события
+    /// комнаты с номерами больше номера снимка
+    fn reconstruct(snapshot: &WhoSnapshot, events: &[(u64, String)]) -> Vec<String> {
+        let mut members: BTreeSet<String> =
+            snapshot.members.iter().map(|m| m.name.clone()).collect();
+        for (_, text) in events.iter().filter(|(seq, _)| *seq > snapshot.seq) {
+            let event = text.split(" (").next().unwrap();
+            if let Some(name) = event.strip_suffix(" joined") {
+                members.insert(name.to_owned());
+            } else if let Some(name) = event
+                .strip_suffix(" left the room")
+                .or_else(|| event.strip_suffix(" disconnected"))
+            {
+                members.remove(name);
+            }
+        }
+        members.into_iter().collect()
+    }
+
+    #[test]
+    fn snapshot_and_membership_events_rebuild_the_member_list() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let (watcher, events) = observe(&server).await;
+            let join = |id, room: &str| Join {
+                id,
+                name: room.to_owned(),
+                password: None,
+                capacity: None,
+            };
+            server.send(join(watcher, "storm")).await.unwrap().unwrap();
+            let mut users = Vec::new();
+            for i in 0..30 {
+                users.push(named(&server, &format!("user{:02}", i)).await);
+            }
+
+            // шторм входов и выходов, снимок снимается посреди него, и часть
+            // уже учтенных в нем событий приходит клиенту позже
+            let who = || Who {
+                room: "storm".to_owned(),
+            };
+            let mut snapshot = None;
+            for (i, user) in users.iter().enumerate() {
+                server.do_send(join(user.id, "storm"));
+                match i % 3 {
+                    1 => server.do_send(join(user.id, DEFAULT_ROOM)),
+                    2 if i % 2 == 0 => server.do_send(Disconnect { id: user.id }),
+                    _ => {}
+                }
+                if i == 15 {
+                    snapshot = server.send(who()).await.unwrap();
+                }
+            }
+            let expected = server.send(who()).await.unwrap().unwrap();
+            settle().await;
+
+            let events = events.lock().unwrap();
+            assert!(events.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            let snapshot = snapshot.unwrap();
+            assert!(events.iter().any(|(seq, _)| *seq <= snapshot.seq));
+            let expected: Vec<String> = expected.members.into_iter().map(|m| m.name).collect();
+            assert_eq!(expected.len(), 15);
+            assert_eq!(reconstruct(&snapshot, &events), expected);
+        });
+    }
+
+    #[test]
+    fn msg_ids_increase_per_room_independently_of_other_rooms() {
+        System::new("test").block_on(async {
+            let server = test_server().start();
+            let (first, first_events) = observe(&server).await;
+            let (second, second_events) = observe(&server).await;
+            let first_writer = connect(&server).await;
+            let second_writer = connect(&server).await;
+            let join = |id, room: &str| Join {
+                id,
+                name: room.to_owned(),
+                password: None,
+                capacity: None,
+            };
+            for (id, room) in [
+                (first, "first"),
+                (first_writer.id, "first"),
+                (second, "second"),
+                (second_writer.id, "second"),
+            ] {
+                server.send(join(id, room)).await.unwrap().unwrap();
+            }
+            settle().await;
+            // сообщение не знает своей комнаты, поэтому события комнаты по
+            // умолчанию отбрасываются
+            first_events.lock().unwrap().clear();
+            second_events.lock().unwrap().clear();
+            let say = |id, room: &str, n| ClientMessage {
+                id,
+                msg: format!("message {}", n),
+                room: room.to_owned(),
+                correlation_id: None,
+            };
+            // в первую комнату пишут втрое чаще
+            for n in 0..9 {
+                server.do_send(say(first_writer.id, "first", n));
+                if n % 3 == 0 {
+                    server.do_send(say(second_writer.id, "second", n));
+                }
+            }
+            let seqs = inspect(&server, |s| (s.rooms["first"].seq, s.rooms["second"].seq)).await;
+            settle().await;
+
+            for (room, seq, sent, events) in [
+                ("first", seqs.0, 9, &first_events),
+                ("second", seqs.1, 3, &second_events),
+            ] {
+                let ids: Vec<u64> = events.lock().unwrap().iter().map(|(id, _)| *id).collect();
+                assert_eq!(ids.len(), sent, "{}", room);
+                // номера комнаты идут подряд, без пропусков на сообщения другой комнаты
+                assert!(
+                    ids.windows(2).all(|pair| pair[1] == pair[0] + 1),
+                    "{}",
+                    room
+                );
+                assert_eq!(ids.last(), Some(&seq), "{}", room);
+            }
+            // обе комнаты считают одинаково до сообщений
+            assert_eq!(seqs.0 - seqs.1, 6);
+            let history = server
+                .send(GetHistory {
+                    room: "first".to_owned(),
+                    limit: usize::MAX,
+                })
+                .await
+                .unwrap();
+            assert!(history.windows(2).all(|pair| pair[0].ts <= pair[1].ts));
+        });
+    }
+}
@@ -0,0 +1,163 @@
+//! Проверка согласованности состояния `ChatServer`.
+//! Комнаты, реестр имён и списки ограничений ссылаются на сессии по id,
+//! ошибка в любом обработчике может оставить в них «призраков».
+//! В режиме исправления удаляются только висячие ссылки, каждое исправление пишется в лог.
+//! Каждая часть сервера проверяет свои комнаты и имена своих сессий.
+
+use actix::prelude::*;
+use log::warn;
+
+use super::{name_key, ChatServer, Room};
+
+/// Проверить состояние сервера, при `repair` исправить найденные нарушения
+pub struct Fsck {
+    pub repair: bool,
+}
+
+impl actix::Message for Fsck {
+    type Result = Vec<String>;
+}
+
+impl ChatServer {
+    /// Найти нарушения инвариантов, при `repair` исправить их.
+    /// Возвращает описание каждого нарушения
+    pub(super) fn fsck(&mut self, repair: bool) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        let owns_default = self.owns(&self.default_room);
+        if owns_default && !self.rooms.contains_key(&self.default_room) {
+            violations.push(format!("room {} is missing", self.default_room));
+            if repair {
+                self.rooms
+                    .insert(self.default_room.clone(), Room::default());
+                audit(&format!("recreated room {}", self.default_room));
+            }
+        }
+
+        // участники комнат и списки ограничений ссылаются на живые сессии
+        let sessions = &self.sessions;
+        for (name, room) in &mut self.rooms {
+            let dangling: Vec<usize> = room
+                .sessions
+                .iter()
+                .chain(&room.muted)
+                .copied()
+                .filter(|id| !sessions.contains_key(id))
+                .collect();
+            for id in dangling {
+                violations.push(format!("room {} references unknown session {}", name, id));
+                if repair {
+                    room.leave(id);
+                    audit(&format!("removed session {} from room {}", id, name));
+                }
+            }
+
+            let stray_mutes: Vec<usize> = room.muted.difference(&room.sessions).copied().collect();
+            for id in stray_mutes {
+                violations.push(format!("room {} mutes non-member {}", name, id));
+                if repair {
+                    room.muted.remove(&id);
+                    audit(&format!("unmuted non-member {} in room {}", id, name));
+                }
+            }
+
+            if self.history_len < room.history.len() {
+                violations.push(format!(
+                    "room {} keeps {} history entries over the limit of {}",
+                    name,
+                    room.history.len(),
+                    self.history_len
+                ));
+                if repair {
+                    let excess = room.history.len() - self.history_len;
+                    room.history.drain(..excess);
+                    audit(&format!("trimmed history of room {}", name));
+                }
+            }
+
+            let ordered = room
+                .history
+                .iter()
+                .zip(room.history.iter().skip(1))
+                .all(|(a, b)| a.msg_id < b.msg_id);
+            if !ordered {
+                violations.push(format!("room {} history is out of order", name));
+            }
+        }
+
+        // каждая сессия находится ровно в одной комнате
+        for id in self.sessions.keys() {
+            let count = self
+                .rooms
+                .values()
+                .filter(|room| room.sessions.contains(id))
+                .count();
+            if count == 0 {
+                violations.push(format!("session {} is not in any room", id));
+                if repair && owns_default {
+                    if let Some(room) = self.rooms.get_mut(&self.default_room) {
+                        room.enter(*id);
+                        audit(&format!("moved session {} to {}", id, self.default_room));
+                    }
+                }
+            } else if count > 1 {
+                violations.push(format!("session {} is in {} rooms", id, count));
+            }
+        }
+
+        // реестр имён совпадает с именами сессий. Имена без подключенной
+        // сессии проверяет первая часть сервера
+        let mut registry = self.directory.registry();
+        let shard = self.shard;
+        let stale: Vec<String> = registry
+            .names
+            .iter()
+            .filter(|(key, id)| {
+                let here = registry
+                    .homes
+                    .get(id)
+                    .map_or(shard == 0, |home| home.shard == shard);
+                here && sessions
+                    .get(id)
+                    .and_then(|s| s.name.as_ref())
+                    .is_none_or(|name| name_key(name) != **key)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            violations.push(format!("name {} points to a session without it", key));
+            if repair {
+                registry.names.remove(&key);
+                audit(&format!("released name {}", key));
+            }
+        }
+        for (id, session) in sessions {
+            if let Some(name) = &session.name {
+                let key = name_key(name);
+                if registry.names.get(&key) != Some(id) {
+                    violations.push(format!("session {} name {} is not registered", id, name));
+                    if repair && !registry.names.contains_key(&key) {
+                        registry.names.insert(key, *id);
+                        audit(&format!("registered name {} for session {}", name, id));
+                    }
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+/// Записать исправление в журнал
+fn audit(action: &str) {
+    warn!("fsck repair: {}", action);
+}
+
+/// Обработчик сообщения `Fsck`.
+impl Handler<Fsck> for ChatServer {
+    type Result = MessageResult<Fsck>;
+
+    fn handle(&mut self, msg: Fsck, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.fsck(msg.repair))
+    }
+}
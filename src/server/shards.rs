@@ -0,0 +1,1025 @@
+//! Разделение комнат между несколькими `ChatServer`.
+//! Каждая часть сервера (шард) работает в своем потоке и владеет комнатами,
+//! на которые указывает хеш их названия, вместе с сессиями в этих комнатах.
+//! Имена, число подключений, токены возобновления и предел создания комнат
+//! общие для всех частей и хранятся в `Directory`. Сессия, входящая в комнату
+//! другой части, переезжает туда целиком.
+
+use actix::prelude::*;
+use futures::future;
+use sha2::{Digest, Sha256};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use super::{
+    secrets_match, sort_rooms, Away, Broadcast, ChatServer, Connect, Connected, Control,
+    Disconnect, Flood, Fsck, GetStats, Join, JoinError, ListRooms, ListSessions, Message, RoomPage,
+    ServerFull, ServerStats, Session, SessionInfo, Shutdown, Suspended, ROOM_CREATION_KEYS,
+    ROOM_CREATION_LIMIT, ROOM_CREATION_WINDOW,
+};
+use crate::limiter::{KeyedLimiter, SlidingWindow};
+
+/// Где сейчас подключенная сессия
+pub(super) struct Home {
+    /// Номер части сервера
+    pub shard: usize,
+    /// Сессия переезжает: прежняя часть её уже забыла, новая ещё не приняла
+    pub moving: bool,
+    /// Клиент отключился во время переезда, сессию удалит принявшая её часть
+    pub disconnected: bool,
+    pub addr: Recipient<Message>,
+    pub control: Recipient<Control>,
+    /// Токен возобновления, он же токен сессии в REST API
+    pub token: String,
+    /// Имя сессии, как она его задала
+    pub name: Option<String>,
+    /// Отсутствие сессии, для ответов на упоминания из любой части
+    pub away: Option<Away>,
+}
+
+/// Состояние, общее для всех частей сервера
+pub(super) struct Registry {
+    /// Занятые имена в нижнем регистре -> id сессии
+    pub names: HashMap<String, usize>,
+    /// Id подключенной сессии -> где она сейчас
+    pub homes: HashMap<usize, Home>,
+    /// Токен возобновления -> недавно отключенная сессия
+    pub suspended: HashMap<String, Suspended>,
+    /// Ограничение частоты создания комнат по id сессии
+    pub room_creation: KeyedLimiter<usize, SlidingWindow>,
+    /// SHA-256 токена сессии -> id сессии, чтобы найти сессию по токену
+    /// одним поиском, а не сравнением с каждым токеном
+    tokens: HashMap<[u8; 32], usize>,
+}
+
+impl Registry {
+    /// Зарегистрировать подключенную сессию
+    pub fn insert_home(&mut self, id: usize, home: Home) {
+        self.tokens.insert(token_digest(&home.token), id);
+        self.homes.insert(id, home);
+    }
+
+    /// Снять регистрацию сессии вместе с её токеном
+    pub fn remove_home(&mut self, id: usize) -> Option<Home> {
+        let home = self.homes.remove(&id)?;
+        self.tokens.remove(&token_digest(&home.token));
+        Some(home)
+    }
+
+    /// Id сессии с токеном `token`. Поиск идет по хешу, а найденный токен
+    /// сравнивается за постоянное время
+    pub fn session_by_token(&self, token: &str) -> Option<usize> {
+        let id = *self.tokens.get(&token_digest(token))?;
+        self.homes
+            .get(&id)
+            .filter(|home| secrets_match(&home.token, token))
+            .map(|_| id)
+    }
+}
+
+/// SHA-256 токена, ключ `Registry::tokens`
+fn token_digest(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+/// Части сервера и их общее состояние
+pub(super) struct Directory {
+    registry: Mutex<Registry>,
+    /// Сколько частей у сервера
+    count: usize,
+    /// Адреса частей, задаются после их запуска
+    shards: OnceLock<Vec<Addr<ChatServer>>>,
+}
+
+impl Directory {
+    pub(super) fn new(count: usize) -> Directory {
+        Directory {
+            registry: Mutex::new(Registry {
+                names: HashMap::new(),
+                homes: HashMap::new(),
+                suspended: HashMap::new(),
+                room_creation: KeyedLimiter::new(ROOM_CREATION_KEYS, |_| {
+                    SlidingWindow::new(ROOM_CREATION_LIMIT, ROOM_CREATION_WINDOW)
+                }),
+                tokens: HashMap::new(),
+            }),
+            count,
+            shards: OnceLock::new(),
+        }
+    }
+
+    /// Общее состояние. Блокировка держится только внутри одного обработчика,
+    /// и пока она взята, нельзя ждать другую часть сервера
+    pub(super) fn registry(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap()
+    }
+
+    /// Номер части, владеющей комнатой `room`
+    pub(super) fn owner(&self, room: &str) -> usize {
+        if self.count == 1 {
+            return 0;
+        }
+        let mut hasher = DefaultHasher::new();
+        room.hash(&mut hasher);
+        (hasher.finish() % self.count as u64) as usize
+    }
+
+    /// Адрес части, владеющей комнатой `room`. Частей больше одной только у
+    /// сервера, запущенного через `Router`, и адреса к этому моменту заданы
+    pub(super) fn shard(&self, room: &str) -> &Addr<ChatServer> {
+        &self.shards()[self.owner(room)]
+    }
+
+    /// Адреса всех частей по номерам
+    pub(super) fn shards(&self) -> &[Addr<ChatServer>] {
+        self.shards.get().expect("shards are started by Router")
+    }
+}
+
+/// Вход только что зарегистрированной сессии: в прежнюю комнату `room`,
+/// если она еще есть и не забанила сессию, иначе в комнату по умолчанию
+pub(super) struct Admit {
+    pub id: usize,
+    pub session: Session,
+    pub room: Option<String>,
+    /// Сессия вернулась по токену возобновления
+    pub resumed: bool,
+}
+
+impl actix::Message for Admit {
+    type Result = Result<Connected, ServerFull>;
+}
+
+/// Вход сессии из другой части сервера в комнату этой части. Сессия проходит
+/// те же проверки, что и при `Join`, и переезжает сюда, только если её пустили
+pub(super) struct Transfer {
+    pub session: Session,
+    pub flood: Option<Flood>,
+    pub join: Join,
+}
+
+impl actix::Message for Transfer {
+    type Result = Result<(), JoinError>;
+}
+
+/// Переезд сессии из другой части сервера в комнату по умолчанию,
+/// `control` объясняет сессии причину
+pub(super) struct Relocate {
+    pub id: usize,
+    pub session: Session,
+    pub flood: Option<Flood>,
+    pub control: Control,
+}
+
+impl actix::Message for Relocate {
+    type Result = ();
+}
+
+/// Обработчик сообщения `Admit`
+impl Handler<Admit> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<Connected, ServerFull>>;
+
+    fn handle(&mut self, msg: Admit, _: &mut Context<Self>) -> Self::Result {
+        self.admit(msg)
+    }
+}
+
+/// Обработчик сообщения `Transfer`
+impl Handler<Transfer> for ChatServer {
+    type Result = Result<(), JoinError>;
+
+    fn handle(&mut self, msg: Transfer, _: &mut Context<Self>) -> Self::Result {
+        let Transfer {
+            session,
+            flood,
+            join,
+        } = msg;
+        let id = join.id;
+        self.check_join(&join, session.name.as_deref())?;
+        let disconnected = self.receive(id, session, flood);
+        self.enter_joined(join);
+        if disconnected {
+            self.remove_session(id);
+        }
+        Ok(())
+    }
+}
+
+/// Обработчик сообщения `Relocate`
+impl Handler<Relocate> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Relocate, _: &mut Context<Self>) {
+        let disconnected = self.receive(msg.id, msg.session, msg.flood);
+        self.enter_default_room(msg.id, msg.control);
+        if disconnected {
+            self.remove_session(msg.id);
+        }
+    }
+}
+
+impl ChatServer {
+    /// Принять сессию, переехавшую из другой части сервера или вернувшуюся
+    /// после отказа. Возвращает `true`, если клиент отключился во время
+    /// переезда и сессию осталось удалить
+    fn receive(&mut self, id: usize, session: Session, flood: Option<Flood>) -> bool {
+        let disconnected = match self.directory.registry().homes.get_mut(&id) {
+            Some(home) => {
+                home.shard = self.shard;
+                home.moving = false;
+                home.disconnected
+            }
+            None => true,
+        };
+        self.sessions.insert(id, session);
+        if let Some(flood) = flood {
+            self.floods.insert(id, flood);
+        }
+        disconnected
+    }
+
+    /// Вернуть сессию, которую не приняла другая часть сервера. Из комнат
+    /// этой части она не уходила
+    pub(super) fn restore(&mut self, id: usize, session: Session, flood: Option<Flood>) {
+        if self.receive(id, session, flood) {
+            self.remove_session(id);
+        }
+    }
+
+    /// Забыть сессию, которая переезжает в другую часть сервера, и отметить
+    /// переезд в `directory`. Её имя, токен и место в общем числе подключений
+    /// остаются за ней. Из комнат сессия уходит отдельно
+    pub(super) fn release(&mut self, id: usize) -> Option<(Session, Option<Flood>)> {
+        self.typing.remove(&id);
+        self.pending_status.remove(&id);
+        self.away_replies
+            .retain(|&(from, to), _| from != id && to != id);
+        let session = self.sessions.remove(&id)?;
+        if let Some(home) = self.directory.registry().homes.get_mut(&id) {
+            home.moving = true;
+        }
+        if self.sessions.is_empty() {
+            if let Some(drained) = self.drained.take() {
+                let _ = drained.send(());
+            }
+        }
+        Some((session, self.floods.remove(&id)))
+    }
+
+    /// `Disconnect` для сессии, которой в этой части нет: переехавшей сессии
+    /// он пересылается, а переезжающую удалит принявшая её часть
+    pub(super) fn forward_disconnect(&mut self, msg: Disconnect) {
+        let mut registry = self.directory.registry();
+        match registry.homes.get_mut(&msg.id) {
+            Some(home) if home.moving => home.disconnected = true,
+            Some(home) if home.shard != self.shard => {
+                self.directory.shards()[home.shard].do_send(msg);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Распределяет сообщения между частями сервера: по комнате, если она
+/// известна, и по всем частям, если ответ собирается из всех комнат или сессий
+#[derive(Clone)]
+pub struct Router {
+    directory: Arc<Directory>,
+    /// Комната новых сессий, `factory` сервера задает ту же
+    default_room: String,
+}
+
+impl Router {
+    /// Запустить `shards` частей сервера, каждую в своем потоке. `factory`
+    /// создает сервер одной части со всеми настройками, включая комнату по
+    /// умолчанию `default_room`. Сохраненные комнаты чужих частей каждая
+    /// часть отбрасывает сама
+    pub fn start<F>(shards: usize, default_room: String, factory: F) -> Router
+    where
+        F: Fn() -> ChatServer + Send + Sync + 'static,
+    {
+        let count = shards.max(1);
+        let directory = Arc::new(Directory::new(count));
+        let factory = Arc::new(factory);
+        let addrs = (0..count)
+            .map(|shard| {
+                let (directory, factory) = (directory.clone(), factory.clone());
+                ChatServer::start_in_arbiter(&Arbiter::new(), move |_| {
+                    factory().with_shard(directory, shard)
+                })
+            })
+            .collect();
+        let _ = directory.shards.set(addrs);
+        Router {
+            directory,
+            default_room,
+        }
+    }
+
+    /// Часть, владеющая комнатой `room`
+    pub fn shard(&self, room: &str) -> &Addr<ChatServer> {
+        self.directory.shard(room)
+    }
+
+    fn shards(&self) -> &[Addr<ChatServer>] {
+        self.directory.shards()
+    }
+
+    /// Зарегистрировать сессию в части её будущей комнаты: прежней для
+    /// вернувшейся по токену сессии и комнаты по умолчанию для новой
+    pub fn connect(&self, msg: Connect) -> Request<ChatServer, Connect> {
+        let room = msg.resume.as_ref().and_then(|token| {
+            self.directory
+                .registry()
+                .suspended
+                .get(token)
+                .and_then(|suspended| suspended.room.clone())
+        });
+        self.shard(room.as_deref().unwrap_or(&self.default_room))
+            .send(msg)
+    }
+
+    /// Сообщить об отключении сессии части, за которой она числится.
+    /// Если сессия тем временем переехала, часть перешлет сообщение дальше
+    pub fn disconnect(&self, id: usize) {
+        let shard = self
+            .directory
+            .registry()
+            .homes
+            .get(&id)
+            .map(|home| home.shard);
+        if let Some(shard) = shard {
+            self.shards()[shard].do_send(Disconnect { id });
+        }
+    }
+
+    /// Страница комнат всех частей. Каждая часть отдает свои первые
+    /// `offset + limit` комнат, из них складывается общая страница
+    pub fn list_rooms(
+        &self,
+        msg: ListRooms,
+    ) -> impl Future<Output = Result<RoomPage, MailboxError>> {
+        let first = msg.offset.saturating_add(msg.limit);
+        let pages = future::try_join_all(self.shards().iter().map(|shard| {
+            shard.send(ListRooms {
+                filter: msg.filter.clone(),
+                offset: 0,
+                limit: first,
+                sort: msg.sort,
+            })
+        }));
+        async move {
+            let mut rooms = Vec::new();
+            let mut total = 0;
+            for page in pages.await? {
+                rooms.extend(page.rooms);
+                total += page.total;
+            }
+            sort_rooms(&mut rooms, msg.sort);
+            let rooms = rooms.into_iter().skip(msg.offset).take(msg.limit).collect();
+            Ok(RoomPage { rooms, total })
+        }
+    }
+
+    /// Сессии всех частей
+    pub fn list_sessions(&self) -> impl Future<Output = Result<Vec<SessionInfo>, MailboxError>> {
+        let lists =
+            future::try_join_all(self.shards().iter().map(|shard| shard.send(ListSessions)));
+        async move { Ok(lists.await?.into_iter().flatten().collect()) }
+    }
+
+    /// Счетчики всех частей вместе
+    pub fn stats(&self) -> impl Future<Output = Result<ServerStats, MailboxError>> {
+        let stats = future::try_join_all(self.shards().iter().map(|shard| shard.send(GetStats)));
+        async move {
+            let total = ServerStats {
+                sessions: 0,
+                rooms: 0,
+                messages: 0,
+            };
+            Ok(stats
+                .await?
+                .into_iter()
+                .fold(total, |total, shard| ServerStats {
+                    sessions: total.sessions + shard.sessions,
+                    rooms: total.rooms + shard.rooms,
+                    messages: total.messages + shard.messages,
+                }))
+        }
+    }
+
+    /// Объявление оператора сессиям всех частей
+    pub fn broadcast(&self, text: String) -> impl Future<Output = Result<(), MailboxError>> {
+        let sent = future::try_join_all(
+            self.shards()
+                .iter()
+                .map(|shard| shard.send(Broadcast { text: text.clone() })),
+        );
+        async move { sent.await.map(drop) }
+    }
+
+    /// Остановить все части, ответ приходит, когда остановилась последняя
+    pub fn shutdown(&self, timeout: Duration) -> impl Future<Output = ()> {
+        let stopped = future::join_all(
+            self.shards()
+                .iter()
+                .map(|shard| shard.send(Shutdown { timeout })),
+        );
+        async move {
+            stopped.await;
+        }
+    }
+
+    /// Нарушения во всех частях
+    pub fn fsck(&self, repair: bool) -> impl Future<Output = Result<Vec<String>, MailboxError>> {
+        let found = future::try_join_all(
+            self.shards()
+                .iter()
+                .map(|shard| shard.send(Fsck { repair })),
+        );
+        async move { Ok(found.await?.into_iter().flatten().collect()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::server::tests::{connect_via, inspect, settle, test_server, Client};
+    use crate::server::{
+        Announcements, Ban, ClientMessage, Counters, Invite, RoomSort, SetAway, SetName,
+        SetNameResult, SetStatus, StatusError, DEFAULT_ROOM,
+    };
+
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    fn router(shards: usize) -> Router {
+        Router::start(shards, DEFAULT_ROOM.to_owned(), test_server)
+    }
+
+    /// Первая комната `prefix-N`, которой не владеет часть сервера с комнатой `other`
+    fn room_apart(router: &Router, prefix: &str, other: &str) -> String {
+        let taken = router.directory.owner(other);
+        (0..)
+            .map(|n| format!("{}-{}", prefix, n))
+            .find(|room| router.directory.owner(room) != taken)
+            .unwrap()
+    }
+
+    async fn join(
+        router: &Router,
+        client: &Client,
+        from: &str,
+        room: &str,
+    ) -> Result<(), JoinError> {
+        router
+            .shard(from)
+            .send(Join {
+                id: client.id,
+                name: room.to_owned(),
+                password: None,
+                capacity: None,
+            })
+            .await
+            .unwrap()
+    }
+
+    fn say(router: &Router, client: &Client, room: &str, text: &str) {
+        router.shard(room).do_send(ClientMessage {
+            id: client.id,
+            msg: text.to_owned(),
+            room: room.to_owned(),
+            correlation_id: None,
+        });
+    }
+
+    /// Сколько сессий в части сервера, владеющей комнатой `room`
+    async fn sessions_of(router: &Router, room: &str) -> usize {
+        inspect(router.shard(room), |s| s.sessions.len()).await
+    }
+
+    #[test]
+    fn sessions_move_to_the_shard_of_their_room() {
+        System::new("test").block_on(async {
+            let router = router(4);
+            let far = room_apart(&router, "far", DEFAULT_ROOM);
+            let alice = connect_via(|msg| router.connect(msg), None).await;
+            let bob = connect_via(|msg| router.connect(msg), None).await;
+            assert_eq!(sessions_of(&router, DEFAULT_ROOM).await, 2);
+
+            join(&router, &alice, DEFAULT_ROOM, &far).await.unwrap();
+            assert_eq!(sessions_of(&router, DEFAULT_ROOM).await, 1);
+            assert_eq!(sessions_of(&router, &far).await, 1);
+
+            // сообщения доходят только до своей комнаты, история едет с комнатой
+            say(&router, &alice, &far, "over here");
+            say(&router, &bob, DEFAULT_ROOM, "still in main");
+            settle().await;
+            join(&router, &bob, DEFAULT_ROOM, &far).await.unwrap();
+            settle().await;
+            assert!(bob.received("left the room"));
+            assert!(bob.received("over here"));
+            assert!(!alice.received("still in main"));
+
+            // обратно в комнату по умолчанию
+            join(&router, &alice, &far, DEFAULT_ROOM).await.unwrap();
+            settle().await;
+            assert_eq!(sessions_of(&router, DEFAULT_ROOM).await, 1);
+            assert_eq!(sessions_of(&router, &far).await, 1);
+            let violations = router.fsck(false).await.unwrap();
+            assert!(violations.is_empty(), "{:?}", violations);
+        });
+    }
+
+    #[test]
+    fn names_stay_unique_across_shards() {
+        System::new("test").block_on(async {
+            let router = router(4);
+            let far = room_apart(&router, "far", DEFAULT_ROOM);
+            let alice = connect_via(|msg| router.connect(msg), None).await;
+            let bob = connect_via(|msg| router.connect(msg), None).await;
+            join(&router, &bob, DEFAULT_ROOM, &far).await.unwrap();
+
+            let set_name = |client: &Client, room: &str, name: &str| {
+                router.shard(room).send(SetName {
+                    id: client.id,
+                    name: name.to_owned(),
+                })
+            };
+            let claimed = set_name(&alice, DEFAULT_ROOM, "Alice").await.unwrap();
+            assert!(matches!(claimed, SetNameResult::Ok));
+            let taken = set_name(&bob, &far, "ALICE").await.unwrap();
+            assert!(matches!(taken, SetNameResult::Taken));
+
+            // имя освобождается при отключении, где бы ни была сессия
+            router.disconnect(alice.id);
+            settle().await;
+            let claimed = set_name(&bob, &far, "alice").await.unwrap();
+            assert!(matches!(claimed, SetNameResult::Ok));
+        });
+    }
+
+    #[test]
+    fn names_and_statuses_need_the_shard_of_the_session() {
+        System::new("test").block_on(async {
+            let router = router(4);
+            let far = room_apart(&router, "far", DEFAULT_ROOM);
+            let alice = connect_via(|msg| router.connect(msg), None).await;
+            join(&router, &alice, DEFAULT_ROOM, &far).await.unwrap();
+
+            // прежняя часть сессию уже не знает и имя за ней не занимает
+            let stale = router
+                .shard(DEFAULT_ROOM)
+                .send(SetName {
+                    id: alice.id,
+                    name: "alice".to_owned(),
+                })
+                .await
+                .unwrap();
+            assert!(matches!(stale, SetNameResult::Unavailable));
+            assert!(router.directory.registry().names.is_empty());
+            let stale = router
+                .shard(DEFAULT_ROOM)
+                .send(SetStatus {
+                    id: alice.id,
+                    status: Some("busy".to_owned()),
+                })
+                .await
+                .unwrap();
+            assert!(matches!(stale, Err(StatusError::Unavailable)));
+        });
+    }
+
+    #[test]
+    fn away_replies_answer_mentions_from_another_shard() {
+        System::new("test").block_on(async {
+            let router = router(4);
+            let far = room_apart(&router, "far", DEFAULT_ROOM);
+            let alice = connect_via(|msg| router.connect(msg), None).await;
+            let bob = connect_via(|msg| router.connect(msg), None).await;
+            router
+                .shard(DEFAULT_ROOM)
+                .send(SetName {
+                    id: bob.id,
+                    name: "Bob".to_owned(),
+                })
+                .await
+                .unwrap();
+            join(&router, &bob, DEFAULT_ROOM, &far).await.unwrap();
+            router
+                .shard(&far)
+                .send(SetAway {
+                    id: bob.id,
+                    away: Some(Away { reason: None }),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+
+            say(&router, &alice, DEFAULT_ROOM, "ping @bob");
+            settle().await;
+            assert!(bob.received("ping @bob"));
+            assert!(alice.received("Bob is away"));
+        });
+    }
+
+    #[test]
+    fn tokens_are_found_by_digest_until_the_session_leaves() {
+        System::new("test").block_on(async {
+            let router = router(4);
+            let alice = connect_via(|msg| router.connect(msg), None).await;
+            let bob = connect_via(|msg| router.connect(msg), None).await;
+            {
+                let registry = router.directory.registry();
+                assert_eq!(registry.session_by_token(&alice.token), Some(alice.id));
+                assert_eq!(registry.session_by_token(&bob.token), Some(bob.id));
+                assert_eq!(registry.session_by_token("not a token"), None);
+            }
+
+            router.disconnect(alice.id);
+            settle().await;
+            let registry = router.directory.registry();
+            assert_eq!(registry.session_by_token(&alice.token), None);
+            assert_eq!(registry.tokens.len(), 1);
+        });
+    }
+
+    #[test]
+    fn room_list_merges_every_shard() {
+        System::new("test").block_on(async {
+            let router = router(4);
+            let alice = connect_via(|msg| router.connect(msg), None).await;
+            let bob = connect_via(|msg| router.connect(msg), None).await;
+            let mut owners = HashSet::new();
+            let mut from = DEFAULT_ROOM.to_owned();
+            for n in 0..4 {
+                let room = format!("room-{}", n);
+                owners.insert(router.directory.owner(&room));
+                join(&router, &alice, &from, &room).await.unwrap();
+                from = room;
+            }
+            // alice ушла из всех комнат, кроме последней: их держит пауза до удаления
+            join(&router, &bob, DEFAULT_ROOM, "room-0").await.unwrap();
+            assert!(owners.len() > 1, "rooms must land on several shards");
+
+            let list = |offset, limit, sort| {
+                router.list_rooms(ListRooms {
+                    filter: Some("room".to_owned()),
+                    offset,
+                    limit,
+                    sort,
+                })
+            };
+            let page = list(0, 10, RoomSort::Name).await.unwrap();
+            let names: Vec<&str> = page.rooms.iter().map(|r| r.name.as_str()).collect();
+            assert_eq!(names, ["room-0", "room-1", "room-2", "room-3"]);
+            assert_eq!(page.total, 4);
+
+            let page = list(1, 2, RoomSort::Members).await.unwrap();
+            let names: Vec<(&str, usize)> = page
+                .rooms
+                .iter()
+                .map(|r| (r.name.as_str(), r.members))
+                .collect();
+            assert_eq!(names, [("room-3", 1), ("room-1", 0)]);
+            assert_eq!(page.total, 4);
+
+            let stats = router.stats().await.unwrap();
+            assert_eq!(stats.sessions, 2);
+            assert_eq!(stats.rooms, 5);
+        });
+    }
+
+    #[test]
+    fn invites_and_bans_cross_shards() {
+        System::new("test").block_on(async {
+            let router = router(4);
+            let far = room_apart(&router, "far", DEFAULT_ROOM);
+            let alice = connect_via(|msg| router.connect(msg), None).await;
+            let bob = connect_via(|msg| router.connect(msg), None).await;
+            for (client, room, name) in
+                [(&alice, DEFAULT_ROOM, "alice"), (&bob, DEFAULT_ROOM, "bob")]
+            {
+                router
+                    .shard(room)
+                    .send(SetName {
+                        id: client.id,
+                        name: name.to_owned(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            router
+                .shard(DEFAULT_ROOM)
+                .send(Join {
+                    id: alice.id,
+                    name: far.clone(),
+                    password: Some("secret".to_owned()),
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            router
+                .shard(&far)
+                .send(Invite {
+                    by: alice.id,
+                    room: far.clone(),
+                    target_name: "bob".to_owned(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            settle().await;
+            assert!(bob.received("alice invited you to"));
+            join(&router, &bob, DEFAULT_ROOM, &far).await.unwrap();
+
+            // забаненный возвращается в комнату по умолчанию другой части
+            router
+                .shard(&far)
+                .send(Ban {
+                    by: alice.id,
+                    room: far.clone(),
+                    target_name: "bob".to_owned(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            settle().await;
+            let id = bob.id;
+            let in_main = inspect(router.shard(DEFAULT_ROOM), move |s| {
+                s.rooms[DEFAULT_ROOM].sessions.contains(&id)
+            })
+            .await;
+            assert!(in_main);
+            assert_eq!(sessions_of(&router, &far).await, 1);
+            assert_eq!(
+                join(&router, &bob, DEFAULT_ROOM, &far)
+                    .await
+                    .unwrap_err()
+                    .to_string(),
+                "you are banned from this room"
+            );
+        });
+    }
+
+    #[test]
+    fn resumed_sessions_return_to_a_room_on_another_shard() {
+        System::new("test").block_on(async {
+            let router = router(4);
+            let far = room_apart(&router, "far", DEFAULT_ROOM);
+            let alice = connect_via(|msg| router.connect(msg), None).await;
+            let bob = connect_via(|msg| router.connect(msg), None).await;
+            join(&router, &alice, DEFAULT_ROOM, &far).await.unwrap();
+            join(&router, &bob, DEFAULT_ROOM, &far).await.unwrap();
+
+            router.disconnect(alice.id);
+            settle().await;
+            let back = connect_via(|msg| router.connect(msg), Some(alice.token.clone())).await;
+            settle().await;
+            assert_eq!(back.id, alice.id);
+            assert!(back.received(&format!("Welcome back! 2 in {}", far)));
+            assert!(bob.received("reconnected"));
+        });
+    }
+
+    #[test]
+    fn sessions_disconnected_while_moving_are_removed_once() {
+        System::new("test").block_on(async {
+            let counters = Arc::new(Counters::default());
+            let shared = counters.clone();
+            let router = Router::start(4, DEFAULT_ROOM.to_owned(), move || {
+                ChatServer::new(
+                    shared.clone(),
+                    Announcements::Full,
+                    50,
+                    20,
+                    Duration::from_secs(60),
+                    10_000,
+                    None,
+                )
+            });
+            let far = room_apart(&router, "far", DEFAULT_ROOM);
+            let locked = room_apart(&router, "locked", DEFAULT_ROOM);
+            let owner = connect_via(|msg| router.connect(msg), None).await;
+            router
+                .shard(DEFAULT_ROOM)
+                .send(Join {
+                    id: owner.id,
+                    name: locked.clone(),
+                    password: Some("secret".to_owned()),
+                    capacity: None,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+
+            // отключение приходит, пока Transfer в пути: принятый и
+            // отклоненный переезд
+            for room in [&far, &locked] {
+                let client = connect_via(|msg| router.connect(msg), None).await;
+                let moving = join(&router, &client, DEFAULT_ROOM, room);
+                router.disconnect(client.id);
+                let _ = moving.await;
+                settle().await;
+            }
+
+            assert_eq!(counters.active_sessions.load(Ordering::SeqCst), 1);
+            assert_eq!(router.stats().await.unwrap().sessions, 1);
+            let registry = router.directory.registry();
+            assert_eq!(registry.homes.len(), 1);
+            assert_eq!(registry.suspended.len(), 2);
+        });
+    }
+
+    #[test]
+    fn the_connection_cap_counts_every_shard() {
+        System::new("test").block_on(async {
+            let router = Router::start(4, DEFAULT_ROOM.to_owned(), || {
+                ChatServer::new(
+                    Arc::default(),
+                    Announcements::Full,
+                    50,
+                    20,
+                    Duration::from_secs(60),
+                    2,
+                    None,
+                )
+            });
+            let far = room_apart(&router, "far", DEFAULT_ROOM);
+            let alice = connect_via(|msg| router.connect(msg), None).await;
+            join(&router, &alice, DEFAULT_ROOM, &far).await.unwrap();
+            let _bob = connect_via(|msg| router.connect(msg), None).await;
+
+            let refused = router
+                .connect(Connect {
+                    addr: alice.probe.clone().recipient(),
+                    control: alice.probe.clone().recipient(),
+                    resume: None,
+                    peer: None,
+                    user_agent: None,
+                })
+                .await
+                .unwrap();
+            assert!(refused.is_err());
+        });
+    }
+
+    /// Сессия для замеров, считает доставленные сообщения с пометкой `bench`
+    struct Counter(Arc<AtomicUsize>);
+
+    impl Actor for Counter {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Counter {
+        type Result = ();
+
+        fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
+            if msg.text.contains("bench") {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    impl Handler<Control> for Counter {
+        type Result = ();
+
+        fn handle(&mut self, _: Control, _: &mut Context<Self>) {}
+    }
+
+    /// Пропускная способность одного актора сервера и частей по числу ядер:
+    /// несколько сотен отправителей в десятках комнат.
+    /// `cargo test --release -- --ignored --nocapture bench_`
+    #[test]
+    #[ignore]
+    fn bench_sharded_throughput() {
+        const ROOMS: usize = 32;
+        const PER_ROOM: usize = 10;
+        const MESSAGES: usize = 50;
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        System::new("test").block_on(async move {
+            for &shards in &[1, cores.max(4)] {
+                let router = Router::start(shards, DEFAULT_ROOM.to_owned(), || {
+                    test_server().with_flood_limit(crate::server::FloodLimit {
+                        burst: 1_000_000,
+                        window: Duration::from_secs(1),
+                        max_warnings: 0,
+                    })
+                });
+                // сессии живут в нескольких потоках, как в воркерах actix-web
+                let workers: Vec<Arbiter> = (0..cores.max(4)).map(|_| Arbiter::new()).collect();
+                let delivered = Arc::new(AtomicUsize::new(0));
+                let mut senders = Vec::new();
+                for n in 0..ROOMS * PER_ROOM {
+                    let counter = delivered.clone();
+                    let addr = Counter::start_in_arbiter(&workers[n % workers.len()], move |_| {
+                        Counter(counter)
+                    });
+                    let connected = router
+                        .connect(Connect {
+                            addr: addr.clone().recipient(),
+                            control: addr.recipient(),
+                            resume: None,
+                            peer: None,
+                            user_agent: None,
+                        })
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    let room = format!("bench-{}", n % ROOMS);
+                    router
+                        .shard(DEFAULT_ROOM)
+                        .send(Join {
+                            id: connected.id,
+                            name: room.clone(),
+                            password: None,
+                            capacity: None,
+                        })
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    senders.push((connected.id, room));
+                }
+                settle().await;
+                delivered.store(0, Ordering::Relaxed);
+
+                // отправитель своё сообщение не получает
+                let expected = ROOMS * PER_ROOM * MESSAGES * (PER_ROOM - 1);
+                let started = Instant::now();
+                for i in 0..MESSAGES {
+                    for (id, room) in &senders {
+                        router.shard(room).do_send(ClientMessage {
+                            id: *id,
+                            msg: format!("bench {}", i),
+                            room: room.clone(),
+                            correlation_id: None,
+                        });
+                    }
+                }
+                while delivered.load(Ordering::Relaxed) < expected {
+                    assert!(
+                        started.elapsed() < Duration::from_secs(60),
+                        "deliveries lost"
+                    );
+                    actix::clock::delay_for(Duration::from_millis(1)).await;
+                }
+                let elapsed = started.elapsed();
+                println!(
+                    "{} shard(s) on {} core(s): {} deliveries in {:?}, {:.0}/s",
+                    shards,
+                    cores,
+                    expected,
+                    elapsed,
+                    expected as f64 / elapsed.as_secs_f64()
+                );
+                for worker in workers {
+                    worker.stop();
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn broadcasts_reach_each_session_once_across_shards() {
+        System::new("test").block_on(async {
+            let router = router(4);
+            let mut clients = Vec::new();
+            for _ in 0..4 {
+                clients.push(connect_via(|msg| router.connect(msg), None).await);
+            }
+            let far = room_apart(&router, "far", DEFAULT_ROOM);
+            for client in &clients[..2] {
+                join(&router, client, DEFAULT_ROOM, &far).await.unwrap();
+            }
+            let shards: HashSet<usize> = clients
+                .iter()
+                .map(|client| router.directory.registry().homes[&client.id].shard)
+                .collect();
+            assert_eq!(shards.len(), 2);
+
+            router
+                .broadcast("restarting in 5 minutes".to_owned())
+                .await
+                .unwrap();
+            settle().await;
+            for client in &clients {
+                let copies = client
+                    .messages
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|m| *m == "[announcement] restarting in 5 minutes")
+                    .count();
+                assert_eq!(copies, 1, "session {}", client.id);
+            }
+        });
+    }
+}
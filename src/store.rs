@@ -0,0 +1,299 @@
+//! Хранение истории комнат в SQLite (`CHAT_DB_PATH`).
+//!
+//! При запуске сервер загружает из базы названия комнат и их последние
+//! сообщения. Затем каждое сообщение комнаты записывается в базу актором
+//! `Store` в отдельном потоке (`SyncArbiter`), поэтому медленный диск не
+//! задерживает `ChatServer`.
+
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use actix::prelude::*;
+use log::error;
+use rusqlite::{params, Connection};
+
+use crate::server::HistoryEntry;
+
+/// Комната, сохраненная в базе
+#[derive(Clone)]
+pub struct StoredRoom {
+    pub name: String,
+    /// Последние сообщения, самое старое первым
+    pub history: Vec<HistoryEntry>,
+}
+
+/// Записать сообщение комнаты в базу
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SaveMessage {
+    pub room: String,
+    pub entry: HistoryEntry,
+}
+
+/// Заменить текст сохраненного сообщения после правки или удаления
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UpdateMessage {
+    pub room: String,
+    pub msg_id: u64,
+    pub text: Arc<str>,
+}
+
+/// Удалить из базы всю историю комнаты
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClearRoom {
+    pub room: String,
+}
+
+/// Соединение с базой истории
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    /// Открыть базу, создав таблицу при первом запуске
+    pub fn open(path: &str) -> rusqlite::Result<Store> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                 id INTEGER PRIMARY KEY,
+                 room TEXT NOT NULL,
+                 msg_id INTEGER NOT NULL,
+                 sender TEXT,
+                 text TEXT NOT NULL,
+                 ts INTEGER NOT NULL,
+                 author INTEGER
+             );",
+        )?;
+
+        // в таблицах прежних версий нет id сессии автора
+        let has_author = conn
+            .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'author'")?
+            .exists([])?;
+        if !has_author {
+            conn.execute_batch("ALTER TABLE messages ADD COLUMN author INTEGER;")?;
+        }
+
+        // прежние версии не удаляли историю удаленных комнат, и номера
+        // сообщений могли повториться: остается последняя строка каждого номера
+        let unique: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master
+             WHERE type = 'index' AND name = 'messages_room_msg_id'",
+            [],
+            |row| row.get(0),
+        )?;
+        if unique == 0 {
+            conn.execute_batch(
+                "DROP INDEX IF EXISTS messages_room;
+                 DELETE FROM messages WHERE id NOT IN
+                     (SELECT MAX(id) FROM messages GROUP BY room, msg_id);
+                 CREATE UNIQUE INDEX messages_room_msg_id ON messages (room, msg_id);",
+            )?;
+        }
+        Ok(Store { conn })
+    }
+
+    /// Все комнаты базы с последними `limit` сообщениями каждой
+    pub fn load(&self, limit: usize) -> rusqlite::Result<Vec<StoredRoom>> {
+        let names: Vec<String> = self
+            .conn
+            .prepare("SELECT DISTINCT room FROM messages")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut latest = self.conn.prepare(
+            "SELECT msg_id, sender, text, ts, author FROM messages
+             WHERE room = ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let mut rooms = Vec::with_capacity(names.len());
+        for name in names {
+            let mut history: Vec<HistoryEntry> = latest
+                .query_map(params![name, limit as i64], |row| {
+                    Ok(HistoryEntry {
+                        msg_id: row.get::<_, i64>(0)? as u64,
+                        from: row.get(1)?,
+                        text: row.get(2)?,
+                        ts: UNIX_EPOCH + Duration::from_millis(row.get::<_, i64>(3)? as u64),
+                        author: row.get::<_, Option<i64>>(4)?.map(|id| id as usize),
+                        deleted: false,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            history.reverse();
+            rooms.push(StoredRoom { name, history });
+        }
+        Ok(rooms)
+    }
+}
+
+impl Actor for Store {
+    type Context = SyncContext<Self>;
+}
+
+impl Handler<SaveMessage> for Store {
+    type Result = ();
+
+    fn handle(&mut self, msg: SaveMessage, _: &mut Self::Context) {
+        let ts = msg
+            .entry
+            .ts
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let saved = self.conn.execute(
+            "INSERT INTO messages (room, msg_id, sender, text, ts, author)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                msg.room,
+                msg.entry.msg_id as i64,
+                msg.entry.from,
+                msg.entry.text,
+                ts,
+                msg.entry.author.map(|id| id as i64)
+            ],
+        );
+        if let Err(err) = saved {
+            error!("failed to save a message of room {}: {}", msg.room, err);
+        }
+    }
+}
+
+impl Handler<UpdateMessage> for Store {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateMessage, _: &mut Self::Context) {
+        let updated = self.conn.execute(
+            "UPDATE messages SET text = ?1 WHERE room = ?2 AND msg_id = ?3",
+            params![msg.text, msg.room, msg.msg_id as i64],
+        );
+        if let Err(err) = updated {
+            error!("failed to update a message of room {}: {}", msg.room, err);
+        }
+    }
+}
+
+impl Handler<ClearRoom> for Store {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClearRoom, _: &mut Self::Context) {
+        let cleared = self
+            .conn
+            .execute("DELETE FROM messages WHERE room = ?1", params![msg.room]);
+        if let Err(err) = cleared {
+            error!("failed to clear history of room {}: {}", msg.room, err);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    /// Путь к новой базе во временном каталоге
+    pub(crate) fn temp_db(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("chat-store-{}-{}.db", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn entry(msg_id: u64, text: &str) -> HistoryEntry {
+        HistoryEntry {
+            msg_id,
+            from: None,
+            text: Arc::from(text),
+            ts: SystemTime::now(),
+            author: None,
+            deleted: false,
+        }
+    }
+
+    #[test]
+    fn open_deduplicates_message_ids_of_older_databases() {
+        let path = temp_db("dedup");
+        let old = Connection::open(&path).unwrap();
+        old.execute_batch(
+            "CREATE TABLE messages (
+                 id INTEGER PRIMARY KEY,
+                 room TEXT NOT NULL,
+                 msg_id INTEGER NOT NULL,
+                 sender TEXT,
+                 text TEXT NOT NULL,
+                 ts INTEGER NOT NULL
+             );
+             CREATE INDEX messages_room ON messages (room, msg_id);
+             INSERT INTO messages (room, msg_id, text, ts) VALUES ('r', 1, 'stale', 0);
+             INSERT INTO messages (room, msg_id, text, ts) VALUES ('r', 1, 'fresh', 0);
+             INSERT INTO messages (room, msg_id, text, ts) VALUES ('r', 2, 'next', 0);",
+        )
+        .unwrap();
+        drop(old);
+
+        let store = Store::open(path.to_str().unwrap()).unwrap();
+        let rooms = store.load(10).unwrap();
+        assert_eq!(rooms.len(), 1);
+        let texts: Vec<&str> = rooms[0].history.iter().map(|e| &*e.text).collect();
+        assert_eq!(texts, ["fresh", "next"]);
+        assert!(rooms[0].history.iter().all(|e| e.author.is_none()));
+
+        let duplicate = store.conn.execute(
+            "INSERT INTO messages (room, msg_id, text, ts) VALUES ('r', 2, 'again', 0)",
+            [],
+        );
+        assert!(duplicate.is_err());
+        // повторное открытие ничего не удаляет
+        drop(store);
+        let store = Store::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(store.load(10).unwrap()[0].history.len(), 2);
+    }
+
+    #[test]
+    fn cleared_rooms_restart_numbering_without_old_rows() {
+        let path = temp_db("clear");
+        let db = path.to_str().unwrap().to_owned();
+        System::new("test").block_on(async move {
+            let store = SyncArbiter::start(1, move || Store::open(&db).unwrap());
+            let save = |msg_id, text| SaveMessage {
+                room: "r".to_owned(),
+                entry: entry(msg_id, text),
+            };
+            store.do_send(save(1, "old"));
+            store.do_send(save(2, "old"));
+            store.do_send(ClearRoom {
+                room: "r".to_owned(),
+            });
+            store.do_send(SaveMessage {
+                entry: HistoryEntry {
+                    author: Some(42),
+                    ..entry(1, "new")
+                },
+                ..save(1, "new")
+            });
+            store
+                .send(UpdateMessage {
+                    room: "r".to_owned(),
+                    msg_id: 1,
+                    text: Arc::from("edited"),
+                })
+                .await
+                .unwrap();
+        });
+
+        let rooms = Store::open(path.to_str().unwrap())
+            .unwrap()
+            .load(10)
+            .unwrap();
+        assert_eq!(rooms.len(), 1);
+        let history: Vec<(u64, &str)> = rooms[0]
+            .history
+            .iter()
+            .map(|e| (e.msg_id, &*e.text))
+            .collect();
+        assert_eq!(history, [(1, "edited")]);
+        assert_eq!(rooms[0].history[0].author, Some(42));
+    }
+}
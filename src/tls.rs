@@ -0,0 +1,55 @@
+//! TLS для `wss://` и `https://`: сертификат и ключ в PEM из `CHAT_TLS_CERT`
+//! и `CHAT_TLS_KEY`. Без них сервер слушает обычный HTTP.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig};
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Настройки TLS из окружения, `None` если TLS не включен
+pub fn from_env() -> io::Result<Option<ServerConfig>> {
+    match (
+        std::env::var("CHAT_TLS_CERT"),
+        std::env::var("CHAT_TLS_KEY"),
+    ) {
+        (Ok(cert), Ok(key)) => load(&cert, &key).map(Some),
+        (Err(_), Err(_)) => Ok(None),
+        _ => Err(invalid(
+            "CHAT_TLS_CERT and CHAT_TLS_KEY must be set together".to_owned(),
+        )),
+    }
+}
+
+/// Загрузить цепочку сертификатов и закрытый ключ (PKCS#8 или RSA)
+fn load(cert_path: &str, key_path: &str) -> io::Result<ServerConfig> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| invalid(format!("cannot open {}: {}", path, err)))
+    };
+
+    let chain = certs(&mut open(cert_path)?)
+        .map_err(|_| invalid(format!("{} is not a PEM certificate", cert_path)))?;
+    if chain.is_empty() {
+        return Err(invalid(format!("no certificates in {}", cert_path)));
+    }
+
+    let mut keys = pkcs8_private_keys(&mut open(key_path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(key_path)?).unwrap_or_default();
+    }
+    let key = keys
+        .pop()
+        .ok_or_else(|| invalid(format!("no private key in {}", key_path)))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(chain, key)
+        .map_err(|err| invalid(format!("invalid certificate or key: {}", err)))?;
+    Ok(config)
+}
@@ -0,0 +1,94 @@
+//! Обмен файлами: `POST /upload` сохраняет тело запроса во временный каталог
+//! и возвращает ссылку `/files/{id}`, которую можно отправить в чат командой
+//! `/file`. Файлы старше `ttl` удаляются периодической очисткой.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use actix_files::NamedFile;
+use actix_web::{web, Error, HttpResponse};
+use log::{info, warn};
+use rand::Rng;
+
+/// Как часто удаляются устаревшие файлы
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Настройки загрузки файлов
+#[derive(Clone)]
+pub struct UploadConfig {
+    /// Каталог, в котором хранятся загруженные файлы
+    pub dir: PathBuf,
+    /// Максимальный размер файла в байтах
+    pub max_size: usize,
+    /// Сколько хранится загруженный файл
+    pub ttl: Duration,
+}
+
+/// Id файла: 32 шестнадцатеричных символа, поэтому он не может выйти за пределы каталога
+fn is_valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Принять файл в теле запроса и вернуть ссылку на него
+pub async fn upload_route(
+    body: web::Bytes,
+    config: web::Data<UploadConfig>,
+) -> Result<HttpResponse, Error> {
+    if body.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("empty upload"));
+    }
+
+    let id = format!("{:032x}", rand::thread_rng().gen::<u128>());
+    let path = config.dir.join(&id);
+    let size = body.len();
+    web::block(move || fs::write(path, &body))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    info!("stored upload {} ({} bytes)", id, size);
+
+    Ok(HttpResponse::Created().body(format!("/files/{}", id)))
+}
+
+/// Отдать загруженный файл
+pub async fn file_route(
+    id: web::Path<String>,
+    config: web::Data<UploadConfig>,
+) -> Result<NamedFile, Error> {
+    if !is_valid_id(&id) {
+        return Err(actix_web::error::ErrorNotFound("no such file"));
+    }
+    Ok(NamedFile::open(config.dir.join(id.as_str()))?)
+}
+
+/// Удалить файлы, загруженные раньше чем `ttl` назад
+fn sweep(dir: &Path, ttl: Duration) -> io::Result<()> {
+    let now = SystemTime::now();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let modified = entry.metadata()?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() >= ttl {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Создать каталог для файлов и запустить периодическую очистку
+pub fn start(config: &UploadConfig) -> io::Result<()> {
+    fs::create_dir_all(&config.dir)?;
+
+    let (dir, ttl) = (config.dir.clone(), config.ttl);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let dir = dir.clone();
+            if let Err(err) = web::block(move || sweep(&dir, ttl)).await {
+                warn!("failed to remove expired uploads: {}", err);
+            }
+        }
+    });
+    Ok(())
+}